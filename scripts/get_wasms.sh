@@ -18,3 +18,7 @@ curl --output-dir $DESTINATION_DIR -sLO https://download.dfinity.systems/ic/$COM
 # Build the XRC mock canister
 cargo build -p xrc_mock --locked --target wasm32-unknown-unknown --release
 cp target/wasm32-unknown-unknown/release/xrc_mock.wasm src/subnet_rental_canister/tests/exchange-rate-canister.wasm
+
+# Build the governance mock canister
+cargo build -p governance_mock --locked --target wasm32-unknown-unknown --release
+cp target/wasm32-unknown-unknown/release/governance_mock.wasm src/subnet_rental_canister/tests/governance-canister.wasm
@@ -0,0 +1,108 @@
+//! A mock implementation of the NNS governance canister, which allows us to serve
+//! executed CreateSubnet proposals to the subnet rental canister in PocketIC tests.
+//!
+//! Running the real governance canister would require setting up neurons, voting on
+//! proposals and a registry that can actually create subnets. Since the subnet rental
+//! canister only reads executed proposals via `list_proposals`, this mock only provides
+//! that method, plus a way to inject executed CreateSubnet proposals.
+
+use candid::{CandidType, Deserialize, Principal};
+use std::{cell::RefCell, collections::BTreeMap};
+
+const PROPOSAL_STATUS_EXECUTED: i32 = 4;
+const NNS_FUNCTION_CREATE_SUBNET: i32 = 1;
+
+thread_local! {
+    static PROPOSALS: RefCell<BTreeMap<u64, ProposalInfo>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+struct ProposalId {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ListProposalInfo {
+    limit: u32,
+    before_proposal: Option<ProposalId>,
+    include_status: Vec<i32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ListProposalInfoResponse {
+    proposal_info: Vec<ProposalInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ProposalInfo {
+    id: Option<ProposalId>,
+    status: i32,
+    executed_timestamp_seconds: u64,
+    proposal: Option<Proposal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Proposal {
+    action: Option<Action>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum Action {
+    ExecuteNnsFunction(ExecuteNnsFunction),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ExecuteNnsFunction {
+    nns_function: i32,
+    payload: serde_bytes::ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CreateSubnetPayload {
+    subnet_id_override: Option<Principal>,
+    subnet_rental_request_proposal_id: Option<u64>,
+}
+
+#[ic_cdk::query]
+fn list_proposals(request: ListProposalInfo) -> ListProposalInfoResponse {
+    let before = request.before_proposal.map_or(u64::MAX, |p| p.id);
+    let proposal_info = PROPOSALS.with_borrow(|proposals| {
+        proposals
+            .range(..before)
+            .rev()
+            .map(|(_, p)| p)
+            .filter(|p| {
+                request.include_status.is_empty() || request.include_status.contains(&p.status)
+            })
+            .take(request.limit as usize)
+            .cloned()
+            .collect()
+    });
+    ListProposalInfoResponse { proposal_info }
+}
+
+/// Adds an executed CreateSubnet proposal with the given id.
+#[ic_cdk::update]
+fn add_executed_create_subnet_proposal(
+    proposal_id: u64,
+    subnet_id: Option<Principal>,
+    subnet_rental_request_proposal_id: Option<u64>,
+) {
+    let payload = candid::encode_one(CreateSubnetPayload {
+        subnet_id_override: subnet_id,
+        subnet_rental_request_proposal_id,
+    })
+    .unwrap();
+    let proposal = ProposalInfo {
+        id: Some(ProposalId { id: proposal_id }),
+        status: PROPOSAL_STATUS_EXECUTED,
+        executed_timestamp_seconds: ic_cdk::api::time() / 1_000_000_000,
+        proposal: Some(Proposal {
+            action: Some(Action::ExecuteNnsFunction(ExecuteNnsFunction {
+                nns_function: NNS_FUNCTION_CREATE_SUBNET,
+                payload: serde_bytes::ByteBuf::from(payload),
+            })),
+        }),
+    };
+    PROPOSALS.with_borrow_mut(|proposals| proposals.insert(proposal_id, proposal));
+}
@@ -1,49 +1,178 @@
 use crate::{
+    canister_state::update_canister_ids,
     canister_state::{
-        self, get_cached_rate, get_rental_agreement, get_rental_conditions, get_rental_request,
-        insert_rental_condition, iter_rental_agreements, iter_rental_conditions,
-        iter_rental_requests, persist_event, persist_rental_agreement, persist_rental_request,
-        remove_rental_request, update_rental_agreement, update_rental_request, CallerGuard,
+        self, cache_rate, get_cached_rate, get_config, get_rental_agreement, get_rental_conditions,
+        get_rental_request, insert_rental_condition, iter_rental_agreements,
+        iter_rental_conditions, iter_rental_requests, persist_event, persist_rental_agreement,
+        persist_rental_request, remove_cached_rates_before, remove_rental_request, update_config,
+        update_rental_agreement, update_rental_request, CallerGuard,
     },
+    canister_state::{get_latest_billing_record, insert_billing_record},
+    canister_state::{
+        insert_pending_conversion, insert_pending_transfer, iter_pending_conversions,
+        iter_pending_transfers, remove_pending_conversion, remove_pending_transfer,
+    },
+    canister_state::{insert_price_quote, remove_price_quote},
+    canister_state::{
+        record_timer_end, record_timer_error, record_timer_skip, record_timer_start,
+        set_notification_registration, set_pending_agreement_transfer,
+    },
+    condition_switch, dashboard,
+    exchange_rate::{get_exchange_rate_icp_per_xdr_at_time, rate_retention_cutoff},
     external_calls::{
-        check_subaccount_balance, convert_icp_to_cycles, get_exchange_rate_icp_per_xdr_at_time,
-        refund_user, set_authorized_subnetwork_list,
+        check_subaccount_balance, convert_icp_to_cycles, list_executed_proposals, notify_top_up,
+        refund_user, set_authorized_subnetwork_list, transfer_between_subaccounts, transfer_to_cmc,
+        XRC_CALL_CYCLES,
+    },
+    external_types::{
+        Action, CreateSubnetPayload, ExecuteNnsFunction, NotifyError, ProposalInfo,
+        NNS_FUNCTION_CREATE_SUBNET,
     },
     history::EventType,
-    migration, CreateRentalAgreementPayload, EventPage, ExecuteProposalError, OperationType,
-    PriceCalculationData, RentalAgreement, RentalAgreementStatus, RentalConditionId,
-    RentalConditions, RentalRequest, SubnetRentalProposalPayload, TopUpSummary,
-    UpdateSubnetAdminsError, UpdateSubnetAdminsPayload, UpdateSubnetAdminsResult, BILLION,
-    SECONDS_PER_DAY, TRILLION,
+    http_certification, metrics, migration, notifications,
+    pricing::{self, TopUpCalculation, TopUpEstimate},
+    statement, subnet_admins, AgreementRole, AgreementRolesError, AgreementRolesPayload,
+    AgreementTransferError, ApproveAgreementTransferPayload, BillingRecord, CachedRate,
+    CanisterIds, CanisterStatus, ConversionError, CreatePriceQuoteError,
+    CreateRentalAgreementPayload, EmptyRecord, EventPage, ExecuteProposalError, ExternalCallError,
+    HistoricalPrice, HttpRequest, HttpResponse, InitArgs, MigrationReport, NotificationKind,
+    NotificationRegistration, OperationType, OverrideExchangeRatePayload, PendingAgreementTransfer,
+    PendingConversion, PendingTransfer, PendingTransferKind, PriceCalculationData, PriceError,
+    PriceQuote, ProposeAgreementTransferPayload, RateProvenance, RefundError,
+    RegisterNotificationTargetError, RegisterNotificationTargetPayload, RejectRentalRequestPayload,
+    RentalAgreement, RentalAgreementStatus, RentalAgreementStatusError, RentalConditionId,
+    RentalConditions, RentalRequest, StatementCsvPage, StatementCursor, StatementError,
+    StatementPage, StorageCheckReport, SubnetRentalProposalPayload, SwitchRentalConditionPayload,
+    TopUpError, TopUpSummary, UpdateSubnetAdminsError, UpdateSubnetAdminsPayload,
+    UpdateSubnetAdminsResult, BILLION, SECONDS_PER_DAY, TRILLION,
 };
-use candid::Principal;
+use candid::{types::value::IDLValue, Principal};
 use ic_cdk::{
     api::{msg_caller, msg_reject, msg_reply},
     init, post_upgrade, println, query, update,
 };
-use ic_ledger_types::{
-    AccountIdentifier, Subaccount, Tokens, DEFAULT_FEE, MAINNET_GOVERNANCE_CANISTER_ID,
+use ic_ledger_types::{AccountIdentifier, Subaccount, Tokens, DEFAULT_FEE};
+use serde_bytes::ByteBuf;
+use std::{
+    cmp::{max, min},
+    collections::BTreeSet,
+    future::Future,
+    time::Duration,
 };
-use std::{cmp::min, time::Duration};
 
 const CYCLES_BURN_INTERVAL_SECONDS: u64 = 60;
+const GOVERNANCE_POLLING_INTERVAL_SECONDS: u64 = 10 * 60;
+const GOVERNANCE_POLLING_PAGE_SIZE: u32 = 100;
 const INITIAL_RENTAL_PERIOD_DAYS: u64 = 180;
+const MAX_PRICE_RANGE_DAYS: u64 = 31;
+/// The XRC and the CMC report rates with far fewer decimals than this.
+const MAX_RATE_DECIMALS: u32 = 18;
+const NOTIFICATION_CHECK_INTERVAL_SECONDS: u64 = 60 * 60;
+const CONVERSION_RETRY_INTERVAL_SECONDS: u64 = 10 * 60;
+const MAX_OPEN_PRICE_QUOTES_PER_USER: usize = 10;
+const MAX_OPEN_PRICE_QUOTES: usize = 10_000;
+const PRICE_QUOTE_VALIDITY_SECONDS: u64 = SECONDS_PER_DAY;
+const PRICE_QUOTE_RETENTION_DAYS: u64 = 30;
+const AGREEMENT_TRANSFER_VALIDITY_SECONDS: u64 = 7 * SECONDS_PER_DAY;
+/// The number of events and billing records `get_statement` reads per call.
+const STATEMENT_PAGE_SIZE: u64 = 1_000;
+const LOCKING_TIMER: &str = "locking";
+const BURN_CYCLES_TIMER: &str = "burn_cycles";
+const PRUNE_TIMER: &str = "prune";
+const CHECK_COVERAGE_TIMER: &str = "check_coverage";
+const CONVERSION_TIMER: &str = "complete_pending_conversions";
+const PENDING_TRANSFER_TIMER: &str = "complete_pending_transfers";
+const POLL_GOVERNANCE_TIMER: &str = "poll_governance";
+const STORAGE_CHECK_TIMER: &str = "check_storage";
+/// The number of stable memory entries decoded per message of the storage check, far below
+/// what fits into the instruction limit of a message.
+const STORAGE_CHECK_ENTRIES_PER_CHUNK: u64 = 1_000;
 const SWISS_NODES_DESCRIPTION: &str = "All nodes must be in Switzerland or Liechtenstein.";
 
 ////////// CANISTER METHODS //////////
 
 #[init]
-fn init() {
+fn init(args: Option<InitArgs>) {
     set_initial_conditions();
+    apply_init_args(args);
+    http_certification::certify_skipped_responses();
     println!("Subnet rental canister initialized");
     start_timers();
 }
 
 #[post_upgrade]
-async fn post_upgrade() {
+async fn post_upgrade(args: Option<InitArgs>) {
+    canister_state::start_storage_check(ic_cdk::api::time());
     set_initial_conditions();
-    migration::app13ch_to_app7ch();
+    apply_init_args(args);
+    migration::run_pending_migrations(ic_cdk::api::time());
+    http_certification::certify_skipped_responses();
     start_timers();
+    schedule_storage_check();
+}
+
+/// Checks the next chunk of stable memory in a timer, and schedules the following chunk until
+/// every entry was checked.
+fn schedule_storage_check() {
+    ic_cdk_timers::set_timer(Duration::ZERO, async {
+        run_timer(STORAGE_CHECK_TIMER, async {
+            if !canister_state::check_storage_chunk(
+                ic_cdk::api::time(),
+                STORAGE_CHECK_ENTRIES_PER_CHUNK,
+            ) {
+                schedule_storage_check();
+                return;
+            }
+            if let Some(storage_check) = canister_state::get_storage_check() {
+                for failure in &storage_check.failures {
+                    println!("Stable memory entry does not decode: {failure:?}");
+                }
+                println!(
+                    "Checked {} entries in stable memory, {} do not decode",
+                    storage_check.entries_checked,
+                    storage_check.failures.len()
+                );
+            }
+        })
+        .await
+    });
+}
+
+/// Persist the provided init or upgrade arguments in the config.
+fn apply_init_args(args: Option<InitArgs>) {
+    let Some(InitArgs {
+        poll_governance,
+        ledger_canister_id,
+        cmc_canister_id,
+        governance_canister_id,
+        xrc_canister_id,
+        registry_canister_id,
+        agreement_transfers_require_governance,
+    }) = args
+    else {
+        return;
+    };
+    update_config(|mut config| {
+        if let Some(poll_governance) = poll_governance {
+            config.poll_governance = poll_governance;
+        }
+        if let Some(require_governance) = agreement_transfers_require_governance {
+            config.agreement_transfers_require_governance = Some(require_governance);
+        }
+        config
+    });
+    update_canister_ids(|canister_ids| CanisterIds {
+        ledger: ledger_canister_id.unwrap_or(canister_ids.ledger),
+        cmc: cmc_canister_id.unwrap_or(canister_ids.cmc),
+        governance: governance_canister_id.unwrap_or(canister_ids.governance),
+        xrc: xrc_canister_id.unwrap_or(canister_ids.xrc),
+        registry: registry_canister_id.unwrap_or(canister_ids.registry),
+    });
+    println!(
+        "Applied init args, config is now {:?}, canister ids are {:?}",
+        get_config(),
+        canister_state::get_canister_ids()
+    );
 }
 
 /// Persist initial rental conditions in global map and history.
@@ -85,13 +214,244 @@ fn set_initial_conditions() {
 
 fn start_timers() {
     // Check if any ICP should be locked every hour.
-    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), async || locking().await);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), async || {
+        run_timer(LOCKING_TIMER, locking()).await
+    });
 
     // Burn cycles every minute.
     ic_cdk_timers::set_timer_interval(
         Duration::from_secs(CYCLES_BURN_INTERVAL_SECONDS),
-        async || burn_cycles().await,
+        async || run_timer(BURN_CYCLES_TIMER, burn_cycles()).await,
+    );
+
+    // Prune exchange rates and price quotes that are no longer needed once a day.
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(SECONDS_PER_DAY), async || {
+        run_timer(PRUNE_TIMER, async {
+            prune_rates();
+            prune_price_quotes();
+        })
+        .await
+    });
+
+    // Notify renters about low coverage every hour.
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(NOTIFICATION_CHECK_INTERVAL_SECONDS),
+        async || {
+            run_timer(CHECK_COVERAGE_TIMER, async {
+                notifications::check_coverage()
+            })
+            .await
+        },
+    );
+
+    // Retry minting cycles for ICP that reached the CMC without being converted.
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(CONVERSION_RETRY_INTERVAL_SECONDS),
+        async || run_timer(CONVERSION_TIMER, complete_pending_conversions()).await,
+    );
+
+    // Reconcile refunds and sweeps whose outcome is unknown.
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(CONVERSION_RETRY_INTERVAL_SECONDS),
+        async || run_timer(PENDING_TRANSFER_TIMER, complete_pending_transfers()).await,
+    );
+
+    // Check for executed subnet creation proposals, if enabled.
+    if get_config().poll_governance {
+        ic_cdk_timers::set_timer_interval(
+            Duration::from_secs(GOVERNANCE_POLLING_INTERVAL_SECONDS),
+            async || run_timer(POLL_GOVERNANCE_TIMER, poll_governance()).await,
+        );
+    }
+}
+
+/// Runs an iteration of a timer and records its start and end for `get_canister_status`.
+async fn run_timer(name: &'static str, iteration: impl Future<Output = ()>) {
+    record_timer_start(name, ic_cdk::api::time());
+    iteration.await;
+    record_timer_end(name, ic_cdk::api::time());
+}
+
+/// Removes cached exchange rates that neither an open rental request, a rental agreement,
+/// a stored price quote nor a recent price calculation depends on.
+fn prune_rates() {
+    let now_secs = ic_cdk::api::time() / BILLION;
+    let requests = iter_rental_requests()
+        .into_iter()
+        .map(|(_, rental_request)| rental_request.creation_time_nanos);
+    let agreements = iter_rental_agreements()
+        .into_iter()
+        .map(|(_, rental_agreement)| rental_agreement.creation_time_nanos);
+    let price_quote = canister_state::oldest_price_quote_creation_nanos();
+    let cutoff = rate_retention_cutoff(
+        now_secs,
+        requests
+            .chain(agreements)
+            .chain(price_quote)
+            .map(|time_nanos| time_nanos / BILLION),
+    );
+    let removed = remove_cached_rates_before(cutoff);
+    if removed > 0 {
+        println!("Pruned {removed} cached exchange rates before {cutoff}");
+    }
+}
+
+/// Removes price quotes that expired long enough ago that no proposal can still reference them.
+fn prune_price_quotes() {
+    let cutoff_nanos =
+        ic_cdk::api::time().saturating_sub(PRICE_QUOTE_RETENTION_DAYS * SECONDS_PER_DAY * BILLION);
+    let removed = canister_state::remove_price_quotes_expired_before(cutoff_nanos);
+    if removed > 0 {
+        println!("Pruned {removed} price quotes that expired before {cutoff_nanos}");
+    }
+}
+
+/// Adds a cycles burn to the billing record of the current UTC day.
+/// The rental time billed is the paid-for time that passed since the last burn.
+fn bill_burn(rental_agreement: &RentalAgreement, burned: u128, now_nanos: u64) {
+    let subnet_id = rental_agreement.subnet_id;
+    let day_start_secs = round_to_previous_midnight(now_nanos / BILLION);
+    let latest = get_latest_billing_record(subnet_id);
+    // Records of an earlier agreement for the same subnet do not count.
+    let billed_until_nanos = latest
+        .as_ref()
+        .map_or(0, |record| record.billed_until_nanos);
+    let billed_until_nanos = max(billed_until_nanos, rental_agreement.creation_time_nanos);
+    let covered_nanos =
+        min(now_nanos, rental_agreement.paid_until_nanos).saturating_sub(billed_until_nanos);
+    let cycles_burned_past_due = if now_nanos >= rental_agreement.paid_until_nanos {
+        burned
+    } else {
+        0
+    };
+    let daily_cost_cycles = get_rental_conditions(rental_agreement.rental_condition_id)
+        .map_or(0, |conditions| conditions.daily_cost_cycles);
+
+    let mut record = match latest {
+        Some(record) if record.day_start_secs == day_start_secs => record,
+        _ => BillingRecord {
+            subnet_id,
+            day_start_secs,
+            rental_condition_id: rental_agreement.rental_condition_id,
+            daily_cost_cycles,
+            cycles_burned: 0,
+            cycles_burned_past_due: 0,
+            covered_nanos: 0,
+            billed_until_nanos,
+        },
+    };
+    record.rental_condition_id = rental_agreement.rental_condition_id;
+    record.daily_cost_cycles = daily_cost_cycles;
+    record.cycles_burned = record.cycles_burned.saturating_add(burned);
+    record.cycles_burned_past_due = record
+        .cycles_burned_past_due
+        .saturating_add(cycles_burned_past_due);
+    record.covered_nanos = record.covered_nanos.saturating_add(covered_nanos);
+    record.billed_until_nanos = max(
+        billed_until_nanos,
+        min(now_nanos, rental_agreement.paid_until_nanos),
     );
+    insert_billing_record(record);
+}
+
+/// Looks for executed CreateSubnet proposals on NNS governance that reference the initial
+/// proposal id of an open rental request, and turns that rental request into a rental agreement.
+async fn poll_governance() {
+    let rental_requests = iter_rental_requests();
+    // A subnet creation proposal is always younger than the rental request proposal it references,
+    // so there is no need to page back further than the oldest open rental request.
+    let Some(oldest_proposal_id) = rental_requests
+        .iter()
+        .map(|(_, rental_request)| rental_request.initial_proposal_id)
+        .min()
+    else {
+        return;
+    };
+
+    let Ok(_guard) = CallerGuard::new(Principal::anonymous(), "governance") else {
+        println!("Busy polling governance. Skipping.");
+        record_timer_skip(POLL_GOVERNANCE_TIMER);
+        return;
+    };
+
+    let mut before_proposal = None;
+    loop {
+        let proposals =
+            match list_executed_proposals(before_proposal, GOVERNANCE_POLLING_PAGE_SIZE).await {
+                Ok(proposals) => proposals,
+                Err(e) => {
+                    println!("Failed to list proposals on governance: {e}");
+                    record_timer_error(
+                        POLL_GOVERNANCE_TIMER,
+                        ic_cdk::api::time(),
+                        format!("Failed to list proposals on governance: {e}"),
+                    );
+                    return;
+                }
+            };
+
+        for proposal in proposals.iter() {
+            let Some((proposal_id, create_subnet_payload)) = as_create_subnet(proposal) else {
+                continue;
+            };
+            let Some((user, _)) = rental_requests.iter().find(|(_, rental_request)| {
+                Some(rental_request.initial_proposal_id)
+                    == create_subnet_payload.subnet_rental_request_proposal_id
+            }) else {
+                continue;
+            };
+            let Some(subnet_id) = create_subnet_payload.subnet_id_override else {
+                println!(
+                    "CreateSubnet proposal {proposal_id} for the rental request of {user} \
+                    does not specify a subnet id. Waiting for governance."
+                );
+                continue;
+            };
+            println!("Found executed CreateSubnet proposal {proposal_id} for {user}");
+            let payload = CreateRentalAgreementPayload {
+                user: *user,
+                proposal_id,
+                subnet_id,
+            };
+            if let Err(e) = create_rental_agreement(payload).await {
+                println!("Failed to create rental agreement for {user}: {e:?}");
+                record_timer_error(
+                    POLL_GOVERNANCE_TIMER,
+                    ic_cdk::api::time(),
+                    format!("Failed to create rental agreement for {user}: {e:?}"),
+                );
+            }
+        }
+
+        // Continue with the next page only if this one was full and could still
+        // contain proposals younger than the oldest rental request.
+        let Some(last_proposal_id) = proposals.last().and_then(|p| p.id).map(|id| id.id) else {
+            return;
+        };
+        if proposals.len() < GOVERNANCE_POLLING_PAGE_SIZE as usize
+            || last_proposal_id <= oldest_proposal_id
+        {
+            return;
+        }
+        before_proposal = Some(last_proposal_id);
+    }
+}
+
+/// Returns the proposal id and payload if the given proposal is a CreateSubnet proposal.
+fn as_create_subnet(proposal: &ProposalInfo) -> Option<(u64, CreateSubnetPayload)> {
+    let proposal_id = proposal.id?.id;
+    let Some(Action::ExecuteNnsFunction(ExecuteNnsFunction {
+        nns_function,
+        payload,
+    })) = proposal.proposal.as_ref()?.action.as_ref()
+    else {
+        return None;
+    };
+    if *nns_function != NNS_FUNCTION_CREATE_SUBNET {
+        return None;
+    }
+    let payload = candid::decode_one::<CreateSubnetPayload>(payload).ok()?;
+    Some((proposal_id, payload))
 }
 
 async fn burn_cycles() {
@@ -101,6 +461,7 @@ async fn burn_cycles() {
                 "Busy processing another request. Skipping cycles burn for subnet {}",
                 rental_agreement.subnet_id
             );
+            record_timer_skip(BURN_CYCLES_TIMER);
             continue;
         };
 
@@ -143,7 +504,17 @@ async fn burn_cycles() {
                     "Failed to update rental agreement for subnet {}: {}. Skipping.",
                     rental_agreement.subnet_id, e
                 );
+                record_timer_error(
+                    BURN_CYCLES_TIMER,
+                    now_nanos,
+                    format!(
+                        "Failed to update rental agreement for subnet {}: {}",
+                        rental_agreement.subnet_id, e
+                    ),
+                );
+                continue;
             }
+            bill_burn(&rental_agreement, burned, now_nanos);
             continue;
         }
 
@@ -163,8 +534,17 @@ async fn burn_cycles() {
                 "Failed to update rental agreement for subnet {}: {}. Skipping.",
                 rental_agreement.subnet_id, e
             );
+            record_timer_error(
+                BURN_CYCLES_TIMER,
+                now_nanos,
+                format!(
+                    "Failed to update rental agreement for subnet {}: {}",
+                    rental_agreement.subnet_id, e
+                ),
+            );
             continue;
         }
+        bill_burn(&rental_agreement, burned, now_nanos);
     }
 }
 
@@ -201,33 +581,33 @@ async fn locking() {
 
         let Ok(_guard_res) = CallerGuard::new(user, "request") else {
             println!("Busy processing another request. Skipping.");
+            record_timer_skip(LOCKING_TIMER);
             continue;
         };
 
-        // Convert ICP to cycles.
-        let (block_index, locked_cycles) =
-            match convert_icp_to_cycles(ten_percent_icp, Subaccount::from(user)).await {
-                Ok(result) => result,
-                Err(error) => {
-                    println!("Failed to convert ICP to cycles for rental request of user {user}.");
-                    persist_event(
-                        EventType::LockingFailure {
-                            user,
-                            reason: format!("{error:?}"),
-                        },
-                        Some(user),
-                    );
-                    continue;
-                }
-            };
+        // Convert ICP to cycles. If the ICP may have reached the CMC, it is locked, even if the
+        // cycles are only added once the pending conversion completes.
+        let locked_cycles = match convert(user, None, ten_percent_icp).await {
+            Ok((_, cycles)) => cycles,
+            Err(e) if e.is_pending() => 0,
+            Err(error) => {
+                println!("Failed to convert ICP to cycles for rental request of user {user}.");
+                record_timer_error(
+                    LOCKING_TIMER,
+                    now_nanos,
+                    format!("Failed to convert ICP to cycles for {user}: {error:?}"),
+                );
+                persist_event(
+                    EventType::LockingFailure {
+                        user,
+                        reason: format!("{error:?}"),
+                    },
+                    Some(user),
+                );
+                continue;
+            }
+        };
 
-        persist_event(
-            EventType::TransferSuccess {
-                amount: ten_percent_icp,
-                block_index,
-            },
-            Some(user),
-        );
         println!("SRC gained {locked_cycles} cycles from the locked ICP.");
         persist_event(
             EventType::LockingSuccess {
@@ -257,6 +637,39 @@ async fn locking() {
 
 ////////// QUERY METHODS //////////
 
+/// Returns the status of the timers since the last install or upgrade, the cycle balance,
+/// and the stable memory used per memory region.
+#[query]
+pub fn get_canister_status() -> CanisterStatus {
+    CanisterStatus {
+        timers: canister_state::get_timer_statuses(),
+        cycle_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_pages: ic_cdk::api::stable_size(),
+        memory_regions: canister_state::memory_region_usage(),
+        storage_check: canister_state::get_storage_check(),
+    }
+}
+
+/// Returns the canister ids of the SRC's dependencies.
+#[query]
+pub fn get_canister_ids() -> CanisterIds {
+    canister_state::get_canister_ids()
+}
+
+/// Returns the result of decoding every entry in stable memory after the last upgrade, which is
+/// still running if `finished_time_nanos` is None, or None if the canister was not upgraded.
+#[query]
+pub fn get_storage_check() -> Option<StorageCheckReport> {
+    canister_state::get_storage_check()
+}
+
+/// Lists the registered state migrations. Pending migrations are dry-run to report the
+/// changes they would make if they ran now.
+#[query]
+pub fn list_migrations() -> Vec<MigrationReport> {
+    migration::list_migrations(ic_cdk::api::time())
+}
+
 #[query]
 pub fn list_rental_conditions() -> Vec<(RentalConditionId, RentalConditions)> {
     iter_rental_conditions()
@@ -297,12 +710,208 @@ pub fn get_rental_conditions_history_page(older_than: Option<u64>) -> EventPage
     }
 }
 
+/// List the cached ICP/XDR exchange rates for UTC midnights in the inclusive range
+/// `[from_secs, to_secs]`, given in seconds since epoch, along with their provenance.
+#[query]
+pub fn list_cached_rates(from_secs: u64, to_secs: u64) -> Vec<CachedRate> {
+    canister_state::list_cached_rates(from_secs, to_secs)
+}
+
+/// List the daily billing records of a rental agreement for UTC days starting in the inclusive
+/// range `[from_secs, to_secs]`, given in seconds since epoch.
+#[query]
+pub fn list_billing_records(
+    subnet_id: Principal,
+    from_secs: u64,
+    to_secs: u64,
+) -> Vec<BillingRecord> {
+    canister_state::list_billing_records(subnet_id, from_secs, to_secs)
+}
+
+/// Returns the account statement of a rental agreement for the UTC days from `from_secs` up to
+/// and including the day of `to_secs`, given in seconds since epoch.
+/// The agreement's history is read in pages of `STATEMENT_PAGE_SIZE` entries. As long as the
+/// result is `Incomplete`, call again with the same arguments and the returned cursor.
+/// Statements are public, like the subnet's history they are built from.
+#[query]
+pub fn get_statement(
+    subnet_id: Principal,
+    from_secs: u64,
+    to_secs: u64,
+    cursor: Option<StatementCursor>,
+) -> Result<StatementPage, StatementError> {
+    let Some(agreement) = get_rental_agreement(&subnet_id) else {
+        return Err(StatementError::NotFound);
+    };
+    if from_secs > to_secs {
+        return Err(StatementError::InvalidRange);
+    }
+    let (from_secs, to_secs) = statement::period(from_secs, to_secs);
+    let mut cursor = cursor.unwrap_or_else(|| {
+        let first_seq =
+            canister_state::first_seq_since(Some(subnet_id), agreement.creation_time_nanos);
+        statement::start(first_seq, from_secs)
+    });
+
+    let end_seq = canister_state::get_current_seq(Some(subnet_id)).map_or(0, |seq| seq + 1);
+    let page_end_seq = end_seq.min(cursor.next_seq.saturating_add(STATEMENT_PAGE_SIZE));
+    let (events, _) = canister_state::get_history_page(
+        Some(subnet_id),
+        Some(page_end_seq),
+        page_end_seq.saturating_sub(cursor.next_seq),
+    );
+    statement::read_events(&mut cursor, &events, from_secs, to_secs);
+    let remaining = STATEMENT_PAGE_SIZE - events.len() as u64;
+    if cursor.next_seq < end_seq || remaining == 0 {
+        return Ok(StatementPage::Incomplete(cursor));
+    }
+    let records = canister_state::list_billing_records_since(
+        subnet_id,
+        cursor.next_record_day_secs,
+        remaining,
+    );
+    statement::read_billing_records(&mut cursor, &records, to_secs);
+    if records.len() as u64 == remaining {
+        return Ok(StatementPage::Incomplete(cursor));
+    }
+
+    // The initial rental period of the condition the agreement was created with.
+    let initial_period_nanos = get_rental_conditions(
+        cursor
+            .initial_rental_condition_id
+            .unwrap_or(agreement.rental_condition_id),
+    )
+    .map_or(0, |conditions| conditions.initial_rental_period_days)
+        * SECONDS_PER_DAY
+        * BILLION;
+    Ok(StatementPage::Complete(statement::finish(
+        &agreement,
+        cursor,
+        initial_period_nanos,
+        from_secs,
+        to_secs,
+    )))
+}
+
+/// Like `get_statement`, but rendered as CSV.
+#[query]
+pub fn get_statement_csv(
+    subnet_id: Principal,
+    from_secs: u64,
+    to_secs: u64,
+    cursor: Option<StatementCursor>,
+) -> Result<StatementCsvPage, StatementError> {
+    Ok(
+        match get_statement(subnet_id, from_secs, to_secs, cursor)? {
+            StatementPage::Incomplete(cursor) => StatementCsvPage::Incomplete(cursor),
+            StatementPage::Complete(statement) => {
+                StatementCsvPage::Complete(statement::to_csv(&statement))
+            }
+        },
+    )
+}
+
+/// Returns the price quote with the given id, if it has not been used or pruned yet.
+#[query]
+pub fn get_price_quote(id: u64) -> Option<PriceQuote> {
+    canister_state::get_price_quote(id)
+}
+
 /// Derive the account into which a user must transfer ICP for renting a subnet.
 #[query]
 pub fn get_payment_account(user: Principal) -> String {
     AccountIdentifier::new(&ic_cdk::api::canister_self(), &Subaccount::from(user)).to_hex()
 }
 
+/// Returns the admins of a rented subnet, as last set via `update_subnet_admins`,
+/// or None if the subnet is not rented.
+/// The list is empty until the admins are first updated via the SRC, see `subnet_admins`.
+#[query]
+pub fn get_subnet_admins(subnet_id: Principal) -> Option<Vec<Principal>> {
+    get_rental_agreement(&subnet_id)?;
+    Some(
+        canister_state::get_subnet_admins(&subnet_id)
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+    )
+}
+
+/// Serves `/metrics` in the Prometheus text exposition format, and read-only HTML pages at
+/// `/agreements`, `/requests`, `/conditions` and `/history/<principal>`.
+/// The responses skip certification, so that the HTTP gateway serves them on the canister's
+/// normal domain, see `http_certification`.
+#[query]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    if let Some(principal) = path.strip_prefix("/history/") {
+        let Ok(principal) = Principal::from_text(principal) else {
+            return http_response(400, "text/plain", "Invalid principal".to_string());
+        };
+        let older_than = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("older_than="))
+            .and_then(|value| value.parse().ok());
+        let page = get_history_page(principal, older_than);
+        return html_response(dashboard::history_page(
+            &principal,
+            &page.events,
+            page.continuation,
+        ));
+    }
+    match path {
+        "/agreements" => html_response(dashboard::agreements_page(
+            &list_rental_agreements(),
+            ic_cdk::api::time(),
+        )),
+        "/requests" => html_response(dashboard::requests_page(&list_rental_requests())),
+        "/conditions" => {
+            let mut conditions = list_rental_conditions();
+            conditions.sort_by_key(|(id, _)| *id);
+            html_response(dashboard::conditions_page(&conditions))
+        }
+        "/metrics" => {
+            let mut rental_condition_ids: Vec<_> = iter_rental_conditions()
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            rental_condition_ids.sort();
+            let snapshot = metrics::Snapshot {
+                now_nanos: ic_cdk::api::time(),
+                cycle_balance: ic_cdk::api::canister_cycle_balance(),
+                rental_condition_ids,
+                rental_requests: list_rental_requests(),
+                rental_agreements: list_rental_agreements(),
+                cached_rates: canister_state::count_cached_rates(),
+                timers: canister_state::get_timer_statuses(),
+                held_locks: canister_state::held_lock_counts(),
+            };
+            http_response(200, "text/plain; version=0.0.4", metrics::encode(&snapshot))
+        }
+        _ => http_response(404, "text/plain", "Not found".to_string()),
+    }
+}
+
+fn html_response(body: String) -> HttpResponse {
+    http_response(200, "text/html; charset=utf-8", body)
+}
+
+fn http_response(status_code: u16, content_type: &str, body: String) -> HttpResponse {
+    let mut headers = vec![
+        ("Content-Type".to_string(), content_type.to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+    ];
+    headers.extend(http_certification::skip_certification_headers());
+    HttpResponse {
+        status_code,
+        headers,
+        body: ByteBuf::from(body.into_bytes()),
+    }
+}
+
 /// List all active rental agreements.
 #[query]
 pub fn list_rental_agreements() -> Vec<RentalAgreement> {
@@ -314,9 +923,11 @@ pub fn list_rental_agreements() -> Vec<RentalAgreement> {
 
 /// Returns the status of a rental agreement w.r.t. payment coverage.
 #[query]
-pub fn rental_agreement_status(subnet_id: Principal) -> Result<RentalAgreementStatus, String> {
+pub fn rental_agreement_status(
+    subnet_id: Principal,
+) -> Result<RentalAgreementStatus, RentalAgreementStatusError> {
     let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
-        return Err("Rental agreement not found".to_string());
+        return Err(RentalAgreementStatusError::NotFound);
     };
     let now_nanos = ic_cdk::api::time();
     let paid_until_nanos = rental_agreement.paid_until_nanos;
@@ -346,32 +957,158 @@ pub fn rental_agreement_status(subnet_id: Principal) -> Result<RentalAgreementSt
 /// Calculate the price of a subnet in ICP according to the exchange rate at the previous UTC midnight.
 /// The first call per day will cost 1_000_000_000 cycles.
 #[update]
-pub async fn get_todays_price(id: RentalConditionId) -> Result<Tokens, String> {
+pub async fn get_todays_price(id: RentalConditionId) -> Result<Tokens, PriceError> {
+    let now_secs = ic_cdk::api::time() / BILLION;
+    get_price_at_midnight(id, round_to_previous_midnight(now_secs))
+        .await
+        .map(|price| price.icp)
+}
+
+/// Calculate the price of a subnet in ICP according to the exchange rate at the UTC midnight
+/// before the given time in seconds since epoch, which must not be in the future.
+/// If the exchange rate of an earlier day than today is not cached, it is fetched from the XRC
+/// at the caller's expense, see `accept_cycles_for_uncached_rates`.
+#[update]
+pub async fn get_price_at(
+    id: RentalConditionId,
+    time_secs: u64,
+) -> Result<HistoricalPrice, PriceError> {
+    let now_secs = ic_cdk::api::time() / BILLION;
+    if time_secs > now_secs {
+        return Err(PriceError::TimeInFuture);
+    }
+    let midnight = round_to_previous_midnight(time_secs);
+    accept_cycles_for_uncached_rates([midnight], now_secs)?;
+    get_price_at_midnight(id, midnight).await
+}
+
+/// The SRC pays for fetching today's rate, which it needs itself and caches once per day.
+/// For each earlier day whose rate is not cached, the caller must attach `XRC_CALL_CYCLES`,
+/// which are accepted upfront, whether or not the XRC provides an acceptable rate.
+fn accept_cycles_for_uncached_rates(
+    midnights: impl IntoIterator<Item = u64>,
+    now_secs: u64,
+) -> Result<(), PriceError> {
+    let today = round_to_previous_midnight(now_secs);
+    let uncached_days = midnights
+        .into_iter()
+        .filter(|midnight| *midnight < today && get_cached_rate(*midnight).is_none())
+        .count();
+    let need = uncached_days as u128 * XRC_CALL_CYCLES;
+    let attached = ic_cdk::api::msg_cycles_available();
+    if attached < need {
+        return Err(PriceError::InsufficientCycles { need, attached });
+    }
+    ic_cdk::api::msg_cycles_accept(need);
+    Ok(())
+}
+
+/// Like `get_price_at`, but for every UTC midnight in the inclusive range between the midnights
+/// before `from_secs` and `to_secs`. At most `MAX_PRICE_RANGE_DAYS` days can be priced per call.
+#[update]
+pub async fn get_price_range(
+    id: RentalConditionId,
+    from_secs: u64,
+    to_secs: u64,
+) -> Result<Vec<HistoricalPrice>, PriceError> {
+    let now_secs = ic_cdk::api::time() / BILLION;
+    if to_secs > now_secs {
+        return Err(PriceError::TimeInFuture);
+    }
+    let invalid_range = PriceError::InvalidRange {
+        max_days: MAX_PRICE_RANGE_DAYS,
+    };
+    if from_secs > to_secs {
+        return Err(invalid_range);
+    }
+    let first_midnight = round_to_previous_midnight(from_secs);
+    let last_midnight = round_to_previous_midnight(to_secs);
+    if (last_midnight - first_midnight) / SECONDS_PER_DAY >= MAX_PRICE_RANGE_DAYS {
+        return Err(invalid_range);
+    }
+    let midnights = (first_midnight..=last_midnight).step_by(SECONDS_PER_DAY as usize);
+    accept_cycles_for_uncached_rates(midnights.clone(), now_secs)?;
+    let mut prices = vec![];
+    for midnight in midnights {
+        prices.push(get_price_at_midnight(id, midnight).await?);
+    }
+    Ok(prices)
+}
+
+/// Create a binding price quote for the caller, which locks the price of the given rental
+/// condition at the exchange rate of the previous UTC midnight.
+/// A SubnetRentalRequest proposal for the caller that is created before the quote expires can
+/// reference it by its id, in which case exactly the quoted amount of ICP is charged.
+#[update]
+pub async fn create_price_quote(
+    rental_condition_id: RentalConditionId,
+) -> Result<PriceQuote, CreatePriceQuoteError> {
+    let caller = msg_caller();
+    if caller == Principal::anonymous() {
+        return Err(CreatePriceQuoteError::AnonymousCaller);
+    }
+    let now_nanos = ic_cdk::api::time();
+    let open_price_quotes = canister_state::count_open_price_quotes_of(caller, now_nanos);
+    if open_price_quotes >= MAX_OPEN_PRICE_QUOTES_PER_USER {
+        return Err(CreatePriceQuoteError::TooManyOpenQuotes {
+            open: open_price_quotes as u64,
+        });
+    }
+    if canister_state::count_open_price_quotes(now_nanos, MAX_OPEN_PRICE_QUOTES)
+        >= MAX_OPEN_PRICE_QUOTES
+    {
+        return Err(CreatePriceQuoteError::TooManyOpenQuotesOverall {
+            open: MAX_OPEN_PRICE_QUOTES as u64,
+        });
+    }
+
+    let price = get_price_at_midnight(
+        rental_condition_id,
+        round_to_previous_midnight(now_nanos / BILLION),
+    )
+    .await
+    .map_err(CreatePriceQuoteError::Price)?;
+
+    let price_quote = insert_price_quote(|id| PriceQuote {
+        id,
+        user: caller,
+        rental_condition_id,
+        cycles: price.cycles,
+        icp: price.icp,
+        scaled_exchange_rate_xdr_per_icp: price.scaled_exchange_rate_xdr_per_icp,
+        decimals: price.decimals,
+        creation_time_nanos: now_nanos,
+        expiry_time_nanos: now_nanos + PRICE_QUOTE_VALIDITY_SECONDS * BILLION,
+    });
+    println!("Created price quote: {:?}", &price_quote);
+    Ok(price_quote)
+}
+
+/// Calculate the price of a subnet according to the exchange rate at the given UTC midnight,
+/// consulting the rate cache first.
+async fn get_price_at_midnight(
+    id: RentalConditionId,
+    midnight: u64,
+) -> Result<HistoricalPrice, PriceError> {
     let Some(conditions) = get_rental_conditions(id) else {
-        return Err("RentalConditionId not found".to_string());
+        return Err(PriceError::NotFound);
     };
-    let now_secs = ic_cdk::api::time() / BILLION;
-    let prev_midnight = round_to_previous_midnight(now_secs);
     // Consult cache:
-    let (scaled_exchange_rate_xdr_per_icp, decimals) = if let Some(tup) =
-        get_cached_rate(prev_midnight)
+    let (scaled_exchange_rate_xdr_per_icp, decimals) = if let Some(tup) = get_cached_rate(midnight)
     {
         tup
     } else {
         // Cache miss; only call the XRC if nobody else is currently making this call.
         let guard_res = CallerGuard::new(Principal::anonymous(), "XRC");
         if guard_res.is_err() {
-            return Err(
-                "Failed to acquire lock on calling exchange rate canister. Try again.".to_string(),
-            );
+            return Err(PriceError::Busy);
         }
         // Call exchange rate canister.
-        let res = get_exchange_rate_icp_per_xdr_at_time(prev_midnight).await;
+        let res = get_exchange_rate_icp_per_xdr_at_time(midnight).await;
         let Ok(tup) = res else {
-            return Err(format!(
-                "Failed to call the exchange rate canister: {:?}",
-                res.unwrap_err()
-            ));
+            return Err(PriceError::ExchangeRateUnavailable {
+                reason: format!("{:?}", res.unwrap_err()),
+            });
         };
         drop(guard_res);
         tup
@@ -383,8 +1120,16 @@ pub async fn get_todays_price(id: RentalConditionId) -> Result<Tokens, String> {
         decimals,
     );
     match res {
-        Ok(tokens) => Ok(tokens),
-        Err(e) => Err(format!("Failed to calculate price: {:?}", e)),
+        Ok(icp) => Ok(HistoricalPrice {
+            rental_condition_id: id,
+            time_secs: midnight,
+            // Cannot overflow, as the price calculation multiplies this product further.
+            cycles: conditions.daily_cost_cycles * conditions.initial_rental_period_days as u128,
+            icp,
+            scaled_exchange_rate_xdr_per_icp,
+            decimals,
+        }),
+        Err(data) => Err(PriceError::CalculationFailed(data)),
     }
 }
 
@@ -394,7 +1139,7 @@ fn calculate_subnet_price(
     initial_rental_period_days: u64,
     scaled_exchange_rate_xdr_per_icp: u64,
     decimals: u32,
-) -> Result<Tokens, ExecuteProposalError> {
+) -> Result<Tokens, PriceCalculationData> {
     // ICP needed = cycles needed / cycles_per_icp
     //            = cycles needed / (scaled_rate_xdr_per_icp / 10^decimals)
     //            = cycles needed / ((scaled_rate_cycles_per_e8s * 10^4) / 10^decimals)
@@ -403,25 +1148,23 @@ fn calculate_subnet_price(
     // The divisions are performed at the end so that accuracy is lost at the very end (if at all).
     let needed_cycles = daily_cost_cycles.checked_mul(initial_rental_period_days as u128);
     let e8s = needed_cycles
-        .and_then(|x| x.checked_mul(u128::pow(10, decimals)))
+        .and_then(|x| x.checked_mul(u128::checked_pow(10, decimals)?))
         .and_then(|x| x.checked_div(scaled_exchange_rate_xdr_per_icp as u128))
         .and_then(|x| x.checked_div(10_000));
     let Some(e8s) = e8s else {
-        return Err(ExecuteProposalError::PriceCalculationError(
-            PriceCalculationData {
-                daily_cost_cycles,
-                initial_rental_period_days,
-                scaled_exchange_rate_xdr_per_icp,
-                decimals,
-            },
-        ));
+        return Err(PriceCalculationData {
+            daily_cost_cycles,
+            initial_rental_period_days,
+            scaled_exchange_rate_xdr_per_icp,
+            decimals,
+        });
     };
     let tokens = Tokens::from_e8s(e8s as u64);
     println!(
         "SRC requires {} cycles or {} ICP, according to exchange rate {}",
         needed_cycles.unwrap(), // Safe because we err out above.
         tokens,
-        scaled_exchange_rate_xdr_per_icp as f64 / u64::pow(10, decimals) as f64
+        scaled_exchange_rate_xdr_per_icp as f64 / 10f64.powi(decimals as i32)
     );
     Ok(tokens)
 }
@@ -433,7 +1176,7 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
     // See [here](https://github.com/dfinity/ic/blob/d8fb1363ef39bae56493a8e48907c76b50d914e6/rs/nns/governance/src/governance.rs#L5061).
 
     if let Err(e) = execute_rental_request_proposal_(payload).await {
-        msg_reject(format!("Subnet rental request proposal failed: {:?}", e));
+        reject_proposal("Subnet rental request proposal failed", e);
     } else {
         msg_reply(candid::encode_one(()).unwrap());
     }
@@ -444,6 +1187,7 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
             rental_condition_id,
             proposal_id,
             proposal_creation_time_seconds,
+            price_quote_id,
         }: SubnetRentalProposalPayload,
     ) -> Result<(), ExecuteProposalError> {
         /// This function makes sense locally only because EventType::RentalRequestFailed is fixed.
@@ -465,7 +1209,9 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
         verify_caller_is_governance()?;
 
         // make sure no concurrent calls to this method can exist, in addition to governance's check.
-        let _guard = CallerGuard::new(user, "request").expect("Fatal: Concurrent call");
+        let Ok(_guard) = CallerGuard::new(user, "request") else {
+            return with_error(user, proposal_id, ExecuteProposalError::ConcurrentCall);
+        };
 
         // Fail if user has an existing rental request going on
         if get_rental_request(&user).is_some() {
@@ -515,30 +1261,54 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
 
         // ------------------------------------------------------------------
         // Attempt to transfer enough ICP to cover the initial rental period.
-        // Proposal creation time passed by NNS Governance is in seconds.
-        let exchange_rate_query_time = round_to_previous_midnight(proposal_creation_time_seconds);
-
-        // Call exchange rate canister.
-        let res = get_exchange_rate_icp_per_xdr_at_time(exchange_rate_query_time).await;
-        let Ok((scaled_exchange_rate_xdr_per_icp, decimals)) = res else {
-            let e = ExecuteProposalError::CallXRCFailed(format!("{:?}", res.unwrap_err()));
-            return with_error(user, proposal_id, e);
-        };
+        let needed_icp = if let Some(price_quote_id) = price_quote_id {
+            // A binding price quote replaces the exchange rate lookup.
+            match check_price_quote(
+                price_quote_id,
+                user,
+                rental_condition_id,
+                proposal_creation_time_seconds,
+            ) {
+                Ok(price_quote) => price_quote.icp,
+                Err(e) => return with_error(user, proposal_id, e),
+            }
+        } else {
+            // Proposal creation time passed by NNS Governance is in seconds.
+            let exchange_rate_query_time =
+                round_to_previous_midnight(proposal_creation_time_seconds);
+
+            // Call exchange rate canister.
+            let res = get_exchange_rate_icp_per_xdr_at_time(exchange_rate_query_time).await;
+            let Ok((scaled_exchange_rate_xdr_per_icp, decimals)) = res else {
+                let e = ExecuteProposalError::CallXRCFailed(format!("{:?}", res.unwrap_err()));
+                return with_error(user, proposal_id, e);
+            };
 
-        let res = calculate_subnet_price(
-            daily_cost_cycles,
-            initial_rental_period_days,
-            scaled_exchange_rate_xdr_per_icp,
-            decimals,
-        );
-        let Ok(needed_icp) = res else {
-            println!("Fatal: Failed to get exchange rate");
-            let e = res.unwrap_err();
-            return with_error(user, proposal_id, e);
+            let res = calculate_subnet_price(
+                daily_cost_cycles,
+                initial_rental_period_days,
+                scaled_exchange_rate_xdr_per_icp,
+                decimals,
+            );
+            let Ok(needed_icp) = res else {
+                println!("Fatal: Failed to get exchange rate");
+                let e = ExecuteProposalError::PriceCalculationError(res.unwrap_err());
+                return with_error(user, proposal_id, e);
+            };
+            needed_icp
         };
 
         // Check that the amount the user transferred to the SRC/user subaccount covers the initial cost.
-        let available_icp = check_subaccount_balance(Subaccount::from(user)).await;
+        let available_icp = match check_subaccount_balance(Subaccount::from(user)).await {
+            Ok(balance) => balance,
+            Err(e) => {
+                return with_error(
+                    user,
+                    proposal_id,
+                    ExecuteProposalError::ExternalCallFailed(e),
+                )
+            }
+        };
         println!(
             "Available icp: {}; Needed icp: {}",
             available_icp, needed_icp
@@ -559,19 +1329,16 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
             lock_amount_icp
         );
 
-        let res = convert_icp_to_cycles(lock_amount_icp, Subaccount::from(user)).await;
-        let Ok((block_index, locked_cycles)) = res else {
-            println!("Fatal: Failed to convert ICP to cycles");
-            let e = res.unwrap_err();
-            return with_error(user, proposal_id, e);
+        // Once the ICP may have reached the CMC, the rental request must be created. Otherwise, the
+        // cycles of a pending conversion would have no rental request to be added to.
+        let locked_cycles = match convert(user, None, lock_amount_icp).await {
+            Ok((_, cycles)) => cycles,
+            Err(e) if e.is_pending() => 0,
+            Err(e) => {
+                println!("Fatal: Failed to convert ICP to cycles");
+                return with_error(user, proposal_id, ExecuteProposalError::ConversionFailed(e));
+            }
         };
-        persist_event(
-            EventType::TransferSuccess {
-                amount: lock_amount_icp,
-                block_index,
-            },
-            Some(user),
-        );
         println!("SRC gained {} cycles from the locked ICP.", locked_cycles);
 
         let now_nanos = ic_cdk::api::time();
@@ -588,6 +1355,11 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
 
         // unwrap safety: The user cannot have an open rental request, as ensured at the start of this function.
         persist_rental_request(rental_request).unwrap();
+        // A price quote can only be used once. Unused quotes leave no trace in the history,
+        // since any caller can create them.
+        if let Some(price_quote) = price_quote_id.and_then(remove_price_quote) {
+            persist_event(EventType::PriceQuoteUsed { price_quote }, Some(user));
+        }
         println!("Created rental request for user {}", &user);
 
         Ok(())
@@ -602,7 +1374,7 @@ pub async fn execute_rental_request_proposal(payload: SubnetRentalProposalPayloa
 #[update(manual_reply = true)]
 pub async fn execute_create_rental_agreement(payload: CreateRentalAgreementPayload) {
     if let Err(e) = execute_create_rental_agreement_(payload).await {
-        msg_reject(format!("Creating rental agreement failed: {:?}", e));
+        reject_proposal("Creating rental agreement failed", e);
     } else {
         msg_reply(candid::encode_one(()).unwrap());
     }
@@ -611,60 +1383,245 @@ pub async fn execute_create_rental_agreement(payload: CreateRentalAgreementPaylo
         payload: CreateRentalAgreementPayload,
     ) -> Result<(), ExecuteProposalError> {
         verify_caller_is_governance()?;
-        let _guard = CallerGuard::new(payload.user, "request").expect("Fatal: Concurrent call");
-        let _guard = CallerGuard::new(payload.subnet_id, "nns").expect("Fatal: Concurrent call");
+        create_rental_agreement(payload).await
+    }
+}
+
+/// Turns a rental request into a rental agreement. Used both by governance via
+/// `execute_create_rental_agreement` and by the governance polling timer.
+async fn create_rental_agreement(
+    payload: CreateRentalAgreementPayload,
+) -> Result<(), ExecuteProposalError> {
+    let _guard = CallerGuard::new(payload.user, "request")
+        .map_err(|_| ExecuteProposalError::ConcurrentCall)?;
+    let _guard = CallerGuard::new(payload.subnet_id, "nns")
+        .map_err(|_| ExecuteProposalError::ConcurrentCall)?;
+
+    // Check if the user has an active rental request.
+    let Some(rental_request) = get_rental_request(&payload.user) else {
+        return Err(ExecuteProposalError::RentalRequestNotFound);
+    };
+
+    // Fail if the subnet is already being rented.
+    if get_rental_agreement(&payload.subnet_id).is_some() {
+        return Err(ExecuteProposalError::SubnetAlreadyRented);
+    }
+
+    let rental_condition = get_rental_conditions(rental_request.rental_condition_id)
+        .expect("Fatal: Rental condition not found");
+    let initial_rental_period_nanos =
+        rental_condition.initial_rental_period_days * SECONDS_PER_DAY * BILLION;
+
+    // Whitelist the user on the CMC before any ICP is converted, so that a failure leaves
+    // nothing to undo but the whitelisting.
+    if let Err(e) = set_authorized_subnetwork_list(&payload.user, vec![payload.subnet_id]).await {
+        return Err(rental_agreement_failed(
+            &payload,
+            ExecuteProposalError::ExternalCallFailed(e),
+        ));
+    }
+
+    // Convert all remaining ICP to cycles. If the ICP may have reached the CMC, the agreement
+    // is created, and the cycles are added once the pending conversion completes.
+    let remaining_icp = rental_request.initial_cost_icp - rental_request.locked_amount_icp;
+    let converted_cycles = match convert(payload.user, None, remaining_icp).await {
+        Ok((_, cycles)) => cycles,
+        Err(e) if e.is_pending() => 0,
+        // Locking may have left less than the transfer fee, which stays on the subaccount.
+        Err(ConversionError::AmountTooSmall { .. }) => 0,
+        Err(e) => {
+            // Compensate the whitelisting. If this fails as well, the user stays whitelisted
+            // until the rental agreement is created, which whitelists them anyway.
+            if let Err(e) = set_authorized_subnetwork_list(&payload.user, vec![]).await {
+                println!(
+                    "Failed to remove {} from the CMC whitelist: {e:?}",
+                    payload.user
+                );
+            }
+            return Err(rental_agreement_failed(
+                &payload,
+                ExecuteProposalError::ConversionFailed(e),
+            ));
+        }
+    };
+    let total_cycles_created = converted_cycles.saturating_add(rental_request.locked_amount_cycles);
+
+    // Create the rental agreement.
+    let now_nanos = ic_cdk::api::time();
+    let rental_agreement = RentalAgreement {
+        user: payload.user,
+        subnet_id: payload.subnet_id,
+        rental_request_proposal_id: rental_request.initial_proposal_id,
+        subnet_creation_proposal_id: Some(payload.proposal_id),
+        rental_condition_id: rental_request.rental_condition_id,
+        creation_time_nanos: now_nanos,
+        paid_until_nanos: now_nanos + initial_rental_period_nanos,
+        total_icp_paid: rental_request.initial_cost_icp,
+        total_cycles_created,
+        total_cycles_burned: 0,
+    };
+
+    // Removing the rental request will also stop the monthly locking process which locks 10% of the initial cost.
+    remove_rental_request(&payload.user).unwrap(); // It is checked above that the user has a rental request.
+
+    persist_rental_agreement(rental_agreement).unwrap(); // It is checked above that the subnet is not being rented.
+
+    Ok(())
+}
+
+/// Records that creating a rental agreement failed after the rental request was found.
+fn rental_agreement_failed(
+    payload: &CreateRentalAgreementPayload,
+    error: ExecuteProposalError,
+) -> ExecuteProposalError {
+    println!(
+        "Failed to create rental agreement for {}: {error:?}",
+        payload.user
+    );
+    persist_event(
+        EventType::RentalAgreementCreationFailed {
+            user: payload.user,
+            subnet_id: payload.subnet_id,
+            reason: format!("{error:?}"),
+        },
+        Some(payload.user),
+    );
+    error
+}
+
+/// This function is called by the NNS Governance canister to reject an existing rental request,
+/// e.g., because the subnet creation proposal was rejected.
+/// 1. The rental request is removed, which will terminate the monthly locking process.
+/// 2. The locked cycles are burned, as they are not refundable.
+/// 3. The remaining ICP on the user subaccount is refunded to the user.
+///
+/// If the refund fails, the user can still call `refund` to retrieve the remaining ICP.
+#[update(manual_reply = true)]
+pub async fn execute_reject_rental_request(payload: RejectRentalRequestPayload) {
+    if let Err(e) = execute_reject_rental_request_(payload).await {
+        reject_proposal("Rejecting rental request failed", e);
+    } else {
+        msg_reply(candid::encode_one(()).unwrap());
+    }
+
+    pub async fn execute_reject_rental_request_(
+        RejectRentalRequestPayload { user, proposal_id }: RejectRentalRequestPayload,
+    ) -> Result<(), ExecuteProposalError> {
+        verify_caller_is_governance()?;
+        let _guard =
+            CallerGuard::new(user, "request").map_err(|_| ExecuteProposalError::ConcurrentCall)?;
 
-        // Check if the user has an active rental request.
-        let Some(rental_request) = get_rental_request(&payload.user) else {
+        // Removing the rental request before any await makes sure that the request
+        // cannot be turned into an agreement or locked any further in the meantime.
+        let Some(rental_request) = remove_rental_request(&user) else {
             return Err(ExecuteProposalError::RentalRequestNotFound);
         };
 
-        // Fail if the subnet is already being rented.
-        if get_rental_agreement(&payload.subnet_id).is_some() {
-            return Err(ExecuteProposalError::SubnetAlreadyRented);
-        }
+        ic_cdk::api::cycles_burn(rental_request.locked_amount_cycles);
+        println!(
+            "Burned {} locked cycles after rejecting the rental request of {}",
+            rental_request.locked_amount_cycles, user
+        );
 
-        let rental_condition = get_rental_conditions(rental_request.rental_condition_id)
-            .expect("Fatal: Rental condition not found");
-        let initial_rental_period_nanos =
-            rental_condition.initial_rental_period_days * SECONDS_PER_DAY * BILLION;
+        // The rental request is rejected even if the refund fails, as the user can still
+        // call `refund` to retrieve the remaining ICP.
+        let (refunded_icp, refund_block_index) = match refund_balance(user).await {
+            Ok((refunded_icp, block_index)) => (refunded_icp, Some(block_index)),
+            Err(_) => (Tokens::from_e8s(0), None),
+        };
 
-        // Convert all remaining ICP to cycles.
-        let remaining_icp = rental_request.initial_cost_icp - rental_request.locked_amount_icp;
-        let (block_index, converted_cycles) =
-            convert_icp_to_cycles(remaining_icp, Subaccount::from(payload.user)).await?;
         persist_event(
-            EventType::TransferSuccess {
-                amount: remaining_icp,
-                block_index,
+            EventType::RentalRequestRejected {
+                rental_request,
+                subnet_creation_proposal_id: proposal_id,
+                refunded_icp,
+                refund_block_index,
             },
-            Some(payload.user),
+            Some(user),
         );
-        let total_cycles_created =
-            converted_cycles.saturating_add(rental_request.locked_amount_cycles);
+        println!("Rejected rental request of {user}, refunded {refunded_icp} ICP");
 
-        // Create the rental agreement.
-        let now_nanos = ic_cdk::api::time();
-        let rental_agreement = RentalAgreement {
-            user: payload.user,
-            subnet_id: payload.subnet_id,
-            rental_request_proposal_id: rental_request.initial_proposal_id,
-            subnet_creation_proposal_id: Some(payload.proposal_id),
-            rental_condition_id: rental_request.rental_condition_id,
-            creation_time_nanos: now_nanos,
-            paid_until_nanos: now_nanos + initial_rental_period_nanos,
-            total_icp_paid: rental_request.initial_cost_icp,
-            total_cycles_created,
-            total_cycles_burned: 0,
+        Ok(())
+    }
+}
+
+/// This function is called by the NNS Governance canister to move a rental agreement to another
+/// rental condition, typically alongside a registry change of the subnet's topology.
+/// The unburned cycles are repriced at the new daily cost, see `condition_switch`: the
+/// agreement is extended when downsizing and shortened when upsizing. The renter can top up
+/// to make up for the shortened coverage.
+#[update(manual_reply = true)]
+pub fn execute_switch_rental_condition(payload: SwitchRentalConditionPayload) {
+    if let Err(e) = execute_switch_rental_condition_(payload) {
+        reject_proposal("Switching rental condition failed", e);
+    } else {
+        msg_reply(candid::encode_one(()).unwrap());
+    }
+
+    fn execute_switch_rental_condition_(
+        SwitchRentalConditionPayload {
+            subnet_id,
+            new_condition_id,
+        }: SwitchRentalConditionPayload,
+    ) -> Result<(), ExecuteProposalError> {
+        verify_caller_is_governance()?;
+        let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
+            return Err(ExecuteProposalError::ConcurrentCall);
+        };
+        let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
+            return Err(ExecuteProposalError::SubnetNotRented);
         };
 
-        set_authorized_subnetwork_list(&payload.user, &payload.subnet_id).await;
+        let switch =
+            condition_switch::plan(&rental_agreement, new_condition_id, ic_cdk::api::time())
+                .map_err(ExecuteProposalError::InvalidRentalConditionSwitch)?;
+        condition_switch::apply(&switch)
+            .map_err(ExecuteProposalError::InvalidRentalConditionSwitch)?;
+        println!(
+            "Governance switched rental condition: {}",
+            switch.describe()
+        );
+        Ok(())
+    }
+}
 
-        // Removing the rental request will also stop the monthly locking process which locks 10% of the initial cost.
-        remove_rental_request(&payload.user).unwrap(); // It is checked above that the user has a rental request.
+/// This function is called by the NNS Governance canister to replace a bad cached exchange rate.
+/// Subsequent price calculations for the given day will use the new rate.
+#[update(manual_reply = true)]
+pub fn execute_override_exchange_rate(payload: OverrideExchangeRatePayload) {
+    if let Err(e) = execute_override_exchange_rate_(payload) {
+        reject_proposal("Overriding exchange rate failed", e);
+    } else {
+        msg_reply(candid::encode_one(()).unwrap());
+    }
 
-        persist_rental_agreement(rental_agreement).unwrap(); // It is checked above that the subnet is not being rented.
+    fn execute_override_exchange_rate_(
+        OverrideExchangeRatePayload {
+            time_secs,
+            rate,
+            decimals,
+        }: OverrideExchangeRatePayload,
+    ) -> Result<(), ExecuteProposalError> {
+        verify_caller_is_governance()?;
+        // Rates are only ever looked up at UTC midnight.
+        if rate == 0 || round_to_previous_midnight(time_secs) != time_secs {
+            return Err(ExecuteProposalError::InvalidExchangeRate);
+        }
+        // Make sure that the price calculation does not overflow with the new rate.
+        if decimals > MAX_RATE_DECIMALS {
+            return Err(ExecuteProposalError::InvalidExchangeRate);
+        }
 
+        let old_rate = cache_rate(time_secs, rate, decimals, RateProvenance::Governance);
+        persist_event(
+            EventType::ExchangeRateOverridden {
+                time_secs,
+                old_rate,
+                new_rate: (rate, decimals),
+            },
+            None,
+        );
+        println!("Governance set exchange rate at {time_secs} to {rate} (10^-{decimals}), was {old_rate:?}");
         Ok(())
     }
 }
@@ -675,38 +1632,18 @@ pub async fn execute_create_rental_agreement(payload: CreateRentalAgreementPaylo
 /// the SRC will refund the entire balance on the caller subaccount.
 /// Returns the block index of the refund transaction.
 #[update]
-pub async fn refund() -> Result<u64, String> {
+pub async fn refund() -> Result<u64, RefundError> {
     let caller = msg_caller();
     // To not flood the ledger canister, we only do one refund at a time.
     let Ok(_guard_res) = CallerGuard::new(Principal::anonymous(), "refund") else {
-        return Err("Busy processing another request. Try again.".to_string());
+        return Err(RefundError::Busy);
     };
     // We might remove a rental request, so we need to acquire a lock on it.
     let Ok(_guard_res) = CallerGuard::new(caller, "request") else {
-        return Err("Busy processing another request. Try again.".to_string());
+        return Err(RefundError::Busy);
     };
 
-    let balance = check_subaccount_balance(Subaccount::from(caller)).await;
-    if balance < DEFAULT_FEE {
-        return Err(format!(
-            "Failed refund: {caller} has insufficient funds {balance}"
-        ));
-    }
-    let to_be_refunded = balance - DEFAULT_FEE;
-
-    let block_id = refund_user(caller, to_be_refunded).await.map_err(|e| {
-        format!(
-            "Failed to refund {} ICP to {}: {:?}",
-            to_be_refunded, caller, e
-        )
-    })?;
-    persist_event(
-        EventType::TransferSuccess {
-            amount: to_be_refunded,
-            block_index: block_id,
-        },
-        Some(caller),
-    );
+    let (to_be_refunded, block_id) = refund_balance(caller).await?;
 
     // If the user has a rental request, burn the locked cycles and remove the request.
     if let Some(rental_request) = get_rental_request(&caller) {
@@ -729,193 +1666,1113 @@ pub async fn refund() -> Result<u64, String> {
     Ok(block_id)
 }
 
+/// Refunds the balance of the user's SRC subaccount, minus the transfer fee, to the user.
+/// Returns the refunded amount and the block index of the transfer.
+async fn refund_balance(user: Principal) -> Result<(Tokens, u64), RefundError> {
+    let balance = check_subaccount_balance(Subaccount::from(user))
+        .await
+        .map_err(|e| refund_failed(user, e))?;
+    if balance < DEFAULT_FEE {
+        return Err(RefundError::InsufficientFunds {
+            have: balance,
+            need: DEFAULT_FEE,
+        });
+    }
+    let to_be_refunded = balance - DEFAULT_FEE;
+
+    let created_at_time_nanos = ic_cdk::api::time();
+    let block_index = refund_user(user, to_be_refunded, created_at_time_nanos, None)
+        .await
+        .map_err(|e| {
+            println!("Failed to refund {to_be_refunded} ICP to {user}: {e:?}");
+            if let ExternalCallError::OutcomeUnknown { .. } = e {
+                transfer_pending(
+                    PendingTransfer {
+                        user,
+                        kind: PendingTransferKind::Refund,
+                        amount: to_be_refunded,
+                        created_at_time_nanos,
+                        next_search_block_index: None,
+                    },
+                    &e,
+                );
+                return RefundError::ExternalCallFailed(e);
+            }
+            refund_failed(user, e)
+        })?;
+    persist_event(
+        EventType::TransferSuccess {
+            amount: to_be_refunded,
+            block_index,
+        },
+        Some(user),
+    );
+    Ok((to_be_refunded, block_index))
+}
+
+/// Records a failed call of a refund in the user's history.
+fn refund_failed(user: Principal, error: ExternalCallError) -> RefundError {
+    persist_event(
+        EventType::RefundFailed {
+            user,
+            reason: format!("{error:?}"),
+        },
+        Some(user),
+    );
+    RefundError::ExternalCallFailed(error)
+}
+
 /// Estimates how many cycles and days a given ICP amount would provide for a subnet rental.
+/// `icp` is the amount in the user's SRC subaccount, i.e., excluding the fee of the transfer to the SRC.
 /// Uses the (potentially cached) exchange rate from the previous midnight to calculate the conversion.
+/// The result is computed exactly like an actual top-up, and bounds account for the CMC's rate
+/// deviating from this exchange rate.
 #[update]
 pub async fn subnet_top_up_estimate(
     subnet_id: Principal,
     icp: Tokens,
-) -> Result<TopUpSummary, String> {
+) -> Result<TopUpSummary, TopUpError> {
     let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
-        return Err("Rental agreement not found".to_string());
+        return Err(TopUpError::NotFound);
     };
 
     let now_secs = ic_cdk::api::time() / BILLION;
     let prev_midnight = round_to_previous_midnight(now_secs);
 
-    let Ok((scaled_exchange_rate_xdr_per_icp, decimals)) =
-        get_exchange_rate_icp_per_xdr_at_time(prev_midnight).await
-    else {
-        return Err("Failed to get exchange rate".to_string());
+    let (scaled_exchange_rate_xdr_per_icp, decimals) =
+        get_exchange_rate_icp_per_xdr_at_time(prev_midnight)
+            .await
+            .map_err(|e| TopUpError::ExchangeRateUnavailable {
+                reason: format!("{e:?}"),
+            })?;
+
+    let Some(icp_converted) = pricing::icp_to_convert(icp) else {
+        return Err(TopUpError::InsufficientFunds {
+            have: icp,
+            need: pricing::TOP_UP_FEES + Tokens::from_e8s(1),
+        });
     };
 
-    if icp < DEFAULT_FEE {
-        return Err(format!(
-            "Must top up more than {} ICP to cover the default fee",
-            DEFAULT_FEE
-        ));
-    }
-
-    let to_be_topped_up = icp - DEFAULT_FEE; // User pays the default fee to send funds to the SRC.
+    let rental_condition =
+        get_rental_conditions(rental_agreement.rental_condition_id).ok_or(TopUpError::NotFound)?;
 
-    let estimated_cycles = (to_be_topped_up - DEFAULT_FEE).e8s() as u128 // Account for internal transfer to CMC cost.
-        * scaled_exchange_rate_xdr_per_icp as u128
-        / (u128::pow(10, decimals) / 10_000); // Factor 10_000 to go from trillion cycles (10^12) to e8s (10^8).
-
-    let rental_condition = get_rental_conditions(rental_agreement.rental_condition_id)
-        .ok_or("Rental condition not found")?;
-
-    let daily = rental_condition.daily_cost_cycles;
-    let estimated_days = ((estimated_cycles + daily / 2) / daily) as u64;
+    let TopUpEstimate {
+        expected,
+        lower,
+        upper,
+    } = pricing::estimate_top_up(
+        icp_converted,
+        scaled_exchange_rate_xdr_per_icp,
+        decimals,
+        rental_condition.daily_cost_cycles,
+        rental_agreement.paid_until_nanos,
+    );
 
     let description = format!(
-        "Estimate: {} ICP would provide approximately {} cycles, \
-        extending the rental for subnet {} for about {} days. \
+        "Estimate: {} ICP would provide approximately {} cycles \
+        (between {} and {}), extending the rental for subnet {} by {} days. \
         Note that this is an estimate and the actual amount will vary.",
-        icp, estimated_cycles, subnet_id, estimated_days
+        icp,
+        expected.cycles_added,
+        lower.cycles_added,
+        upper.cycles_added,
+        subnet_id,
+        expected.days_added
     );
 
     Ok(TopUpSummary {
         description,
-        cycles_added: estimated_cycles,
-        days_added: estimated_days,
+        cycles_added: expected.cycles_added,
+        days_added: expected.days_added,
+        icp_converted,
+        fees: pricing::TOP_UP_FEES,
+        scaled_exchange_rate_xdr_per_icp,
+        decimals,
+        paid_until_nanos: expected.new_paid_until_nanos,
+        min_cycles_added: lower.cycles_added,
+        max_cycles_added: upper.cycles_added,
+        min_paid_until_nanos: lower.new_paid_until_nanos,
+        max_paid_until_nanos: upper.new_paid_until_nanos,
     })
 }
 
 /// Callable by anyone to trigger the conversion of ICP to cycles and the extension of the rental agreement.
 #[update]
-pub async fn top_up_subnet(subnet_id: Principal) -> Result<TopUpSummary, String> {
+pub async fn top_up_subnet(subnet_id: Principal) -> Result<TopUpSummary, TopUpError> {
     let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
-        return Err("Concurrent call, aborting".to_string());
+        return Err(TopUpError::Busy);
     };
 
     let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
-        return Err("Rental agreement not found".to_string());
+        return Err(TopUpError::NotFound);
     };
 
-    let user_icp_balance = check_subaccount_balance(Subaccount::from(rental_agreement.user)).await;
+    let payer = rental_agreement.user;
+    top_up_from_subaccount(rental_agreement, payer).await
+}
 
-    if user_icp_balance < DEFAULT_FEE {
-        let reason = format!(
-            "Failed to top up: {} has insufficient funds {}",
-            rental_agreement.user, user_icp_balance
-        );
-        persist_event(
-            EventType::SubnetTopUpFailed {
-                user: rental_agreement.user,
-                reason: reason.clone(),
+/// Callable by principals with the `TopUp` role to convert the ICP in their own SRC subaccount
+/// to cycles and extend the rental agreement, like `top_up_subnet` does for the renter.
+#[update]
+pub async fn top_up_subnet_as_delegate(subnet_id: Principal) -> Result<TopUpSummary, TopUpError> {
+    let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
+        return Err(TopUpError::Busy);
+    };
+
+    let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
+        return Err(TopUpError::NotFound);
+    };
+    let caller = msg_caller();
+    if !has_role(&rental_agreement, caller, AgreementRole::TopUp) {
+        return Err(TopUpError::Unauthorized);
+    }
+
+    top_up_from_subaccount(rental_agreement, caller).await
+}
+
+/// Converts the balance of the payer's SRC subaccount to cycles, see `top_up_subnet`.
+/// The caller must hold the agreement's lock.
+async fn top_up_from_subaccount(
+    rental_agreement: RentalAgreement,
+    payer: Principal,
+) -> Result<TopUpSummary, TopUpError> {
+    let subnet_id = rental_agreement.subnet_id;
+    let payer_icp_balance = check_subaccount_balance(Subaccount::from(payer))
+        .await
+        .map_err(|e| top_up_failed(subnet_id, payer, TopUpError::ExternalCallFailed(e)))?;
+
+    let Some(icp_converted) = pricing::icp_to_convert(payer_icp_balance) else {
+        return Err(top_up_failed(
+            subnet_id,
+            payer,
+            TopUpError::InsufficientFunds {
+                have: payer_icp_balance,
+                need: pricing::TOP_UP_FEES + Tokens::from_e8s(1),
             },
-            Some(subnet_id),
-        );
-        return Err(reason);
+        ));
+    };
+
+    // One fee stays in the subaccount, the transfer to the CMC pays the other.
+    convert_and_extend(
+        subnet_id,
+        rental_agreement,
+        payer,
+        icp_converted + DEFAULT_FEE,
+        payer_icp_balance,
+        pricing::TOP_UP_FEES,
+    )
+    .await
+}
+
+/// Callable by anyone to extend the rental agreement by the given number of days.
+/// Only the ICP needed for these days at the exchange rate of the previous midnight is converted
+/// to cycles; any surplus stays in the user's SRC subaccount. The exact number of days added
+/// depends on the rate at which the CMC converts.
+#[update]
+pub async fn top_up_subnet_for_days(
+    subnet_id: Principal,
+    days: u64,
+) -> Result<TopUpSummary, TopUpError> {
+    let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
+        return Err(TopUpError::Busy);
+    };
+
+    let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
+        return Err(TopUpError::NotFound);
+    };
+    if days == 0 {
+        return Err(TopUpError::InvalidDays);
     }
 
-    let icp_amount_for_cycles = user_icp_balance - DEFAULT_FEE;
+    let now_secs = ic_cdk::api::time() / BILLION;
+    let (scaled_exchange_rate_xdr_per_icp, decimals) =
+        get_exchange_rate_icp_per_xdr_at_time(round_to_previous_midnight(now_secs))
+            .await
+            .map_err(|e| TopUpError::ExchangeRateUnavailable {
+                reason: format!("{e:?}"),
+            })?;
 
-    // If the user were to withdraw before this call, the function would return an error.
-    let (block_index, actual_cycles) = match convert_icp_to_cycles(
+    let daily_cost_cycles = get_rental_conditions(rental_agreement.rental_condition_id)
+        .ok_or(TopUpError::NotFound)?
+        .daily_cost_cycles;
+    let icp_converted = daily_cost_cycles
+        .checked_mul(days as u128)
+        .and_then(|cycles| {
+            pricing::icp_for_cycles(cycles, scaled_exchange_rate_xdr_per_icp, decimals)
+        })
+        .ok_or(TopUpError::CalculationFailed)?;
+    // The transfer to the CMC pays one fee.
+    let icp_amount_for_cycles = icp_converted + DEFAULT_FEE;
+
+    let user_icp_balance = check_subaccount_balance(Subaccount::from(rental_agreement.user))
+        .await
+        .map_err(|e| {
+            top_up_failed(
+                subnet_id,
+                rental_agreement.user,
+                TopUpError::ExternalCallFailed(e),
+            )
+        })?;
+    if user_icp_balance < icp_amount_for_cycles {
+        return Err(top_up_failed(
+            subnet_id,
+            rental_agreement.user,
+            TopUpError::InsufficientFunds {
+                have: user_icp_balance,
+                need: icp_amount_for_cycles,
+            },
+        ));
+    }
+
+    let payer = rental_agreement.user;
+    convert_and_extend(
+        subnet_id,
+        rental_agreement,
+        payer,
+        icp_amount_for_cycles,
         icp_amount_for_cycles,
-        Subaccount::from(rental_agreement.user),
+        DEFAULT_FEE,
     )
     .await
-    {
-        Ok(v) => v,
-        Err(e) => {
-            let reason = format!("Failed to convert ICP to cycles: {:?}", e);
-            persist_event(
-                EventType::SubnetTopUpFailed {
-                    user: rental_agreement.user,
-                    reason: reason.clone(),
-                },
-                Some(subnet_id),
-            );
-            return Err(reason);
-        }
-    };
-    persist_event(
-        EventType::TransferSuccess {
-            amount: icp_amount_for_cycles,
-            block_index,
-        },
-        Some(subnet_id),
-    );
+}
+
+/// Converts `icp_amount_for_cycles` from the payer's SRC subaccount to cycles and extends the
+/// rental agreement accordingly. `icp_paid` is added to the total ICP paid for the agreement.
+async fn convert_and_extend(
+    subnet_id: Principal,
+    rental_agreement: RentalAgreement,
+    payer: Principal,
+    icp_amount_for_cycles: Tokens,
+    icp_paid: Tokens,
+    fees: Tokens,
+) -> Result<TopUpSummary, TopUpError> {
+    let icp_converted = icp_amount_for_cycles - DEFAULT_FEE;
+    // If the user were to withdraw before this call, the function would return an error.
+    // If the CMC does not mint the cycles, the top-up completes with the pending conversion.
+    let (_, actual_cycles) = convert(payer, Some(subnet_id), icp_amount_for_cycles)
+        .await
+        .map_err(|e| top_up_failed(subnet_id, payer, TopUpError::ConversionFailed(e)))?;
     println!(
         "Converted {} ICP to {} cycles",
         icp_amount_for_cycles, actual_cycles
     );
 
+    let TopUpCalculation {
+        cycles_added,
+        days_added,
+        new_paid_until_nanos,
+        ..
+    } = credit_top_up(&rental_agreement, payer, icp_paid, actual_cycles);
+
+    let description = format!(
+        "Topped up subnet {} with {} ICP corresponding to {} cycles, \
+        extending the rental agreement by {} days",
+        subnet_id, icp_paid, cycles_added, days_added,
+    );
+
+    Ok(TopUpSummary {
+        description,
+        cycles_added,
+        days_added,
+        icp_converted,
+        fees,
+        scaled_exchange_rate_xdr_per_icp: pricing::effective_rate(icp_converted, cycles_added),
+        decimals: pricing::EFFECTIVE_RATE_DECIMALS,
+        paid_until_nanos: new_paid_until_nanos,
+        min_cycles_added: cycles_added,
+        max_cycles_added: cycles_added,
+        min_paid_until_nanos: new_paid_until_nanos,
+        max_paid_until_nanos: new_paid_until_nanos,
+    })
+}
+
+/// Extends a rental agreement by the time that the given cycles cover and records the top-up
+/// paid by `payer`. The caller must hold the agreement's lock since it read `rental_agreement`.
+fn credit_top_up(
+    rental_agreement: &RentalAgreement,
+    payer: Principal,
+    icp_paid: Tokens,
+    cycles: u128,
+) -> TopUpCalculation {
+    let subnet_id = rental_agreement.subnet_id;
     // calculate the new paid_until_nanos
     let daily_cost_cycles = get_rental_conditions(rental_agreement.rental_condition_id)
         .expect("Fatal: Rental Condition not found")
         .daily_cost_cycles;
-    let cost_cycles_per_second = daily_cost_cycles / (SECONDS_PER_DAY as u128); // convert cost to cycles per second, rounding down
-    let seconds_charged = actual_cycles / cost_cycles_per_second; // calculate how many seconds the topup covers, rounding down to nearest second
-    let nanos_charged = seconds_charged.saturating_mul(BILLION as u128);
-    let new_paid_until_nanos =
-        (rental_agreement.paid_until_nanos as u128).saturating_add(nanos_charged);
-
-    let new_paid_until_nanos = match new_paid_until_nanos.try_into() {
-        Ok(val) => val,
-        Err(_) => {
-            // The user topped up the subnet beyond the year 2554.
-            // At that point, u64 is too small to represent the number of nanoseconds since 1970.
-            println!(
-                "Warning: Top-up amount of {icp_amount_for_cycles} ICP = {actual_cycles} \
-                cycles for {subnet_id} \
-                caused a u64 overflow, capping at maximum possible u64 value"
-            );
-            u64::MAX
-        }
-    };
-
-    let new_total_cycles_created = rental_agreement
-        .total_cycles_created
-        .saturating_add(actual_cycles);
-
-    // Until the year 2554, u64 is enough to represent the number of days.
-    let days_added = (seconds_charged / (SECONDS_PER_DAY as u128)) as u64;
+    let calculation =
+        pricing::calculate_top_up(cycles, daily_cost_cycles, rental_agreement.paid_until_nanos);
+    let TopUpCalculation {
+        cycles_added,
+        days_added,
+        new_paid_until_nanos,
+        ..
+    } = calculation;
 
     // update rental agreement
     update_rental_agreement(subnet_id, |mut agreement| {
-        agreement.total_cycles_created = new_total_cycles_created;
-        agreement.total_icp_paid += user_icp_balance; // Tokens do saturating adds
+        agreement.total_cycles_created =
+            agreement.total_cycles_created.saturating_add(cycles_added);
+        agreement.total_icp_paid += icp_paid; // Tokens do saturating adds
         agreement.paid_until_nanos = new_paid_until_nanos;
         agreement
     })
-    .unwrap(); // Safe because we checked above that the rental agreement exists.
+    .unwrap(); // Safe because the caller read the rental agreement.
 
     persist_event(
         EventType::SubnetTopUp {
-            user: rental_agreement.user,
-            icp_amount: user_icp_balance,
-            cycles_added: actual_cycles,
+            user: payer,
+            icp_amount: icp_paid,
+            cycles_added,
             days_added,
             new_paid_until_nanos,
         },
         Some(subnet_id),
     );
+    notifications::notify(
+        subnet_id,
+        NotificationKind::TopUpSucceeded {
+            cycles_added,
+            days_added,
+        },
+    );
+    calculation
+}
 
-    let description = format!(
-        "Topped up subnet {} with {} ICP corresponding to {} cycles, \
-        extending the rental agreement by {} days",
-        subnet_id, user_icp_balance, actual_cycles, days_added,
+/// Converts `amount` from the user's SRC subaccount to cycles, see `convert_icp_to_cycles`.
+/// `subnet_id` is set for top-ups. If the ICP may have reached the CMC but no cycles were
+/// minted, the conversion is recorded as pending and completed by `complete_pending_conversions`.
+async fn convert(
+    user: Principal,
+    subnet_id: Option<Principal>,
+    amount: Tokens,
+) -> Result<(u64, u128), ConversionError> {
+    let history_key = Some(subnet_id.unwrap_or(user));
+    let created_at_time_nanos = ic_cdk::api::time();
+    let result = convert_icp_to_cycles(amount, Subaccount::from(user), created_at_time_nanos).await;
+    let (block_index, error) = match &result {
+        Ok((block_index, _)) => (Some(*block_index), None),
+        Err(ConversionError::NotifyFailed { block_index, error }) => {
+            (Some(*block_index), Some(error))
+        }
+        Err(ConversionError::TransferOutcomeUnknown { error }) => (None, Some(error)),
+        Err(_) => return result,
+    };
+    if let Some(block_index) = block_index {
+        persist_event(
+            EventType::TransferSuccess {
+                amount,
+                block_index,
+            },
+            history_key,
+        );
+    }
+    if let Some(error) = error {
+        let pending_conversion = PendingConversion {
+            user,
+            subnet_id,
+            amount,
+            created_at_time_nanos,
+            block_index,
+            next_search_block_index: None,
+        };
+        insert_pending_conversion(pending_conversion.clone());
+        persist_event(
+            EventType::ConversionPending {
+                pending_conversion,
+                reason: format!("{error:?}"),
+            },
+            history_key,
+        );
+    }
+    result
+}
+
+/// Completes the pending conversions and adds the minted cycles where they belong.
+/// A transfer with an unknown outcome is reconciled by repeating it, which the ledger
+/// deduplicates, or, after the deduplication window, by searching the ledger's blocks. The CMC deduplicates notifications by block index and returns the cycles
+/// minted before, so a conversion is kept pending until its cycles were added.
+async fn complete_pending_conversions() {
+    let Ok(_guard) = CallerGuard::new(Principal::anonymous(), "conversion") else {
+        println!("Busy completing pending conversions. Skipping.");
+        record_timer_skip(CONVERSION_TIMER);
+        return;
+    };
+    for mut pending_conversion in iter_pending_conversions() {
+        let PendingConversion {
+            user,
+            subnet_id,
+            amount,
+            created_at_time_nanos,
+            ..
+        } = pending_conversion;
+        let history_key = Some(subnet_id.unwrap_or(user));
+
+        let block_index = match pending_conversion.block_index {
+            Some(block_index) => block_index,
+            None => match transfer_to_cmc(
+                amount - DEFAULT_FEE,
+                Subaccount::from(user),
+                created_at_time_nanos,
+                pending_conversion.next_search_block_index,
+            )
+            .await
+            {
+                Ok(block_index) => {
+                    pending_conversion.block_index = Some(block_index);
+                    insert_pending_conversion(pending_conversion.clone());
+                    persist_event(
+                        EventType::TransferSuccess {
+                            amount,
+                            block_index,
+                        },
+                        history_key,
+                    );
+                    block_index
+                }
+                // Neither the repeated nor the original transfer was executed,
+                // so the ICP never left the subaccount.
+                Err(error @ ExternalCallError::Transfer(_)) => {
+                    abandon_conversion(pending_conversion, error);
+                    continue;
+                }
+                Err(error) => {
+                    if let ExternalCallError::TransferSearchIncomplete { next_block_index } = error
+                    {
+                        pending_conversion.next_search_block_index = Some(next_block_index);
+                        insert_pending_conversion(pending_conversion.clone());
+                    }
+                    println!("Transfer of pending conversion is still unknown: {error:?}");
+                    record_timer_error(
+                        CONVERSION_TIMER,
+                        ic_cdk::api::time(),
+                        format!("Transfer of pending conversion is still unknown: {error:?}"),
+                    );
+                    continue;
+                }
+            },
+        };
+
+        let cycles = match notify_top_up(block_index).await {
+            Ok(cycles) => cycles,
+            Err(
+                error @ ExternalCallError::Notify(
+                    NotifyError::Refunded { .. }
+                    | NotifyError::InvalidTransaction(_)
+                    | NotifyError::TransactionTooOld(_),
+                ),
+            ) => {
+                abandon_conversion(pending_conversion, error);
+                continue;
+            }
+            Err(error) => {
+                println!("Conversion of block {block_index} is still pending: {error:?}");
+                record_timer_error(
+                    CONVERSION_TIMER,
+                    ic_cdk::api::time(),
+                    format!("Conversion of block {block_index} is still pending: {error:?}"),
+                );
+                continue;
+            }
+        };
+
+        // Without a subnet, the cycles belong to the user's rental request or, once it was
+        // accepted, to the rental agreement created from it.
+        let agreement_subnet_id = match subnet_id {
+            Some(subnet_id) => Some(subnet_id),
+            None if get_rental_request(&user).is_some() => None,
+            None => iter_rental_agreements()
+                .into_iter()
+                .find(|(_, agreement)| agreement.user == user)
+                .map(|(subnet_id, _)| subnet_id),
+        };
+        // Take the lock of what the cycles are added to. If it is held, try again next time.
+        let _guard = match agreement_subnet_id {
+            Some(subnet_id) => CallerGuard::new(subnet_id, "agreement"),
+            None => CallerGuard::new(user, "request"),
+        };
+        if _guard.is_err() {
+            record_timer_skip(CONVERSION_TIMER);
+            continue;
+        }
+        remove_pending_conversion(user, created_at_time_nanos);
+        persist_event(
+            EventType::ConversionCompleted {
+                pending_conversion,
+                cycles,
+            },
+            history_key,
+        );
+        match subnet_id {
+            Some(subnet_id) => match get_rental_agreement(&subnet_id) {
+                Some(rental_agreement) => {
+                    credit_top_up(&rental_agreement, user, amount, cycles);
+                }
+                None => println!("Subnet {subnet_id} is no longer rented, keeping {cycles} cycles"),
+            },
+            None => {
+                // The ICP was already accounted for, only the cycles are missing.
+                if update_rental_request(user, |mut rental_request| {
+                    rental_request.locked_amount_cycles =
+                        rental_request.locked_amount_cycles.saturating_add(cycles);
+                    rental_request
+                })
+                .is_ok()
+                {
+                    continue;
+                }
+                let agreement = agreement_subnet_id
+                    .and_then(|subnet_id| get_rental_agreement(&subnet_id))
+                    .filter(|agreement| agreement.user == user);
+                match agreement {
+                    Some(agreement) => {
+                        let _ = update_rental_agreement(agreement.subnet_id, |mut agreement| {
+                            agreement.total_cycles_created =
+                                agreement.total_cycles_created.saturating_add(cycles);
+                            agreement
+                        });
+                    }
+                    None => println!("{user} no longer rents a subnet, keeping {cycles} cycles"),
+                }
+            }
+        }
+    }
+}
+
+/// Stops trying to complete a pending conversion after a permanent error.
+fn abandon_conversion(pending_conversion: PendingConversion, error: ExternalCallError) {
+    let history_key = Some(
+        pending_conversion
+            .subnet_id
+            .unwrap_or(pending_conversion.user),
+    );
+    remove_pending_conversion(
+        pending_conversion.user,
+        pending_conversion.created_at_time_nanos,
+    );
+    persist_event(
+        EventType::ConversionAbandoned {
+            pending_conversion,
+            reason: format!("{error:?}"),
+        },
+        history_key,
     );
+}
 
-    Ok(TopUpSummary {
-        description,
-        cycles_added: actual_cycles,
-        days_added,
+/// Records a refund or sweep whose outcome is unknown, so that `complete_pending_transfers`
+/// reconciles it.
+fn transfer_pending(pending_transfer: PendingTransfer, error: &ExternalCallError) {
+    let history_key = Some(pending_transfer_history_key(&pending_transfer));
+    insert_pending_transfer(pending_transfer.clone());
+    persist_event(
+        EventType::TransferPending {
+            pending_transfer,
+            reason: format!("{error:?}"),
+        },
+        history_key,
+    );
+}
+
+/// Refunds are recorded in the user's history, sweeps in the subnet's.
+fn pending_transfer_history_key(pending_transfer: &PendingTransfer) -> Principal {
+    match pending_transfer.kind {
+        PendingTransferKind::Refund => pending_transfer.user,
+        PendingTransferKind::Sweep { subnet_id, .. } => subnet_id,
+    }
+}
+
+/// Reconciles the refunds and sweeps whose outcome is unknown like pending conversions: by
+/// repeating the transfer, which the ledger deduplicates, or by searching the ledger's blocks.
+async fn complete_pending_transfers() {
+    let Ok(_guard) = CallerGuard::new(Principal::anonymous(), "transfer") else {
+        println!("Busy completing pending transfers. Skipping.");
+        record_timer_skip(PENDING_TRANSFER_TIMER);
+        return;
+    };
+    for mut pending_transfer in iter_pending_transfers() {
+        let PendingTransfer {
+            user,
+            amount,
+            created_at_time_nanos,
+            next_search_block_index,
+            ..
+        } = pending_transfer;
+        // The transfer is repeated with the amount recorded when it was first attempted, not
+        // with the current balance, which may have changed since.
+        let result = match pending_transfer.kind {
+            PendingTransferKind::Refund => {
+                refund_user(user, amount, created_at_time_nanos, next_search_block_index).await
+            }
+            PendingTransferKind::Sweep { new_user, .. } => {
+                transfer_between_subaccounts(
+                    user,
+                    new_user,
+                    amount,
+                    created_at_time_nanos,
+                    next_search_block_index,
+                )
+                .await
+            }
+        };
+        let (block_index, reason) = match result {
+            Ok(block_index) => (Some(block_index), None),
+            // Neither the repeated nor the original transfer was executed.
+            Err(error @ ExternalCallError::Transfer(_)) => (None, Some(format!("{error:?}"))),
+            Err(error) => {
+                if let ExternalCallError::TransferSearchIncomplete { next_block_index } = error {
+                    pending_transfer.next_search_block_index = Some(next_block_index);
+                    insert_pending_transfer(pending_transfer);
+                }
+                println!("Outcome of pending transfer is still unknown: {error:?}");
+                record_timer_error(
+                    PENDING_TRANSFER_TIMER,
+                    ic_cdk::api::time(),
+                    format!("Outcome of pending transfer is still unknown: {error:?}"),
+                );
+                continue;
+            }
+        };
+        let history_key = Some(pending_transfer_history_key(&pending_transfer));
+        remove_pending_transfer(user, created_at_time_nanos);
+        persist_event(
+            EventType::TransferSettled {
+                pending_transfer,
+                block_index,
+                reason,
+            },
+            history_key,
+        );
+    }
+}
+
+/// Records a failed top-up in the subnet's history and notifies the renter.
+fn top_up_failed(subnet_id: Principal, user: Principal, error: TopUpError) -> TopUpError {
+    let reason = format!("Failed to top up: {error:?}");
+    println!("{reason}");
+    persist_event(
+        EventType::SubnetTopUpFailed {
+            user,
+            reason: reason.clone(),
+        },
+        Some(subnet_id),
+    );
+    notifications::notify(subnet_id, NotificationKind::TopUpFailed { reason });
+    error
+}
+
+/// Callable by the renter of a subnet to register a canister method that is notified about
+/// the rental agreement, or to remove it.
+#[update]
+pub fn register_notification_target(
+    payload: RegisterNotificationTargetPayload,
+) -> Result<(), RegisterNotificationTargetError> {
+    let RegisterNotificationTargetPayload { subnet_id, target } = payload;
+    if verify_caller_is_renting_subnet(subnet_id).is_err() {
+        return Err(RegisterNotificationTargetError::CallerNotRentingSubnet);
+    }
+    let reserved_canister_ids = notifications::reserved_canister_ids();
+    let target = target
+        .map(|target| notifications::validate_target(target, &reserved_canister_ids))
+        .transpose()
+        .map_err(|reason| RegisterNotificationTargetError::InvalidTarget { reason })?;
+    set_notification_registration(
+        subnet_id,
+        target.clone().map(|target| NotificationRegistration {
+            target,
+            lowest_notified_threshold_days: None,
+            past_due_notified: false,
+        }),
+    );
+    persist_event(
+        EventType::NotificationTargetChanged {
+            user: msg_caller(),
+            target,
+        },
+        Some(subnet_id),
+    );
+    Ok(())
+}
+
+/// Returns the notification target registered for a subnet, if any.
+#[query]
+pub fn get_notification_registration(subnet_id: Principal) -> Option<NotificationRegistration> {
+    canister_state::get_notification_registration(&subnet_id)
+}
+
+/// Callable by the renter of a subnet to propose transferring the rental agreement to a new
+/// principal, which completes once the new principal calls `accept_agreement_transfer`.
+/// The proposal expires after `AGREEMENT_TRANSFER_VALIDITY_SECONDS`.
+/// Replaces any earlier proposed transfer of the agreement.
+#[update]
+pub fn propose_agreement_transfer(
+    payload: ProposeAgreementTransferPayload,
+) -> Result<(), AgreementTransferError> {
+    let ProposeAgreementTransferPayload {
+        subnet_id,
+        new_user,
+    } = payload;
+    let caller = msg_caller();
+    // An `accept_agreement_transfer` in flight would otherwise remove this proposal when it ends.
+    let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
+        return Err(AgreementTransferError::Busy);
+    };
+    if verify_caller_is_renting_subnet(subnet_id).is_err() {
+        return Err(AgreementTransferError::CallerNotRentingSubnet);
+    }
+    if new_user == Principal::anonymous() || new_user == caller {
+        return Err(AgreementTransferError::InvalidPrincipal);
+    }
+    verify_new_user_is_eligible(new_user)?;
+    let pending_transfer = PendingAgreementTransfer {
+        subnet_id,
+        user: caller,
+        new_user,
+        proposal_time_nanos: ic_cdk::api::time(),
+        approved_by_governance: false,
+    };
+    set_pending_agreement_transfer(subnet_id, Some(pending_transfer.clone()));
+    persist_event(
+        EventType::AgreementTransferProposed { pending_transfer },
+        Some(subnet_id),
+    );
+    Ok(())
+}
+
+/// This function is called by the NNS Governance canister to approve a proposed transfer of a
+/// rental agreement, which is required if `agreement_transfers_require_governance` is set.
+#[update(manual_reply = true)]
+pub fn execute_approve_agreement_transfer(payload: ApproveAgreementTransferPayload) {
+    if let Err(e) = execute_approve_agreement_transfer_(payload) {
+        reject_proposal("Approving agreement transfer failed", e);
+    } else {
+        msg_reply(candid::encode_one(()).unwrap());
+    }
+
+    fn execute_approve_agreement_transfer_(
+        ApproveAgreementTransferPayload {
+            subnet_id,
+            new_user,
+        }: ApproveAgreementTransferPayload,
+    ) -> Result<(), ExecuteProposalError> {
+        verify_caller_is_governance()?;
+        let Some(mut pending_transfer) =
+            get_unexpired_agreement_transfer(subnet_id, ic_cdk::api::time())
+                .filter(|pending_transfer| pending_transfer.new_user == new_user)
+        else {
+            return Err(ExecuteProposalError::AgreementTransferNotFound);
+        };
+        pending_transfer.approved_by_governance = true;
+        set_pending_agreement_transfer(subnet_id, Some(pending_transfer.clone()));
+        persist_event(
+            EventType::AgreementTransferApproved { pending_transfer },
+            Some(subnet_id),
+        );
+        Ok(())
+    }
+}
+
+/// Callable by the renter or the new principal to cancel a proposed transfer of a rental agreement.
+#[update]
+pub fn cancel_agreement_transfer(subnet_id: Principal) -> Result<(), AgreementTransferError> {
+    // An `accept_agreement_transfer` in flight must not complete a cancelled transfer.
+    let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
+        return Err(AgreementTransferError::Busy);
+    };
+    let Some(pending_transfer) = canister_state::get_pending_agreement_transfer(&subnet_id) else {
+        return Err(AgreementTransferError::NotFound);
+    };
+    let caller = msg_caller();
+    if caller != pending_transfer.user && caller != pending_transfer.new_user {
+        return Err(AgreementTransferError::UnauthorizedCaller);
+    }
+    set_pending_agreement_transfer(subnet_id, None);
+    persist_event(
+        EventType::AgreementTransferCancelled { pending_transfer },
+        Some(subnet_id),
+    );
+    Ok(())
+}
+
+/// Callable by the new principal of a proposed transfer to take over the rental agreement.
+/// The new principal replaces the old one on the CMC's list of principals authorized to create
+/// canisters on the subnet, and the balance of the old principal's SRC subaccount is moved to
+/// the new principal's, from where `top_up_subnet` draws. The subnet admins, delegated roles and
+/// the notification target are reset, since they were chosen by the old principal.
+#[update]
+pub async fn accept_agreement_transfer(subnet_id: Principal) -> Result<(), AgreementTransferError> {
+    let new_user = msg_caller();
+    let Ok(_guard) = CallerGuard::new(subnet_id, "agreement") else {
+        return Err(AgreementTransferError::Busy);
+    };
+    // The new user must not create a rental request while it becomes the renter.
+    let Ok(_guard_request) = CallerGuard::new(new_user, "request") else {
+        return Err(AgreementTransferError::Busy);
+    };
+
+    let Some(pending_transfer) = canister_state::get_pending_agreement_transfer(&subnet_id) else {
+        return Err(AgreementTransferError::NotFound);
+    };
+    if pending_transfer.new_user != new_user {
+        return Err(AgreementTransferError::UnauthorizedCaller);
+    }
+    if is_expired(&pending_transfer, ic_cdk::api::time()) {
+        set_pending_agreement_transfer(subnet_id, None);
+        persist_event(
+            EventType::AgreementTransferExpired { pending_transfer },
+            Some(subnet_id),
+        );
+        return Err(AgreementTransferError::Expired);
+    }
+    if get_config()
+        .agreement_transfers_require_governance
+        .unwrap_or(false)
+        && !pending_transfer.approved_by_governance
+    {
+        return Err(AgreementTransferError::NotApprovedByGovernance);
+    }
+    verify_new_user_is_eligible(new_user)?;
+    let Some(rental_agreement) = get_rental_agreement(&subnet_id) else {
+        return Err(AgreementTransferError::NotFound);
+    };
+    let old_user = rental_agreement.user;
+
+    // Clear the admins before changing the CMC, so that the agreement stays with the old user
+    // if the registry call fails. The old user agreed to give up the subnet when proposing.
+    let old_admins = canister_state::get_subnet_admins(&subnet_id).unwrap_or_default();
+    clear_subnet_admins(subnet_id)
+        .await
+        .map_err(AgreementTransferError::RegistryError)?;
+    persist_event(
+        EventType::SubnetAdminsUpdated {
+            user: new_user,
+            added: vec![],
+            removed: old_admins.into_iter().collect(),
+        },
+        Some(subnet_id),
+    );
+
+    // Authorize the new user before deauthorizing the old one, so that the subnet is never
+    // left without a principal that may create canisters on it.
+    set_authorized_subnetwork_list(&new_user, vec![subnet_id])
+        .await
+        .map_err(AgreementTransferError::ExternalCallFailed)?;
+    if let Err(e) = set_authorized_subnetwork_list(&old_user, vec![]).await {
+        println!("Failed to remove {old_user} from the CMC's authorized list: {e:?}");
+        // The failed call may still have removed the old user, so authorize it again before
+        // removing the new user. If that fails too, the new user stays authorized.
+        match set_authorized_subnetwork_list(&old_user, vec![subnet_id]).await {
+            Ok(()) => {
+                if let Err(e) = set_authorized_subnetwork_list(&new_user, vec![]).await {
+                    println!("Failed to remove {new_user} from the CMC's authorized list: {e:?}");
+                }
+            }
+            Err(e) => {
+                println!("Failed to authorize {old_user} again on the CMC: {e:?}");
+            }
+        }
+        return Err(AgreementTransferError::ExternalCallFailed(e));
+    }
+
+    update_rental_agreement(subnet_id, |mut agreement| {
+        agreement.user = new_user;
+        agreement
     })
+    .unwrap(); // Safe because we checked above that the agreement exists.
+    set_pending_agreement_transfer(subnet_id, None);
+    for (principal, _) in canister_state::list_delegated_roles(subnet_id) {
+        canister_state::set_delegated_roles(subnet_id, principal, BTreeSet::new());
+    }
+    set_notification_registration(subnet_id, None);
+
+    // The agreement is transferred even if the sweep fails, in which case the old user
+    // can still get the balance back via `refund`.
+    let (swept_icp, sweep_error) = match sweep_subaccount(subnet_id, old_user, new_user).await {
+        Ok(swept_icp) => (swept_icp, None),
+        Err(e) => (Tokens::from_e8s(0), Some(format!("{e:?}"))),
+    };
+    println!(
+        "Transferred the rental agreement for subnet {subnet_id} from {old_user} to {new_user}, \
+        swept {swept_icp} ICP"
+    );
+    persist_event(
+        EventType::RentalAgreementTransferred {
+            old_user,
+            new_user,
+            swept_icp,
+            sweep_error,
+        },
+        Some(subnet_id),
+    );
+    Ok(())
+}
+
+/// Returns the proposed transfer of a subnet's rental agreement, if any and not expired.
+#[query]
+pub fn get_pending_agreement_transfer(subnet_id: Principal) -> Option<PendingAgreementTransfer> {
+    get_unexpired_agreement_transfer(subnet_id, ic_cdk::api::time())
+}
+
+fn get_unexpired_agreement_transfer(
+    subnet_id: Principal,
+    now_nanos: u64,
+) -> Option<PendingAgreementTransfer> {
+    canister_state::get_pending_agreement_transfer(&subnet_id)
+        .filter(|pending_transfer| !is_expired(pending_transfer, now_nanos))
+}
+
+fn is_expired(pending_transfer: &PendingAgreementTransfer, now_nanos: u64) -> bool {
+    now_nanos
+        >= pending_transfer
+            .proposal_time_nanos
+            .saturating_add(AGREEMENT_TRANSFER_VALIDITY_SECONDS * BILLION)
+}
+
+/// A principal can only take over a rental agreement if it neither rents nor requests a subnet.
+fn verify_new_user_is_eligible(new_user: Principal) -> Result<(), AgreementTransferError> {
+    if iter_rental_agreements()
+        .iter()
+        .any(|(_, agreement)| agreement.user == new_user)
+    {
+        return Err(AgreementTransferError::NewUserAlreadyHasAgreement);
+    }
+    if get_rental_request(&new_user).is_some() {
+        return Err(AgreementTransferError::NewUserAlreadyRequestingSubnetRental);
+    }
+    Ok(())
+}
+
+/// Moves the balance of the old user's SRC subaccount, minus the transfer fee, to the new
+/// user's SRC subaccount. Returns the amount that arrived.
+/// If the outcome of the transfer is unknown, it is recorded as pending.
+async fn sweep_subaccount(
+    subnet_id: Principal,
+    old_user: Principal,
+    new_user: Principal,
+) -> Result<Tokens, ExternalCallError> {
+    let balance = check_subaccount_balance(Subaccount::from(old_user)).await?;
+    if balance <= DEFAULT_FEE {
+        return Ok(Tokens::from_e8s(0));
+    }
+    let amount = balance - DEFAULT_FEE;
+    let created_at_time_nanos = ic_cdk::api::time();
+    let block_index =
+        match transfer_between_subaccounts(old_user, new_user, amount, created_at_time_nanos, None)
+            .await
+        {
+            Ok(block_index) => block_index,
+            Err(e @ ExternalCallError::OutcomeUnknown { .. }) => {
+                transfer_pending(
+                    PendingTransfer {
+                        user: old_user,
+                        kind: PendingTransferKind::Sweep {
+                            subnet_id,
+                            new_user,
+                        },
+                        amount,
+                        created_at_time_nanos,
+                        next_search_block_index: None,
+                    },
+                    &e,
+                );
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+    println!("Swept {amount} ICP from {old_user} to {new_user} in block {block_index}");
+    Ok(amount)
 }
 
-/// Callable by any principal that is renting a subnet to update the list of subnet admins for this subnet.
+/// Callable by the renter of a subnet to delegate roles on the rental agreement to a principal.
+#[update]
+pub fn grant_agreement_roles(payload: AgreementRolesPayload) -> Result<(), AgreementRolesError> {
+    change_agreement_roles(payload, true)
+}
+
+/// Callable by the renter of a subnet to revoke delegated roles from a principal.
+#[update]
+pub fn revoke_agreement_roles(payload: AgreementRolesPayload) -> Result<(), AgreementRolesError> {
+    change_agreement_roles(payload, false)
+}
+
+fn change_agreement_roles(
+    payload: AgreementRolesPayload,
+    grant: bool,
+) -> Result<(), AgreementRolesError> {
+    let AgreementRolesPayload {
+        subnet_id,
+        principal,
+        roles,
+    } = payload;
+    let caller = msg_caller();
+    if verify_caller_is_renting_subnet(subnet_id).is_err() {
+        return Err(AgreementRolesError::CallerNotRentingSubnet);
+    }
+    if principal == Principal::anonymous() || principal == caller {
+        return Err(AgreementRolesError::InvalidPrincipal);
+    }
+    if roles.is_empty() {
+        return Err(AgreementRolesError::RolesEmpty);
+    }
+    let mut delegated = canister_state::get_delegated_roles(subnet_id, principal);
+    // Only the roles that actually change are recorded.
+    let changed: Vec<AgreementRole> = roles
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|role| {
+            if grant {
+                delegated.insert(*role)
+            } else {
+                delegated.remove(role)
+            }
+        })
+        .collect();
+    if changed.is_empty() {
+        return Ok(());
+    }
+    canister_state::set_delegated_roles(subnet_id, principal, delegated);
+    let event = if grant {
+        EventType::AgreementRolesGranted {
+            user: caller,
+            principal,
+            roles: changed,
+        }
+    } else {
+        EventType::AgreementRolesRevoked {
+            user: caller,
+            principal,
+            roles: changed,
+        }
+    };
+    persist_event(event, Some(subnet_id));
+    Ok(())
+}
+
+/// Lists the principals that the renter of a subnet delegated roles to, with their roles.
+#[query]
+pub fn list_agreement_roles(subnet_id: Principal) -> Vec<(Principal, Vec<AgreementRole>)> {
+    canister_state::list_delegated_roles(subnet_id)
+        .into_iter()
+        .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+        .collect()
+}
+
+/// Callable by the renter of a subnet and by principals with the `ManageSubnetAdmins` role
+/// to update the list of subnet admins for this subnet.
 #[update]
 pub async fn update_subnet_admins(payload: UpdateSubnetAdminsPayload) -> UpdateSubnetAdminsResult {
     let subnet_id = payload.subnet_id;
 
-    // Caller must be renting the subnet they are trying to set admins for.
-    if let Err(e) = verify_caller_is_renting_subnet(subnet_id) {
-        return UpdateSubnetAdminsResult::Err(Some(e));
+    // Caller must be renting the subnet they are trying to set admins for, or be delegated to.
+    let caller = msg_caller();
+    let is_authorized = get_rental_agreement(&subnet_id)
+        .is_some_and(|agreement| has_role(&agreement, caller, AgreementRole::ManageSubnetAdmins));
+    if !is_authorized {
+        println!(
+            "Unauthorized caller {caller} attempted to update subnet admins for subnet {subnet_id}",
+        );
+        return UpdateSubnetAdminsResult::Err(Some(
+            UpdateSubnetAdminsError::CallerNotRentingSubnet(candid::Reserved),
+        ));
     }
 
     // Make sure that there are no other concurrent operations on the subnet
@@ -936,26 +2793,56 @@ pub async fn update_subnet_admins(payload: UpdateSubnetAdminsPayload) -> UpdateS
         )));
     };
 
-    match &payload.operation_type {
+    let operation = match &payload.operation_type {
         None => {
             return UpdateSubnetAdminsResult::Err(Some(
                 UpdateSubnetAdminsError::UnknownOperationType(candid::Reserved),
             ));
         }
         Some(OperationType::Add(provided_principals))
-        | Some(OperationType::Remove(provided_principals)) => {
-            if provided_principals.get().is_empty() {
-                return UpdateSubnetAdminsResult::Err(Some(
-                    UpdateSubnetAdminsError::PrincipalListEmpty(candid::Reserved),
-                ));
-            }
+        | Some(OperationType::Remove(provided_principals))
+            if provided_principals.get().is_empty() =>
+        {
+            return UpdateSubnetAdminsResult::Err(Some(
+                UpdateSubnetAdminsError::PrincipalListEmpty(candid::Reserved),
+            ));
         }
-        Some(OperationType::Clear(_)) => {}
-    }
+        Some(operation) => operation.clone(),
+    };
 
+    // The guard ensures that the admin set does not change while the registry call is in flight.
+    let tracked_admins = canister_state::get_subnet_admins(&subnet_id);
+    let old_admins = tracked_admins.clone().unwrap_or_default();
+    let new_admins = match subnet_admins::validate_operation(&old_admins, &operation) {
+        Ok(new_admins) => new_admins,
+        Err(e) => {
+            println!("Rejected subnet admins update for subnet {subnet_id}: {e:?}");
+            return UpdateSubnetAdminsResult::Err(Some(e));
+        }
+    };
+    // Admins the SRC does not know about would not count towards the limit, so they are
+    // cleared first. A `Clear` operation does that anyway.
+    if tracked_admins.is_none() && !matches!(operation, OperationType::Clear(_)) {
+        if let Err(e) = clear_subnet_admins(subnet_id).await {
+            return UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::RegistryError(e)));
+        }
+    }
     let res = crate::external_calls::update_subnet_admins(payload.into()).await;
     match res {
-        Ok(()) => UpdateSubnetAdminsResult::Ok(candid::Reserved),
+        Ok(()) => {
+            // Only track the admins once the registry has accepted the change.
+            let (added, removed) = subnet_admins::diff(&old_admins, &new_admins);
+            canister_state::set_subnet_admins(subnet_id, new_admins);
+            persist_event(
+                EventType::SubnetAdminsUpdated {
+                    user: caller,
+                    added,
+                    removed,
+                },
+                Some(subnet_id),
+            );
+            UpdateSubnetAdminsResult::Ok(candid::Reserved)
+        }
         Err(e) => UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::RegistryError(e))),
     }
 }
@@ -963,14 +2850,44 @@ pub async fn update_subnet_admins(payload: UpdateSubnetAdminsPayload) -> UpdateS
 // ============================================================================
 // Misc
 
+/// Clears the admins of a subnet in the registry, and tracks the now empty admin set.
+async fn clear_subnet_admins(subnet_id: Principal) -> Result<(), String> {
+    crate::external_calls::update_subnet_admins(
+        UpdateSubnetAdminsPayload {
+            subnet_id,
+            operation_type: Some(OperationType::Clear(EmptyRecord {})),
+        }
+        .into(),
+    )
+    .await?;
+    canister_state::set_subnet_admins(subnet_id, BTreeSet::new());
+    Ok(())
+}
+
+/// Rejects a call of NNS governance with `context` and the candid value of `error`, e.g.
+/// `Switching rental condition failed: variant { SubnetNotRented }`. Governance only tells
+/// replies from rejections, so a failed execution cannot be replied as an `Err`. The part after
+/// the first `": "` parses as an `ExecuteProposalError`.
+fn reject_proposal(context: &str, error: ExecuteProposalError) {
+    let error = IDLValue::try_from_candid_type(&error)
+        .map_or_else(|_| format!("{error:?}"), |value| value.to_string());
+    msg_reject(format!("{context}: {error}"));
+}
+
 fn verify_caller_is_governance() -> Result<(), ExecuteProposalError> {
-    if msg_caller() != MAINNET_GOVERNANCE_CANISTER_ID {
+    if msg_caller() != canister_state::get_canister_ids().governance {
         println!("Caller is not the governance canister");
         return Err(ExecuteProposalError::UnauthorizedCaller);
     }
     Ok(())
 }
 
+/// Whether `principal` is the renter of the agreement or holds the delegated `role`.
+fn has_role(agreement: &RentalAgreement, principal: Principal, role: AgreementRole) -> bool {
+    agreement.user == principal
+        || canister_state::get_delegated_roles(agreement.subnet_id, principal).contains(&role)
+}
+
 fn verify_caller_is_renting_subnet(subnet_id: Principal) -> Result<(), UpdateSubnetAdminsError> {
     let caller = msg_caller();
     let is_renting = iter_rental_agreements()
@@ -987,6 +2904,26 @@ fn verify_caller_is_renting_subnet(subnet_id: Principal) -> Result<(), UpdateSub
     Ok(())
 }
 
+/// Returns the price quote if it belongs to the given user and rental condition,
+/// and if it had not expired at proposal creation time.
+fn check_price_quote(
+    price_quote_id: u64,
+    user: Principal,
+    rental_condition_id: RentalConditionId,
+    proposal_creation_time_seconds: u64,
+) -> Result<PriceQuote, ExecuteProposalError> {
+    let Some(price_quote) = canister_state::get_price_quote(price_quote_id) else {
+        return Err(ExecuteProposalError::PriceQuoteNotFound);
+    };
+    if price_quote.user != user || price_quote.rental_condition_id != rental_condition_id {
+        return Err(ExecuteProposalError::PriceQuoteMismatch);
+    }
+    if proposal_creation_time_seconds.saturating_mul(BILLION) >= price_quote.expiry_time_nanos {
+        return Err(ExecuteProposalError::PriceQuoteExpired);
+    }
+    Ok(price_quote)
+}
+
 fn round_to_previous_midnight(time_secs: u64) -> u64 {
     time_secs - time_secs % 86400
 }
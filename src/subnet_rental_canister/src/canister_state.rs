@@ -6,20 +6,95 @@
 /// Relevant updates to state leave a trace in the corresponding History trace log.  
 use crate::{
     history::{Event, EventType},
-    Principal, RentalAgreement, RentalConditionId, RentalConditions, RentalRequest,
+    versioned::{self, Versioned},
+    AgreementRole, BillingRecord, CachedRate, CanisterIds, Config, DelegatedRoles,
+    MemoryRegionUsage, NotificationRegistration, PendingAgreementTransfer, PendingConversion,
+    PendingTransfer, PriceQuote, Principal, RateProvenance, RentalAgreement, RentalConditionId,
+    RentalConditions, RentalRequest, StorageCheckFailure, StorageCheckReport, SubnetAdmins,
+    TimerError, TimerStatus,
 };
 use ic_cdk::println;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl, StableBTreeMap,
+    DefaultMemoryImpl, Memory, StableBTreeMap, StableCell, Storable,
 };
 use std::{
+    borrow::Cow,
     cell::RefCell,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    ops::Bound,
 };
 
 type EventNum = u64;
 
+/// Checks up to `max_entries` entries of a region, starting after the encoded key `after_key`,
+/// and returns the encoded key of the last checked entry if the region has more entries.
+type RegionCheck = fn(
+    memory_id: u8,
+    after_key: Option<&[u8]>,
+    max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>>;
+
+/// Every memory region, with the check of its entries for the storage check. The stable
+/// structures below get their memory via `memory`, which only hands out regions listed here,
+/// and `get_canister_status` reports the usage of exactly these regions.
+const MEMORY_REGIONS: &[(u8, RegionCheck)] = &[
+    (0, check_versioned_map::<Principal, RentalRequest>),
+    (1, check_versioned_map::<Principal, RentalAgreement>),
+    (2, check_fixed_size_map::<Option<Principal>>),
+    (
+        3,
+        check_versioned_map::<(Option<Principal>, EventNum), Event>,
+    ),
+    (4, check_fixed_size_map::<u64>),
+    (5, check_versioned_cell::<Config>),
+    (6, check_versioned_map::<u64, RateProvenance>),
+    (7, check_versioned_map::<u64, PriceQuote>),
+    (8, check_fixed_size_cell),
+    (9, check_versioned_map::<(Principal, u64), BillingRecord>),
+    (
+        10,
+        check_versioned_map::<Principal, NotificationRegistration>,
+    ),
+    (
+        11,
+        check_versioned_map::<(Principal, u64), PendingConversion>,
+    ),
+    (12, check_versioned_cell::<CanisterIds>),
+    (13, check_versioned_map::<Principal, SubnetAdmins>),
+    (
+        14,
+        check_versioned_map::<(Principal, Principal), DelegatedRoles>,
+    ),
+    (
+        15,
+        check_versioned_map::<Principal, PendingAgreementTransfer>,
+    ),
+    (16, check_fixed_size_map::<u64>),
+    (17, check_fixed_size_map::<(Principal, u64)>),
+    (18, check_versioned_map::<(Principal, u64), PendingTransfer>),
+];
+
+/// The memory of a region listed in `MEMORY_REGIONS`.
+fn memory(memory_id: u8) -> VirtualMemory<DefaultMemoryImpl> {
+    assert!(
+        MEMORY_REGIONS.iter().any(|(id, _)| *id == memory_id),
+        "Memory region {memory_id} is missing from MEMORY_REGIONS"
+    );
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)))
+}
+
+/// The progress of a storage check, which runs in chunks.
+struct StorageCheck {
+    report: StorageCheckReport,
+    /// The index in `MEMORY_REGIONS` of the region that is checked next.
+    region_index: usize,
+    /// The encoded key of the last checked entry of that region, if any.
+    after_key: Option<Vec<u8>>,
+}
+
 thread_local! {
 
     static RENTAL_CONDITIONS: RefCell<HashMap<RentalConditionId, RentalConditions>> =
@@ -27,6 +102,12 @@ thread_local! {
 
     static LOCKS: RefCell<Locks> = const {RefCell::new(Locks{ids: BTreeSet::new()}) };
 
+    // The storage check that started with the last upgrade.
+    static STORAGE_CHECK: RefCell<Option<StorageCheck>> = const { RefCell::new(None) };
+
+    // The status of each named timer since the canister was installed or upgraded.
+    static TIMER_STATUS: RefCell<BTreeMap<&'static str, TimerStatus>> = const { RefCell::new(BTreeMap::new()) };
+
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
@@ -34,30 +115,106 @@ thread_local! {
     /// The keys are user principals, because a subnet_id might not be known at request time. Furthermore, only one active
     /// request is allowed per user principal.
     static RENTAL_REQUESTS: RefCell<StableBTreeMap<Principal, RentalRequest, VirtualMemory<DefaultMemoryImpl>>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))));
+        RefCell::new(StableBTreeMap::init(memory(0)));
 
     // Memory region 1
     // Keys are subnet_ids
     static RENTAL_AGREEMENTS: RefCell<StableBTreeMap<Principal, RentalAgreement, VirtualMemory<DefaultMemoryImpl>>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))));
+        RefCell::new(StableBTreeMap::init(memory(1)));
 
     // Memory region 2
     // The current number of events for each principal. Helps with range queries on the history and with pagination.
     static EVENT_COUNTERS: RefCell<StableBTreeMap<Option<Principal>, EventNum, VirtualMemory<DefaultMemoryImpl>>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))));
+        RefCell::new(StableBTreeMap::init(memory(2)));
 
     // Memory region 3
-    // Keys are subnet_id / user principal; or None for changes to rental conditions.
+    // Keys are subnet_id / user principal; or None for global changes, e.g., to rental conditions or exchange rates.
     #[allow(clippy::type_complexity)]
     static HISTORY: RefCell<StableBTreeMap<(Option<Principal>, EventNum), Event, VirtualMemory<DefaultMemoryImpl>>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))));
+        RefCell::new(StableBTreeMap::init(memory(3)));
 
     // Memory region 4
     // Cache for ICP/XDR exchange rates.
     // The keys are timestamps in seconds since epoch (rounded to midnight).
     // The values are (rate, decimal) where the rate is scaled by 10^decimals.
     static RATES: RefCell<StableBTreeMap<u64, (u64, u32), VirtualMemory<DefaultMemoryImpl>>> =
-        RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))));
+        RefCell::new(StableBTreeMap::init(memory(4)));
+
+    // Memory region 5
+    // The canister configuration, set via init and upgrade arguments.
+    static CONFIG: RefCell<StableCell<Config, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableCell::init(memory(5), Config::default())
+            .expect("Failed to initialize the config cell"));
+
+    // Memory region 6
+    // The provenance of the exchange rates cached in RATES, with the same keys.
+    static RATE_PROVENANCE: RefCell<StableBTreeMap<u64, RateProvenance, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(6)));
+
+    // Memory region 7
+    // Binding price quotes, keyed by quote id.
+    static PRICE_QUOTES: RefCell<StableBTreeMap<u64, PriceQuote, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(7)));
+
+    // Memory region 8
+    // The id of the next price quote. Kept separately so that ids of pruned quotes are never reused.
+    static NEXT_PRICE_QUOTE_ID: RefCell<StableCell<u64, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableCell::init(memory(8), 0)
+            .expect("Failed to initialize the price quote id cell"));
+
+    // Memory region 9
+    // Daily billing records, keyed by subnet_id and the UTC midnight starting the day.
+    static BILLING_RECORDS: RefCell<StableBTreeMap<(Principal, u64), BillingRecord, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(9)));
+
+    // Memory region 10
+    // Renters' notification targets, keyed by subnet_id.
+    static NOTIFICATION_REGISTRATIONS: RefCell<StableBTreeMap<Principal, NotificationRegistration, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(10)));
+
+    // Memory region 11
+    // Transfers to the CMC for which no cycles were minted yet,
+    // keyed by user and the `created_at_time` of the transfer.
+    static PENDING_CONVERSIONS: RefCell<StableBTreeMap<(Principal, u64), PendingConversion, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(11)));
+
+    // Memory region 12
+    // The canister ids of the SRC's dependencies, set via init and upgrade arguments.
+    static CANISTER_IDS: RefCell<StableCell<CanisterIds, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableCell::init(memory(12), CanisterIds::default())
+            .expect("Failed to initialize the canister ids cell"));
+
+    // Memory region 13
+    // The admins of rented subnets, keyed by subnet_id. Subnets whose admins the SRC has not
+    // updated yet have no entry. See the `subnet_admins` module.
+    static SUBNET_ADMINS: RefCell<StableBTreeMap<Principal, SubnetAdmins, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(13)));
+
+    // Memory region 14
+    // The roles delegated by renters, keyed by subnet_id and delegate.
+    static DELEGATED_ROLES: RefCell<StableBTreeMap<(Principal, Principal), DelegatedRoles, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(14)));
+
+    // Memory region 15
+    // Proposed transfers of rental agreements to new principals, keyed by subnet_id.
+    static PENDING_AGREEMENT_TRANSFERS: RefCell<StableBTreeMap<Principal, PendingAgreementTransfer, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(15)));
+
+    // Memory region 16
+    // The ids of applied migrations, mapped to the time they were applied at.
+    static APPLIED_MIGRATIONS: RefCell<StableBTreeMap<u64, u64, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(16)));
+
+    // Memory region 17
+    // An index of PRICE_QUOTES by user, keyed by user and quote id.
+    static PRICE_QUOTES_BY_USER: RefCell<StableBTreeMap<(Principal, u64), (), VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(17)));
+
+    // Memory region 18
+    // Refunds and sweeps whose outcome is unknown, keyed by user and the `created_at_time`
+    // of the transfer.
+    static PENDING_TRANSFERS: RefCell<StableBTreeMap<(Principal, u64), PendingTransfer, VirtualMemory<DefaultMemoryImpl>>> =
+        RefCell::new(StableBTreeMap::init(memory(18)));
 }
 
 struct Locks {
@@ -91,6 +248,78 @@ impl Drop for CallerGuard {
     }
 }
 
+/// Returns the number of currently held locks per tag.
+pub fn held_lock_counts() -> BTreeMap<&'static str, u64> {
+    LOCKS.with_borrow(|locks| {
+        let mut counts = BTreeMap::new();
+        for (_, tag) in &locks.ids {
+            *counts.entry(*tag).or_insert(0) += 1;
+        }
+        counts
+    })
+}
+
+/// Records the start of a timer iteration and resets the skips of the last iteration.
+pub fn record_timer_start(name: &'static str, now_nanos: u64) {
+    TIMER_STATUS.with_borrow_mut(|statuses| {
+        let status = statuses.entry(name).or_insert_with(|| TimerStatus {
+            name: name.to_string(),
+            last_start_time_nanos: now_nanos,
+            last_end_time_nanos: None,
+            skipped_entries_last_run: 0,
+            skipped_entries_total: 0,
+            last_error: None,
+        });
+        status.last_start_time_nanos = now_nanos;
+        status.skipped_entries_last_run = 0;
+    });
+}
+
+pub fn record_timer_end(name: &'static str, now_nanos: u64) {
+    update_timer_status(name, |status| status.last_end_time_nanos = Some(now_nanos));
+}
+
+/// Records that the current iteration of a timer skipped an entry because its lock was held.
+pub fn record_timer_skip(name: &'static str) {
+    update_timer_status(name, |status| {
+        status.skipped_entries_last_run += 1;
+        status.skipped_entries_total += 1;
+    });
+}
+
+pub fn record_timer_error(name: &'static str, now_nanos: u64, message: String) {
+    update_timer_status(name, |status| {
+        status.last_error = Some(TimerError {
+            time_nanos: now_nanos,
+            message,
+        })
+    });
+}
+
+/// Only timers that have started are tracked.
+fn update_timer_status(name: &'static str, update: impl FnOnce(&mut TimerStatus)) {
+    TIMER_STATUS.with_borrow_mut(|statuses| {
+        if let Some(status) = statuses.get_mut(name) {
+            update(status);
+        }
+    });
+}
+
+pub fn get_timer_statuses() -> Vec<TimerStatus> {
+    TIMER_STATUS.with_borrow(|statuses| statuses.values().cloned().collect())
+}
+
+/// Returns the stable memory used by each memory region.
+pub fn memory_region_usage() -> Vec<MemoryRegionUsage> {
+    MEMORY_REGIONS
+        .iter()
+        .map(|&(memory_id, _)| MemoryRegionUsage {
+            memory_id,
+            size_pages: memory(memory_id).size(),
+        })
+        .collect()
+}
+
 // ====================================================================================================================
 
 pub fn get_rental_conditions(key: RentalConditionId) -> Option<RentalConditions> {
@@ -105,6 +334,29 @@ pub fn iter_rental_conditions() -> Vec<(RentalConditionId, RentalConditions)> {
     RENTAL_CONDITIONS.with_borrow(|map| map.iter().map(|(k, v)| (*k, v.clone())).collect())
 }
 
+pub fn get_config() -> Config {
+    CONFIG.with_borrow(|cell| cell.get().clone())
+}
+
+pub fn update_config(transform_config: impl FnOnce(Config) -> Config) {
+    CONFIG.with_borrow_mut(|cell| {
+        let config = transform_config(cell.get().clone());
+        cell.set(config).expect("Failed to persist the config");
+    });
+}
+
+pub fn get_canister_ids() -> CanisterIds {
+    CANISTER_IDS.with_borrow(|cell| cell.get().clone())
+}
+
+pub fn update_canister_ids(transform_canister_ids: impl FnOnce(CanisterIds) -> CanisterIds) {
+    CANISTER_IDS.with_borrow_mut(|cell| {
+        let canister_ids = transform_canister_ids(cell.get().clone());
+        cell.set(canister_ids)
+            .expect("Failed to persist the canister ids");
+    });
+}
+
 pub fn get_rental_request(user: &Principal) -> Option<RentalRequest> {
     RENTAL_REQUESTS.with_borrow(|map| map.get(user))
 }
@@ -157,8 +409,131 @@ pub fn get_cached_rate(time: u64) -> Option<(u64, u32)> {
     RATES.with_borrow(|map| map.get(&time))
 }
 
-pub fn cache_rate(time: u64, rate: u64, decimals: u32) {
-    RATES.with_borrow_mut(|map| map.insert(time, (rate, decimals)));
+/// Caches a rate and its provenance. Returns the previously cached rate, if any.
+pub fn cache_rate(
+    time: u64,
+    rate: u64,
+    decimals: u32,
+    provenance: RateProvenance,
+) -> Option<(u64, u32)> {
+    RATE_PROVENANCE.with_borrow_mut(|map| map.insert(time, provenance));
+    RATES.with_borrow_mut(|map| map.insert(time, (rate, decimals)))
+}
+
+pub fn count_cached_rates() -> u64 {
+    RATES.with_borrow(|map| map.len())
+}
+
+/// Returns the cached rates with keys in the inclusive range `[from, to]`.
+pub fn list_cached_rates(from: u64, to: u64) -> Vec<CachedRate> {
+    if from > to {
+        return vec![];
+    }
+    RATES.with_borrow(|rates| {
+        RATE_PROVENANCE.with_borrow(|provenance| {
+            rates
+                .range(from..=to)
+                .map(|(time_secs, (rate, decimals))| CachedRate {
+                    time_secs,
+                    rate,
+                    decimals,
+                    provenance: provenance.get(&time_secs),
+                })
+                .collect()
+        })
+    })
+}
+
+/// Removes all cached rates with keys before `cutoff`. Returns the number of removed rates.
+pub fn remove_cached_rates_before(cutoff: u64) -> usize {
+    let keys: Vec<u64> = RATES.with_borrow(|map| map.range(..cutoff).map(|(k, _)| k).collect());
+    RATES.with_borrow_mut(|rates| {
+        RATE_PROVENANCE.with_borrow_mut(|provenance| {
+            for key in keys.iter() {
+                rates.remove(key);
+                provenance.remove(key);
+            }
+        })
+    });
+    keys.len()
+}
+
+/// Returns the creation time of the oldest stored price quote. Ids are assigned in the order
+/// of creation, so this is the quote with the smallest id.
+pub fn oldest_price_quote_creation_nanos() -> Option<u64> {
+    PRICE_QUOTES.with_borrow(|map| {
+        map.iter()
+            .next()
+            .map(|(_, price_quote)| price_quote.creation_time_nanos)
+    })
+}
+
+/// Persist a new price quote under the next unused id and return it.
+pub fn insert_price_quote(make_price_quote: impl FnOnce(u64) -> PriceQuote) -> PriceQuote {
+    let id = NEXT_PRICE_QUOTE_ID.with_borrow_mut(|cell| {
+        let id = *cell.get();
+        cell.set(id + 1)
+            .expect("Failed to persist the next price quote id");
+        id
+    });
+    let price_quote = make_price_quote(id);
+    PRICE_QUOTES.with_borrow_mut(|map| map.insert(id, price_quote.clone()));
+    PRICE_QUOTES_BY_USER.with_borrow_mut(|map| map.insert((price_quote.user, id), ()));
+    price_quote
+}
+
+pub fn get_price_quote(id: u64) -> Option<PriceQuote> {
+    PRICE_QUOTES.with_borrow(|map| map.get(&id))
+}
+
+pub fn remove_price_quote(id: u64) -> Option<PriceQuote> {
+    let price_quote = PRICE_QUOTES.with_borrow_mut(|map| map.remove(&id))?;
+    PRICE_QUOTES_BY_USER.with_borrow_mut(|map| map.remove(&(price_quote.user, id)));
+    Some(price_quote)
+}
+
+// Quotes are created with a fixed validity, so later ids expire later. This bounds the
+// following scans by the number of unexpired quotes rather than all stored ones.
+
+/// Returns the number of the user's price quotes that expire after `now_nanos`.
+pub fn count_open_price_quotes_of(user: Principal, now_nanos: u64) -> usize {
+    PRICE_QUOTES_BY_USER.with_borrow(|index| {
+        PRICE_QUOTES.with_borrow(|quotes| {
+            index
+                .range((user, 0)..=(user, u64::MAX))
+                .rev()
+                .map_while(|((_, id), _)| quotes.get(&id))
+                .take_while(|quote| quote.expiry_time_nanos > now_nanos)
+                .count()
+        })
+    })
+}
+
+/// Returns the number of price quotes that expire after `now_nanos`, counting at most `limit`.
+pub fn count_open_price_quotes(now_nanos: u64, limit: usize) -> usize {
+    PRICE_QUOTES.with_borrow(|quotes| {
+        quotes
+            .iter()
+            .rev()
+            .take_while(|(_, quote)| quote.expiry_time_nanos > now_nanos)
+            .take(limit)
+            .count()
+    })
+}
+
+/// Removes the price quotes that expired before `cutoff_nanos` and returns how many there were.
+pub fn remove_price_quotes_expired_before(cutoff_nanos: u64) -> usize {
+    let ids: Vec<u64> = PRICE_QUOTES.with_borrow(|quotes| {
+        quotes
+            .iter()
+            .take_while(|(_, quote)| quote.expiry_time_nanos < cutoff_nanos)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in &ids {
+        remove_price_quote(*id);
+    }
+    ids.len()
 }
 
 /// Returns the next unused sequence number for the given principal and increases
@@ -217,6 +592,27 @@ pub fn get_history_page(
     (page, low_seq)
 }
 
+/// Returns the number of the principal's first event at or after `time_nanos`, or the number
+/// the principal's next event will get if there is none. Events are numbered in time order.
+pub fn first_seq_since(principal: Option<Principal>, time_nanos: u64) -> u64 {
+    let end = get_current_seq(principal).map_or(0, |seq| seq + 1);
+    HISTORY.with_borrow(|map| {
+        let (mut low, mut high) = (0, end);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if map
+                .get(&(principal, mid))
+                .is_some_and(|event| event.time_nanos() < time_nanos)
+            {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    })
+}
+
 /// Create a RentalRequest if it does not already exist, and persist the corresponding event.
 pub fn persist_rental_request(rental_request: RentalRequest) -> Result<(), String> {
     RENTAL_REQUESTS.with_borrow_mut(|requests| {
@@ -258,12 +654,491 @@ pub fn persist_rental_agreement(rental_agreement: RentalAgreement) -> Result<(),
     })
 }
 
+pub fn insert_billing_record(record: BillingRecord) {
+    BILLING_RECORDS
+        .with_borrow_mut(|map| map.insert((record.subnet_id, record.day_start_secs), record));
+}
+
+/// Returns the most recent billing record of the given subnet.
+pub fn get_latest_billing_record(subnet_id: Principal) -> Option<BillingRecord> {
+    BILLING_RECORDS.with_borrow(|map| {
+        map.range((subnet_id, 0)..=(subnet_id, u64::MAX))
+            .next_back()
+            .map(|(_, record)| record)
+    })
+}
+
+/// Returns up to `limit` billing records of the given subnet for days starting at or after `from`.
+pub fn list_billing_records_since(
+    subnet_id: Principal,
+    from: u64,
+    limit: u64,
+) -> Vec<BillingRecord> {
+    BILLING_RECORDS.with_borrow(|map| {
+        map.range((subnet_id, from)..=(subnet_id, u64::MAX))
+            .take(limit as usize)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+/// Returns the billing records of the given subnet for days starting in the inclusive range `[from, to]`.
+pub fn list_billing_records(subnet_id: Principal, from: u64, to: u64) -> Vec<BillingRecord> {
+    if from > to {
+        return vec![];
+    }
+    BILLING_RECORDS.with_borrow(|map| {
+        map.range((subnet_id, from)..=(subnet_id, to))
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+pub fn get_notification_registration(subnet_id: &Principal) -> Option<NotificationRegistration> {
+    NOTIFICATION_REGISTRATIONS.with_borrow(|map| map.get(subnet_id))
+}
+
+/// Sets the notification registration of a subnet, or removes it if `None` is given.
+pub fn set_notification_registration(
+    subnet_id: Principal,
+    registration: Option<NotificationRegistration>,
+) {
+    NOTIFICATION_REGISTRATIONS.with_borrow_mut(|map| match registration {
+        Some(registration) => map.insert(subnet_id, registration),
+        None => map.remove(&subnet_id),
+    });
+}
+
+pub fn iter_notification_registrations() -> Vec<(Principal, NotificationRegistration)> {
+    NOTIFICATION_REGISTRATIONS.with_borrow(|map| map.iter().collect())
+}
+
+/// Inserts a pending conversion or replaces the one of the same transfer.
+pub fn insert_pending_conversion(pending_conversion: PendingConversion) {
+    PENDING_CONVERSIONS.with_borrow_mut(|map| {
+        map.insert(
+            (
+                pending_conversion.user,
+                pending_conversion.created_at_time_nanos,
+            ),
+            pending_conversion,
+        )
+    });
+}
+
+pub fn remove_pending_conversion(
+    user: Principal,
+    created_at_time_nanos: u64,
+) -> Option<PendingConversion> {
+    PENDING_CONVERSIONS.with_borrow_mut(|map| map.remove(&(user, created_at_time_nanos)))
+}
+
+pub fn iter_pending_conversions() -> Vec<PendingConversion> {
+    PENDING_CONVERSIONS.with_borrow(|map| map.values().collect())
+}
+
+pub fn insert_pending_transfer(pending_transfer: PendingTransfer) {
+    PENDING_TRANSFERS.with_borrow_mut(|map| {
+        map.insert(
+            (
+                pending_transfer.user,
+                pending_transfer.created_at_time_nanos,
+            ),
+            pending_transfer,
+        )
+    });
+}
+
+pub fn remove_pending_transfer(
+    user: Principal,
+    created_at_time_nanos: u64,
+) -> Option<PendingTransfer> {
+    PENDING_TRANSFERS.with_borrow_mut(|map| map.remove(&(user, created_at_time_nanos)))
+}
+
+pub fn iter_pending_transfers() -> Vec<PendingTransfer> {
+    PENDING_TRANSFERS.with_borrow(|map| map.values().collect())
+}
+
+/// Returns the admins of a subnet, or None if the SRC does not track them yet.
+pub fn get_subnet_admins(subnet_id: &Principal) -> Option<BTreeSet<Principal>> {
+    SUBNET_ADMINS
+        .with_borrow(|map| map.get(subnet_id))
+        .map(|subnet_admins| subnet_admins.admins)
+}
+
+/// Sets the admins of a subnet. An empty set is kept, since it means the subnet has no admins.
+pub fn set_subnet_admins(subnet_id: Principal, admins: BTreeSet<Principal>) {
+    SUBNET_ADMINS.with_borrow_mut(|map| map.insert(subnet_id, SubnetAdmins { admins }));
+}
+
+pub fn get_delegated_roles(subnet_id: Principal, principal: Principal) -> BTreeSet<AgreementRole> {
+    DELEGATED_ROLES
+        .with_borrow(|map| map.get(&(subnet_id, principal)))
+        .unwrap_or_default()
+        .roles
+}
+
+/// Sets the roles delegated to a principal. An empty set removes the entry.
+pub fn set_delegated_roles(
+    subnet_id: Principal,
+    principal: Principal,
+    roles: BTreeSet<AgreementRole>,
+) {
+    DELEGATED_ROLES.with_borrow_mut(|map| {
+        if roles.is_empty() {
+            map.remove(&(subnet_id, principal))
+        } else {
+            map.insert((subnet_id, principal), DelegatedRoles { roles })
+        }
+    });
+}
+
+/// Lists the delegates of a subnet with their roles.
+pub fn list_delegated_roles(subnet_id: Principal) -> Vec<(Principal, BTreeSet<AgreementRole>)> {
+    DELEGATED_ROLES.with_borrow(|map| {
+        // The empty principal sorts first.
+        map.range((subnet_id, Principal::from_slice(&[]))..)
+            .take_while(|((subnet, _), _)| *subnet == subnet_id)
+            .map(|((_, principal), delegated)| (principal, delegated.roles))
+            .collect()
+    })
+}
+
+pub fn get_pending_agreement_transfer(subnet_id: &Principal) -> Option<PendingAgreementTransfer> {
+    PENDING_AGREEMENT_TRANSFERS.with_borrow(|map| map.get(subnet_id))
+}
+
+/// Sets the proposed transfer of a subnet's rental agreement, or removes it if `None` is given.
+pub fn set_pending_agreement_transfer(
+    subnet_id: Principal,
+    transfer: Option<PendingAgreementTransfer>,
+) {
+    PENDING_AGREEMENT_TRANSFERS.with_borrow_mut(|map| match transfer {
+        Some(transfer) => map.insert(subnet_id, transfer),
+        None => map.remove(&subnet_id),
+    });
+}
+
+pub fn get_migration_applied_time(id: u64) -> Option<u64> {
+    APPLIED_MIGRATIONS.with_borrow(|map| map.get(&id))
+}
+
+pub fn set_migration_applied(id: u64, time_nanos: u64) {
+    APPLIED_MIGRATIONS.with_borrow_mut(|map| map.insert(id, time_nanos));
+}
+
+/// Starts a check that decodes every entry of every memory region and records the failures, so
+/// that entries that an upgrade broke are noticed right away rather than when a timer traps on
+/// them later. The check runs in chunks via `check_storage_chunk`, since the regions can be too
+/// large to decode within a single message.
+pub fn start_storage_check(now_nanos: u64) {
+    STORAGE_CHECK.with_borrow_mut(|check| {
+        *check = Some(StorageCheck {
+            report: StorageCheckReport {
+                time_nanos: now_nanos,
+                finished_time_nanos: None,
+                entries_checked: 0,
+                failures: vec![],
+            },
+            region_index: 0,
+            after_key: None,
+        })
+    });
+}
+
+/// Continues the storage check with up to `max_entries` entries, which must be positive. Returns whether the check is
+/// finished, or true if there is no check.
+/// The regions are read through views with raw values, so a broken entry does not trap.
+pub fn check_storage_chunk(now_nanos: u64, max_entries: u64) -> bool {
+    STORAGE_CHECK.with_borrow_mut(|check| {
+        let Some(check) = check
+            .as_mut()
+            .filter(|check| check.report.finished_time_nanos.is_none())
+        else {
+            return true;
+        };
+        let mut remaining = max_entries;
+        while remaining > 0 {
+            let Some((memory_id, check_region)) = MEMORY_REGIONS.get(check.region_index) else {
+                check.report.finished_time_nanos = Some(now_nanos);
+                return true;
+            };
+            let checked_before = check.report.entries_checked;
+            let after_key = check_region(
+                *memory_id,
+                check.after_key.as_deref(),
+                remaining,
+                &mut check.report,
+            );
+            remaining -= check.report.entries_checked - checked_before;
+            match after_key {
+                Some(after_key) => check.after_key = Some(after_key),
+                None => {
+                    check.region_index += 1;
+                    check.after_key = None;
+                }
+            }
+        }
+        false
+    })
+}
+
+pub fn get_storage_check() -> Option<StorageCheckReport> {
+    STORAGE_CHECK.with_borrow(|check| check.as_ref().map(|check| check.report.clone()))
+}
+
+fn check_versioned<T: Versioned>(bytes: &[u8]) -> Result<(), String> {
+    versioned::decode::<T>(bytes).map(|_| ())
+}
+
+/// Integers and tuples of integers decode from any bytes of the right size.
+fn check_fixed_size(_bytes: &[u8]) -> Result<(), String> {
+    Ok(())
+}
+
+fn check_versioned_map<K: Storable + Ord + Clone + Debug, V: Versioned>(
+    memory_id: u8,
+    after_key: Option<&[u8]>,
+    max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>> {
+    check_map::<K>(
+        memory_id,
+        check_versioned::<V>,
+        after_key,
+        max_entries,
+        report,
+    )
+}
+
+fn check_fixed_size_map<K: Storable + Ord + Clone + Debug>(
+    memory_id: u8,
+    after_key: Option<&[u8]>,
+    max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>> {
+    check_map::<K>(memory_id, check_fixed_size, after_key, max_entries, report)
+}
+
+fn check_versioned_cell<V: Versioned>(
+    memory_id: u8,
+    after_key: Option<&[u8]>,
+    max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>> {
+    check_cell(
+        memory_id,
+        check_versioned::<V>,
+        after_key,
+        max_entries,
+        report,
+    )
+}
+
+fn check_fixed_size_cell(
+    memory_id: u8,
+    after_key: Option<&[u8]>,
+    max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>> {
+    check_cell(memory_id, check_fixed_size, after_key, max_entries, report)
+}
+
+/// Returns the memory of a region, or None if the region was never used.
+fn used_region(memory_id: u8) -> Option<VirtualMemory<DefaultMemoryImpl>> {
+    let memory = memory(memory_id);
+    (memory.size() > 0).then_some(memory)
+}
+
+fn check_map<K: Storable + Ord + Clone + Debug>(
+    memory_id: u8,
+    check_value: fn(&[u8]) -> Result<(), String>,
+    after_key: Option<&[u8]>,
+    max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>> {
+    let memory = used_region(memory_id)?;
+    let map: StableBTreeMap<K, Vec<u8>, _> = StableBTreeMap::load(memory);
+    let start = match after_key {
+        Some(bytes) => Bound::Excluded(K::from_bytes(Cow::Borrowed(bytes))),
+        None => Bound::Unbounded,
+    };
+    let mut last_key = None;
+    for (checked, (key, bytes)) in map.range((start, Bound::Unbounded)).enumerate() {
+        if checked as u64 == max_entries {
+            return last_key.map(|key: K| key.to_bytes().into_owned());
+        }
+        report.entries_checked += 1;
+        if let Err(error) = check_value(&bytes) {
+            report.failures.push(StorageCheckFailure {
+                memory_id,
+                key: format!("{key:?}"),
+                error,
+            });
+        }
+        last_key = Some(key);
+    }
+    None
+}
+
+/// A cell is a single entry, which is checked in one go.
+fn check_cell(
+    memory_id: u8,
+    check_value: fn(&[u8]) -> Result<(), String>,
+    _after_key: Option<&[u8]>,
+    _max_entries: u64,
+    report: &mut StorageCheckReport,
+) -> Option<Vec<u8>> {
+    let memory = used_region(memory_id)?;
+    report.entries_checked += 1;
+    // Loads the existing value; the default is only written to empty memory.
+    let result = StableCell::<Vec<u8>, _>::init(memory, vec![])
+        .map_err(|e| format!("{e:?}"))
+        .and_then(|cell| check_value(cell.get()));
+    if let Err(error) = result {
+        report.failures.push(StorageCheckFailure {
+            memory_id,
+            key: String::new(),
+            error,
+        });
+    }
+    None
+}
+
 #[cfg(test)]
 mod canister_state_test {
     use super::*;
     use crate::history::EventType;
     use ic_ledger_types::Tokens;
 
+    #[test]
+    fn test_rate_cache_range_and_pruning() {
+        const DAY: u64 = 86_400;
+        for day in 1..=5 {
+            cache_rate(day * DAY, day, 9, RateProvenance::Governance);
+        }
+        assert_eq!(
+            cache_rate(3 * DAY, 33, 4, RateProvenance::Governance),
+            Some((3, 9))
+        );
+
+        let rates = list_cached_rates(2 * DAY, 4 * DAY);
+        assert_eq!(
+            rates
+                .iter()
+                .map(|r| (r.time_secs, r.rate))
+                .collect::<Vec<_>>(),
+            vec![(2 * DAY, 2), (3 * DAY, 33), (4 * DAY, 4)]
+        );
+        assert_eq!(rates[1].decimals, 4);
+        assert_eq!(rates[1].provenance, Some(RateProvenance::Governance));
+        assert!(list_cached_rates(4 * DAY, 2 * DAY).is_empty());
+
+        assert_eq!(remove_cached_rates_before(3 * DAY), 2);
+        assert_eq!(get_cached_rate(2 * DAY), None);
+        assert_eq!(get_cached_rate(3 * DAY), Some((33, 4)));
+        assert_eq!(list_cached_rates(0, u64::MAX).len(), 3);
+    }
+
+    #[test]
+    fn test_storage_check_reports_broken_entries() {
+        let subnet_id = Principal::from_slice(&[1]);
+        set_subnet_admins(subnet_id, BTreeSet::from([subnet_id]));
+        let raw_map =
+            |id: u8| -> StableBTreeMap<Principal, Vec<u8>, _> { StableBTreeMap::init(memory(id)) };
+        // An entry written before envelopes existed.
+        raw_map(13).insert(
+            Principal::from_slice(&[2]),
+            candid::encode_one(SubnetAdmins::default()).unwrap(),
+        );
+        raw_map(15).insert(subnet_id, b"garbage".to_vec());
+
+        start_storage_check(7);
+        // One entry per chunk, so that the check resumes within region 13.
+        assert!(!check_storage_chunk(8, 1));
+        assert!(!check_storage_chunk(8, 1));
+        let report = get_storage_check().unwrap();
+        assert_eq!(report.entries_checked, 2);
+        assert_eq!(report.finished_time_nanos, None);
+        while !check_storage_chunk(9, 1) {}
+
+        let report = get_storage_check().unwrap();
+        assert_eq!(report.time_nanos, 7);
+        assert_eq!(report.finished_time_nanos, Some(9));
+        assert_eq!(report.entries_checked, 3);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].memory_id, 15);
+        assert_eq!(report.failures[0].key, format!("{subnet_id:?}"));
+        // A finished check stays as it is.
+        assert!(check_storage_chunk(10, 1));
+        assert_eq!(get_storage_check(), Some(report));
+    }
+
+    #[test]
+    fn test_price_quotes_are_counted_per_user_and_pruned() {
+        let user_1 = Principal::from_slice(b"user_1");
+        let user_2 = Principal::from_slice(b"user_2");
+        for (user, expiry_time_nanos) in [(user_1, 10), (user_2, 20), (user_1, 30), (user_1, 40)] {
+            insert_price_quote(|id| PriceQuote {
+                id,
+                user,
+                rental_condition_id: RentalConditionId::App13CH,
+                cycles: 1,
+                icp: Tokens::from_e8s(1),
+                scaled_exchange_rate_xdr_per_icp: 1,
+                decimals: 0,
+                creation_time_nanos: 0,
+                expiry_time_nanos,
+            });
+        }
+        assert_eq!(count_open_price_quotes_of(user_1, 15), 2);
+        assert_eq!(count_open_price_quotes_of(user_2, 15), 1);
+        assert_eq!(count_open_price_quotes(15, 10), 3);
+        assert_eq!(count_open_price_quotes(15, 2), 2);
+
+        assert_eq!(remove_price_quotes_expired_before(25), 2);
+        assert_eq!(count_open_price_quotes_of(user_2, 0), 0);
+        assert_eq!(count_open_price_quotes_of(user_1, 0), 2);
+    }
+
+    #[test]
+    fn test_delegated_roles_are_listed_per_subnet() {
+        let subnet = |id: u8| Principal::from_slice(&[id]);
+        let delegate = |id: u8| Principal::from_slice(&[0, id]);
+        set_delegated_roles(
+            subnet(1),
+            delegate(1),
+            BTreeSet::from([AgreementRole::TopUp]),
+        );
+        set_delegated_roles(
+            subnet(2),
+            delegate(1),
+            BTreeSet::from([AgreementRole::TopUp]),
+        );
+        set_delegated_roles(
+            subnet(2),
+            delegate(2),
+            BTreeSet::from([AgreementRole::ManageSubnetAdmins]),
+        );
+
+        assert_eq!(
+            list_delegated_roles(subnet(2)),
+            vec![
+                (delegate(1), BTreeSet::from([AgreementRole::TopUp])),
+                (
+                    delegate(2),
+                    BTreeSet::from([AgreementRole::ManageSubnetAdmins])
+                ),
+            ]
+        );
+
+        set_delegated_roles(subnet(2), delegate(1), BTreeSet::new());
+        assert!(get_delegated_roles(subnet(2), delegate(1)).is_empty());
+        assert_eq!(list_delegated_roles(subnet(2)).len(), 1);
+        assert_eq!(list_delegated_roles(subnet(1)).len(), 1);
+    }
+
     #[test]
     fn test_history_pagination() {
         fn make_event(time_nanos: u64) -> Event {
@@ -309,5 +1184,53 @@ mod canister_state_test {
         let (events, oldest) = get_history_page(Some(Principal::anonymous()), Some(3), 2);
         assert!(events.is_empty());
         assert_eq!(oldest, 1); // because 3 - 2 = 1
+
+        // events are found by time
+        assert_eq!(first_seq_since(None, 0), 0);
+        assert_eq!(first_seq_since(None, 3), 2);
+        assert_eq!(first_seq_since(None, 6), 5);
+        assert_eq!(first_seq_since(Some(Principal::anonymous()), 3), 0);
+    }
+
+    #[test]
+    fn test_timer_status_tracks_iterations() {
+        // Nothing is recorded for a timer that has not started.
+        record_timer_skip("test_timer");
+        assert!(get_timer_statuses().is_empty());
+
+        record_timer_start("test_timer", 10);
+        record_timer_skip("test_timer");
+        record_timer_skip("test_timer");
+        record_timer_error("test_timer", 11, "failed".to_string());
+        record_timer_end("test_timer", 12);
+        record_timer_start("test_timer", 20);
+        record_timer_skip("test_timer");
+
+        assert_eq!(
+            get_timer_statuses(),
+            vec![TimerStatus {
+                name: "test_timer".to_string(),
+                last_start_time_nanos: 20,
+                last_end_time_nanos: Some(12),
+                skipped_entries_last_run: 1,
+                skipped_entries_total: 3,
+                last_error: Some(TimerError {
+                    time_nanos: 11,
+                    message: "failed".to_string(),
+                }),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_memory_regions_are_unique() {
+        let ids: BTreeSet<u8> = MEMORY_REGIONS.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids.len(), MEMORY_REGIONS.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "missing from MEMORY_REGIONS")]
+    fn test_memory_outside_the_table_is_refused() {
+        memory(200);
     }
 }
@@ -0,0 +1,176 @@
+//! Moving a rental agreement to another rental condition.
+//!
+//! The cycles that the renter has paid for but that were not burned yet are repriced at the
+//! daily cost of the new condition. This works in both directions: switching to a cheaper
+//! condition extends the agreement, switching to a more expensive one shortens it. No payment
+//! is involved. The new deadline is `now + remaining_cycles / new_daily_cost` rather than a
+//! scaling of the old one, because cycles burned before the switch were charged at the old rate.
+
+use crate::{
+    canister_state::{get_rental_conditions, persist_event, update_rental_agreement},
+    history::EventType,
+    RentalAgreement, RentalConditionId, BILLION, SECONDS_PER_DAY,
+};
+use candid::Principal;
+
+/// The effect of moving a rental agreement to another rental condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConditionSwitch {
+    pub subnet_id: Principal,
+    pub user: Principal,
+    pub old_condition_id: RentalConditionId,
+    pub new_condition_id: RentalConditionId,
+    pub cycles_remaining: u128,
+    pub old_paid_until_nanos: u64,
+    pub new_paid_until_nanos: u64,
+}
+
+impl ConditionSwitch {
+    pub fn describe(&self) -> String {
+        format!(
+            "Subnet {} from {:?} to {:?}: {} cycles paid until {} (was {})",
+            self.subnet_id,
+            self.old_condition_id,
+            self.new_condition_id,
+            self.cycles_remaining,
+            self.new_paid_until_nanos,
+            self.old_paid_until_nanos
+        )
+    }
+}
+
+/// Computes the effect of moving `agreement` to `new_condition_id` at `now_nanos`
+/// without changing any state.
+pub fn plan(
+    agreement: &RentalAgreement,
+    new_condition_id: RentalConditionId,
+    now_nanos: u64,
+) -> Result<ConditionSwitch, String> {
+    if agreement.rental_condition_id == new_condition_id {
+        return Err(format!(
+            "Subnet {} is already on {:?}",
+            agreement.subnet_id, new_condition_id
+        ));
+    }
+    let new_conditions = get_rental_conditions(new_condition_id)
+        .ok_or_else(|| format!("Rental conditions {new_condition_id:?} not found"))?;
+    let cycles_remaining = agreement
+        .total_cycles_created
+        .saturating_sub(agreement.total_cycles_burned);
+    Ok(ConditionSwitch {
+        subnet_id: agreement.subnet_id,
+        user: agreement.user,
+        old_condition_id: agreement.rental_condition_id,
+        new_condition_id,
+        cycles_remaining,
+        old_paid_until_nanos: agreement.paid_until_nanos,
+        new_paid_until_nanos: reprice(
+            cycles_remaining,
+            new_conditions.daily_cost_cycles,
+            now_nanos,
+        ),
+    })
+}
+
+/// Applies a planned switch to the rental agreement and records it in the subnet's history.
+pub fn apply(switch: &ConditionSwitch) -> Result<(), String> {
+    update_rental_agreement(switch.subnet_id, |mut agreement| {
+        agreement.rental_condition_id = switch.new_condition_id;
+        agreement.paid_until_nanos = switch.new_paid_until_nanos;
+        agreement
+    })?;
+    persist_event(
+        EventType::RentalConditionSwitched {
+            user: switch.user,
+            old_condition_id: switch.old_condition_id,
+            new_condition_id: switch.new_condition_id,
+            cycles_remaining: switch.cycles_remaining,
+            old_paid_until_nanos: switch.old_paid_until_nanos,
+            new_paid_until_nanos: switch.new_paid_until_nanos,
+        },
+        Some(switch.subnet_id),
+    );
+    Ok(())
+}
+
+/// How long `cycles_remaining` lasts at `daily_cost_cycles`, as a deadline from `now_nanos`.
+///
+/// Truncating to a per-second cost makes a day cost marginally less than
+/// `daily_cost_cycles`, so the deadline is fractionally generous. Same rounding as the
+/// top-up path.
+pub fn reprice(cycles_remaining: u128, daily_cost_cycles: u128, now_nanos: u64) -> u64 {
+    let cost_cycles_per_second = daily_cost_cycles / (SECONDS_PER_DAY as u128);
+    if cost_cycles_per_second == 0 {
+        return u64::MAX;
+    }
+    let seconds_covered = cycles_remaining / cost_cycles_per_second;
+    (seconds_covered.saturating_mul(BILLION as u128))
+        .try_into()
+        .map_or(u64::MAX, |nanos: u64| now_nanos.saturating_add(nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TRILLION;
+
+    const APP13CH_DAILY: u128 = 820 * TRILLION;
+    const APP7CH_DAILY: u128 = 440 * TRILLION;
+    const DAY_NANOS: u64 = SECONDS_PER_DAY * BILLION;
+
+    #[test]
+    fn reprice_is_exact_for_whole_days() {
+        assert_eq!(reprice(10 * APP7CH_DAILY, APP7CH_DAILY, 0), 10 * DAY_NANOS);
+    }
+
+    #[test]
+    fn reprice_offsets_from_now() {
+        let now = 12_345 * DAY_NANOS;
+        let cycles = 10 * APP7CH_DAILY;
+        assert_eq!(reprice(cycles, APP7CH_DAILY, now), now + 10 * DAY_NANOS);
+    }
+
+    #[test]
+    fn cheaper_condition_buys_more_time() {
+        // 180 * 820 / 440 = 335.45
+        let days = reprice(180 * APP13CH_DAILY, APP7CH_DAILY, 0) / DAY_NANOS;
+        assert_eq!(days, 335);
+    }
+
+    #[test]
+    fn pricier_condition_buys_less_time() {
+        // 180 * 440 / 820 = 96.59
+        let days = reprice(180 * APP7CH_DAILY, APP13CH_DAILY, 0) / DAY_NANOS;
+        assert_eq!(days, 96);
+    }
+
+    #[test]
+    fn reprice_credits_only_whole_seconds() {
+        let cost_per_second = APP7CH_DAILY / (SECONDS_PER_DAY as u128);
+        // A truncated per-second cost undercharges the day, so just under a day's
+        // worth still covers the full day.
+        assert!(cost_per_second * (SECONDS_PER_DAY as u128) < APP7CH_DAILY);
+        assert_eq!(reprice(APP7CH_DAILY - 1, APP7CH_DAILY, 0), DAY_NANOS);
+
+        let cycles = cost_per_second * (SECONDS_PER_DAY as u128 - 1);
+        assert_eq!(reprice(cycles, APP7CH_DAILY, 0), DAY_NANOS - BILLION);
+
+        let cycles = APP7CH_DAILY + APP7CH_DAILY / 2;
+        assert_eq!(reprice(cycles, APP7CH_DAILY, 0) / DAY_NANOS, 1);
+    }
+
+    #[test]
+    fn no_cycles_left_means_no_time_left() {
+        let now = 99 * DAY_NANOS;
+        assert_eq!(reprice(0, APP7CH_DAILY, 0), 0);
+        assert_eq!(reprice(0, APP7CH_DAILY, now), now);
+    }
+
+    #[test]
+    fn reprice_saturates_instead_of_overflowing() {
+        assert_eq!(reprice(u128::MAX, APP7CH_DAILY, 0), u64::MAX);
+        assert_eq!(reprice(10 * APP7CH_DAILY, APP7CH_DAILY, u64::MAX), u64::MAX);
+        // A daily cost below one cycle per second would divide by zero.
+        assert_eq!(reprice(APP7CH_DAILY, 1, 0), u64::MAX);
+    }
+}
@@ -0,0 +1,257 @@
+//! Read-only HTML pages served via `http_request`, so that the state of the canister can be
+//! checked in a browser.
+//!
+//! The pages render the same data as the corresponding queries; all text taken from the state
+//! is escaped.
+//!
+//! The responses skip certification, see `http_certification`, so the pages load on the
+//! canister's normal domain, e.g. `https://<canister id>.icp0.io/agreements`.
+
+use crate::{
+    history::Event, Principal, RentalAgreement, RentalConditionId, RentalConditions, RentalRequest,
+    BILLION, SECONDS_PER_DAY,
+};
+use std::fmt::Write;
+
+/// Same data as `list_rental_agreements`, along with the coverage of each agreement.
+pub fn agreements_page(agreements: &[RentalAgreement], now_nanos: u64) -> String {
+    let rows = agreements
+        .iter()
+        .map(|agreement| {
+            vec![
+                agreement.subnet_id.to_string(),
+                agreement.user.to_string(),
+                format!("{:?}", agreement.rental_condition_id),
+                format_time(agreement.creation_time_nanos),
+                format_time(agreement.paid_until_nanos),
+                coverage(agreement.paid_until_nanos, now_nanos),
+                agreement.total_icp_paid.to_string(),
+                agreement.total_cycles_created.to_string(),
+                agreement.total_cycles_burned.to_string(),
+            ]
+        })
+        .collect();
+    page(
+        "Rental agreements",
+        &table(
+            &[
+                "Subnet",
+                "User",
+                "Rental condition",
+                "Created",
+                "Paid until",
+                "Coverage",
+                "ICP paid",
+                "Cycles created",
+                "Cycles burned",
+            ],
+            rows,
+        ),
+    )
+}
+
+/// Same data as `list_rental_requests`.
+pub fn requests_page(requests: &[RentalRequest]) -> String {
+    let rows = requests
+        .iter()
+        .map(|request| {
+            vec![
+                request.user.to_string(),
+                format!("{:?}", request.rental_condition_id),
+                request.initial_proposal_id.to_string(),
+                format_time(request.creation_time_nanos),
+                request.initial_cost_icp.to_string(),
+                request.locked_amount_icp.to_string(),
+                request.locked_amount_cycles.to_string(),
+            ]
+        })
+        .collect();
+    page(
+        "Rental requests",
+        &table(
+            &[
+                "User",
+                "Rental condition",
+                "Proposal",
+                "Created",
+                "Initial cost",
+                "Locked ICP",
+                "Locked cycles",
+            ],
+            rows,
+        ),
+    )
+}
+
+/// Same data as `list_rental_conditions`.
+pub fn conditions_page(conditions: &[(RentalConditionId, RentalConditions)]) -> String {
+    let rows = conditions
+        .iter()
+        .map(|(id, conditions)| {
+            vec![
+                format!("{id:?}"),
+                conditions.description.clone(),
+                conditions
+                    .subnet_id
+                    .map_or_else(String::new, |subnet_id| subnet_id.to_string()),
+                conditions.daily_cost_cycles.to_string(),
+                conditions.initial_rental_period_days.to_string(),
+            ]
+        })
+        .collect();
+    page(
+        "Rental conditions",
+        &table(
+            &[
+                "Id",
+                "Description",
+                "Subnet",
+                "Daily cost (cycles)",
+                "Initial rental period (days)",
+            ],
+            rows,
+        ),
+    )
+}
+
+/// Same data as `get_history_page`, with a link to the next page if there are older events.
+pub fn history_page(principal: &Principal, events: &[Event], continuation: u64) -> String {
+    let rows = events
+        .iter()
+        .map(|event| {
+            vec![
+                format_time(event.time_nanos()),
+                format!("{:?}", event.event()),
+            ]
+        })
+        .collect();
+    let mut body = table(&["Time", "Event"], rows);
+    if continuation > 0 {
+        let _ = write!(
+            body,
+            "<p><a href=\"/history/{principal}?older_than={continuation}\">Older events</a></p>"
+        );
+    }
+    page(&format!("History of {principal}"), &body)
+}
+
+/// Describes the coverage like `rental_agreement_status` does.
+fn coverage(paid_until_nanos: u64, now_nanos: u64) -> String {
+    if now_nanos > paid_until_nanos {
+        return "PAST DUE".to_string();
+    }
+    let days_left = (paid_until_nanos - now_nanos) / (SECONDS_PER_DAY * BILLION);
+    if days_left <= 30 {
+        format!("WARNING: {days_left} days left")
+    } else {
+        format!("OK: {days_left} days left")
+    }
+}
+
+fn page(title: &str, body: &str) -> String {
+    let title = escape(title);
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>table{{border-collapse:collapse}}td,th{{border:1px solid #ccc;padding:4px;text-align:left}}</style>\
+         </head><body>\
+         <nav><a href=\"/agreements\">Agreements</a> | <a href=\"/requests\">Requests</a> | \
+         <a href=\"/conditions\">Conditions</a></nav>\
+         <h1>{title}</h1>{body}</body></html>\n"
+    )
+}
+
+fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    if rows.is_empty() {
+        return "<p>None.</p>".to_string();
+    }
+    let mut html = "<table><tr>".to_string();
+    for header in headers {
+        let _ = write!(html, "<th>{}</th>", escape(header));
+    }
+    html.push_str("</tr>");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(&cell));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Formats a time in nanos since epoch as UTC date and time.
+fn format_time(time_nanos: u64) -> String {
+    let secs = time_nanos / BILLION;
+    let (year, month, day) = civil_from_days(secs / SECONDS_PER_DAY);
+    let secs_of_day = secs % SECONDS_PER_DAY;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60
+    )
+}
+
+/// Converts days since epoch to a (year, month, day) date in the proleptic Gregorian calendar.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_formatted_as_utc() {
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        // 2024-02-29 13:45:30 UTC
+        assert_eq!(format_time(1_709_214_330 * BILLION), "2024-02-29 13:45 UTC");
+        // 2000-12-31 23:59:59 UTC
+        assert_eq!(format_time(978_307_199 * BILLION), "2000-12-31 23:59 UTC");
+    }
+
+    #[test]
+    fn state_is_escaped() {
+        let conditions = vec![(
+            RentalConditionId::App13CH,
+            RentalConditions {
+                description: "<script>alert(\"x\")</script>".to_string(),
+                subnet_id: None,
+                daily_cost_cycles: 1,
+                initial_rental_period_days: 2,
+            },
+        )];
+        let html = conditions_page(&conditions);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;"));
+    }
+
+    #[test]
+    fn coverage_matches_agreement_status() {
+        let day_nanos = SECONDS_PER_DAY * BILLION;
+        assert_eq!(coverage(day_nanos, 2 * day_nanos), "PAST DUE");
+        assert_eq!(coverage(31 * day_nanos, day_nanos), "WARNING: 30 days left");
+        assert_eq!(coverage(32 * day_nanos, day_nanos), "OK: 31 days left");
+    }
+}
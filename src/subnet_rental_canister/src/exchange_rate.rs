@@ -0,0 +1,282 @@
+//! Sourcing of ICP/XDR exchange rates.
+//!
+//! A single rate determines the ICP price of a rental period that spans months, so a bad
+//! oracle reading must not be trusted blindly. A rate from the XRC is only accepted if
+//! enough sources contributed to it and if they agree closely enough. Otherwise, the CMC's
+//! ICP/XDR conversion rate serves as a fallback, provided that it was determined close
+//! enough to the requested time.
+//!
+//! Only validated XRC rates are cached in `RATES`. A CMC fallback is used once and not
+//! cached, so that the XRC gets another chance with the next request for the same day.
+
+use crate::{
+    canister_state::{cache_rate, get_cached_rate},
+    external_calls::{get_cmc_icp_xdr_conversion_rate, get_xrc_exchange_rate},
+    external_types::IcpXdrConversionRate,
+    RateProvenance, SECONDS_PER_DAY,
+};
+use ic_cdk::println;
+use ic_xrc_types::{ExchangeRate, ExchangeRateMetadata, GetExchangeRateResult};
+
+/// The minimum number of rates that the XRC must have received for both the base
+/// and the quote asset.
+pub const MIN_RECEIVED_RATES: usize = 3;
+/// The maximum standard deviation of the received rates relative to the XRC rate,
+/// in units of 1/10_000.
+pub const MAX_RELATIVE_STANDARD_DEVIATION_PERMYRIAD: u128 = 500;
+/// The maximum distance between the requested time and the time of the CMC rate.
+pub const MAX_CMC_RATE_DISTANCE_SECONDS: u64 = SECONDS_PER_DAY;
+/// How long cached rates are kept, both relative to now and to the creation of the oldest
+/// open rental request, rental agreement or stored price quote. Their prices and billing
+/// may have been determined by the rates since then, so these are kept for reconciliation.
+pub const RATE_RETENTION_DAYS: u64 = 90;
+/// The CMC rate is given in XDR permyriad per ICP.
+const CMC_RATE_DECIMALS: u32 = 4;
+
+/// The reason why a rate from a single source was not accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectedRate {
+    CallFailed(String),
+    XrcError(String),
+    ZeroRate,
+    TooFewSources {
+        base_asset_num_received_rates: usize,
+        quote_asset_num_received_rates: usize,
+    },
+    DeviationTooHigh {
+        rate: u64,
+        standard_deviation: u64,
+    },
+    TooFarFromRequestedTime {
+        rate_timestamp_seconds: u64,
+    },
+}
+
+/// Neither the XRC nor the CMC provided an acceptable rate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExchangeRateUnavailable {
+    pub xrc: RejectedRate,
+    pub cmc: RejectedRate,
+}
+
+/// Query the XDR/ICP exchange rate at the given time in seconds since epoch.
+/// Returns (rate, decimals), where the rate is scaled by 10^decimals.
+/// This function attempts to read from the global RATES cache and updates it.
+pub async fn get_exchange_rate_icp_per_xdr_at_time(
+    time_secs_since_epoch: u64,
+) -> Result<(u64, u32), ExchangeRateUnavailable> {
+    // The SRC keeps a cache of exchange rates
+    if let Some(tup) = get_cached_rate(time_secs_since_epoch) {
+        return Ok(tup);
+    }
+
+    let xrc = match get_xrc_exchange_rate(time_secs_since_epoch).await {
+        Ok(GetExchangeRateResult::Ok(exchange_rate)) => validate_xrc_rate(&exchange_rate)
+            .map(|(rate, decimals)| (rate, decimals, RateProvenance::Xrc(exchange_rate.metadata))),
+        Ok(GetExchangeRateResult::Err(e)) => Err(RejectedRate::XrcError(format!("{e:?}"))),
+        Err(e) => Err(RejectedRate::CallFailed(e)),
+    };
+    let xrc = match xrc {
+        Ok((rate, decimals, provenance)) => {
+            cache_rate(time_secs_since_epoch, rate, decimals, provenance);
+            return Ok((rate, decimals));
+        }
+        Err(rejected) => rejected,
+    };
+    println!("Rejected XRC rate for {time_secs_since_epoch}: {xrc:?}. Falling back to the CMC.");
+
+    let cmc = match get_cmc_icp_xdr_conversion_rate().await {
+        Ok(rate) => validate_cmc_rate(&rate, time_secs_since_epoch),
+        Err(e) => Err(RejectedRate::CallFailed(e)),
+    };
+    match cmc {
+        Ok(tup) => Ok(tup),
+        Err(cmc) => {
+            println!("Rejected CMC rate for {time_secs_since_epoch}: {cmc:?}");
+            Err(ExchangeRateUnavailable { xrc, cmc })
+        }
+    }
+}
+
+/// Returns the time before which cached rates are no longer needed, given the current time
+/// and the creation times of the open rental requests, rental agreements and stored price
+/// quotes, all in seconds since epoch.
+pub fn rate_retention_cutoff(
+    now_secs: u64,
+    dependency_creation_secs: impl IntoIterator<Item = u64>,
+) -> u64 {
+    let oldest_dependency = dependency_creation_secs
+        .into_iter()
+        .fold(now_secs, u64::min);
+    let midnight = oldest_dependency - oldest_dependency % SECONDS_PER_DAY;
+    midnight.saturating_sub(RATE_RETENTION_DAYS * SECONDS_PER_DAY)
+}
+
+/// Accepts an XRC rate only if enough sources were received and their standard deviation
+/// is small relative to the rate.
+fn validate_xrc_rate(exchange_rate: &ExchangeRate) -> Result<(u64, u32), RejectedRate> {
+    let ExchangeRate {
+        rate,
+        metadata:
+            ExchangeRateMetadata {
+                decimals,
+                base_asset_num_received_rates,
+                quote_asset_num_received_rates,
+                standard_deviation,
+                ..
+            },
+        ..
+    } = *exchange_rate;
+    if rate == 0 {
+        return Err(RejectedRate::ZeroRate);
+    }
+    if base_asset_num_received_rates < MIN_RECEIVED_RATES
+        || quote_asset_num_received_rates < MIN_RECEIVED_RATES
+    {
+        return Err(RejectedRate::TooFewSources {
+            base_asset_num_received_rates,
+            quote_asset_num_received_rates,
+        });
+    }
+    // The standard deviation is scaled by the same factor as the rate.
+    if standard_deviation as u128 * 10_000
+        > rate as u128 * MAX_RELATIVE_STANDARD_DEVIATION_PERMYRIAD
+    {
+        return Err(RejectedRate::DeviationTooHigh {
+            rate,
+            standard_deviation,
+        });
+    }
+    Ok((rate, decimals))
+}
+
+/// Accepts the CMC rate only if it was determined close enough to the requested time.
+fn validate_cmc_rate(
+    rate: &IcpXdrConversionRate,
+    time_secs_since_epoch: u64,
+) -> Result<(u64, u32), RejectedRate> {
+    if rate.xdr_permyriad_per_icp == 0 {
+        return Err(RejectedRate::ZeroRate);
+    }
+    if rate.timestamp_seconds.abs_diff(time_secs_since_epoch) > MAX_CMC_RATE_DISTANCE_SECONDS {
+        return Err(RejectedRate::TooFarFromRequestedTime {
+            rate_timestamp_seconds: rate.timestamp_seconds,
+        });
+    }
+    Ok((rate.xdr_permyriad_per_icp, CMC_RATE_DECIMALS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_xrc_types::{Asset, AssetClass};
+
+    fn xrc_rate(
+        rate: u64,
+        standard_deviation: u64,
+        base_asset_num_received_rates: usize,
+        quote_asset_num_received_rates: usize,
+    ) -> ExchangeRate {
+        ExchangeRate {
+            base_asset: Asset {
+                symbol: "ICP".to_string(),
+                class: AssetClass::Cryptocurrency,
+            },
+            quote_asset: Asset {
+                symbol: "CXDR".to_string(),
+                class: AssetClass::FiatCurrency,
+            },
+            timestamp: 0,
+            rate,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_queried_sources: 7,
+                base_asset_num_received_rates,
+                quote_asset_num_queried_sources: 10,
+                quote_asset_num_received_rates,
+                standard_deviation,
+                forex_timestamp: None,
+            },
+        }
+    }
+
+    #[test]
+    fn accepts_well_sourced_xrc_rate() {
+        let rate = xrc_rate(3_500_000_000, 35_000_000, 5, 4);
+        assert_eq!(validate_xrc_rate(&rate), Ok((3_500_000_000, 9)));
+    }
+
+    #[test]
+    fn rejects_xrc_rate_with_too_few_sources() {
+        for (base, quote) in [(2, 4), (5, 2), (0, 0)] {
+            let rate = xrc_rate(3_500_000_000, 0, base, quote);
+            assert_eq!(
+                validate_xrc_rate(&rate),
+                Err(RejectedRate::TooFewSources {
+                    base_asset_num_received_rates: base,
+                    quote_asset_num_received_rates: quote,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn deviation_bound_is_inclusive() {
+        // 5% of 3.5 XDR is 0.175 XDR.
+        let rate = xrc_rate(3_500_000_000, 175_000_000, 5, 4);
+        assert!(validate_xrc_rate(&rate).is_ok());
+        let rate = xrc_rate(3_500_000_000, 175_000_001, 5, 4);
+        assert_eq!(
+            validate_xrc_rate(&rate),
+            Err(RejectedRate::DeviationTooHigh {
+                rate: 3_500_000_000,
+                standard_deviation: 175_000_001,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_zero_rates() {
+        assert_eq!(
+            validate_xrc_rate(&xrc_rate(0, 0, 5, 4)),
+            Err(RejectedRate::ZeroRate)
+        );
+        let rate = IcpXdrConversionRate {
+            xdr_permyriad_per_icp: 0,
+            timestamp_seconds: 0,
+        };
+        assert_eq!(validate_cmc_rate(&rate, 0), Err(RejectedRate::ZeroRate));
+    }
+
+    #[test]
+    fn retention_keeps_rates_of_open_requests() {
+        let now = 1_000 * SECONDS_PER_DAY + 123;
+        let retention = RATE_RETENTION_DAYS * SECONDS_PER_DAY;
+        assert_eq!(
+            rate_retention_cutoff(now, []),
+            1_000 * SECONDS_PER_DAY - retention
+        );
+        let request = 700 * SECONDS_PER_DAY + 5;
+        assert_eq!(
+            rate_retention_cutoff(now, [now - 1, request]),
+            700 * SECONDS_PER_DAY - retention
+        );
+        assert_eq!(rate_retention_cutoff(5, []), 0);
+    }
+
+    #[test]
+    fn cmc_rate_must_be_close_to_requested_time() {
+        let midnight = 19_000 * SECONDS_PER_DAY;
+        let rate = IcpXdrConversionRate {
+            xdr_permyriad_per_icp: 34_979,
+            timestamp_seconds: midnight + SECONDS_PER_DAY,
+        };
+        assert_eq!(validate_cmc_rate(&rate, midnight), Ok((34_979, 4)));
+        assert_eq!(
+            validate_cmc_rate(&rate, midnight - 1),
+            Err(RejectedRate::TooFarFromRequestedTime {
+                rate_timestamp_seconds: midnight + SECONDS_PER_DAY,
+            })
+        );
+    }
+}
@@ -1,103 +1,395 @@
-use crate::canister_state::{cache_rate, get_cached_rate};
 use crate::external_types::{
-    NotifyError, NotifyTopUpArg, SetAuthorizedSubnetworkListArgs, UpdateSubnetAdminsPayload,
+    IcpXdrConversionRate, IcpXdrConversionRateResponse, ListProposalInfo, ListProposalInfoResponse,
+    NotifyError, NotifyTopUpArg, ProposalId, ProposalInfo, SetAuthorizedSubnetworkListArgs,
+    UpdateSubnetAdminsPayload, PROPOSAL_STATUS_EXECUTED,
 };
-use crate::{ExecuteProposalError, MEMO_TOP_UP_CANISTER};
-use candid::Principal;
-use ic_cdk::{call::Call, println};
-use ic_ledger_types::{
-    transfer, AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Tokens, TransferArgs,
-    TransferError, DEFAULT_FEE, DEFAULT_SUBACCOUNT, MAINNET_CYCLES_MINTING_CANISTER_ID,
-    MAINNET_LEDGER_CANISTER_ID,
+use crate::{
+    canister_state::get_canister_ids, ConversionError, ExternalCallError, RenterNotification,
+    MEMO_TOP_UP_CANISTER,
+};
+use candid::{CandidType, Func, Principal};
+use ic_cdk::{
+    call::{Call, CallErrorExt, CallFailed, Response},
+    println,
 };
-use ic_xrc_types::{
-    Asset, AssetClass, ExchangeRate, ExchangeRateError, ExchangeRateMetadata,
-    GetExchangeRateRequest, GetExchangeRateResult,
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, Block, GetBlocksArgs, GetBlocksResult, Memo, Operation,
+    QueryBlocksResponse, Subaccount, Timestamp, Tokens, TransferArgs, TransferError,
+    TransferResult, DEFAULT_FEE, DEFAULT_SUBACCOUNT,
 };
-use std::cell::RefCell;
+use ic_xrc_types::{Asset, AssetClass, GetExchangeRateRequest, GetExchangeRateResult};
+use std::fmt::Display;
+
+/// The cycles that the SRC pays per call to the XRC, since it is not "privileged" on the XRC.
+/// See https://github.com/dfinity/exchange-rate-canister/blob/2f2a08f36fa6d043da9751d61d77952b36a59006/src/xrc/src/lib.rs#L56
+/// for the constant.
+pub const XRC_CALL_CYCLES: u128 = 1_000_000_000;
+/// The timeout of bounded-wait calls. A call that times out has an unknown outcome.
+const CALL_TIMEOUT_SECONDS: u32 = 60;
+/// How often an idempotent call is attempted while its failures might be fixed by retrying.
+const MAX_CALL_ATTEMPTS: usize = 3;
+/// How long the ledger deduplicates transfers after their `created_at_time`.
+const LEDGER_TRANSACTION_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// How far `created_at_time` may deviate from the ledger's time. This is an upper bound of the
+/// ledger's actual drift, so that a search for a transfer covers all blocks it could be in.
+const LEDGER_PERMITTED_DRIFT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+/// The number of blocks requested per call while searching the ledger for a transfer.
+const BLOCKS_PER_SEARCH_CALL: u64 = 1_000;
+/// The number of calls of one search for a transfer. The window of a transfer can hold many
+/// more blocks, so the search stops there and continues on the next attempt, rather than
+/// holding the caller's locks until it is done.
+const MAX_SEARCH_CALLS: u64 = 10;
 
-thread_local! {
-    static EXCHANGE_RATE_CANISTER_ID: RefCell<Principal> =
-        RefCell::new(Principal::from_text("uf6dk-hyaaa-aaaaq-qaaaq-cai").expect("Invalid XRC canister ID"));
-    static REGISTRY_CANISTER_ID: RefCell<Principal> =
-        RefCell::new(Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").expect("Invalid Registry canister ID"));
+/// A bounded-wait call, so that an unresponsive canister cannot hold the SRC's locks forever.
+fn bounded_wait<'m>(canister_id: Principal, method: &'m str) -> Call<'m, 'static> {
+    Call::bounded_wait(canister_id, method).change_timeout(CALL_TIMEOUT_SECONDS)
 }
 
-pub fn get_exchange_rate_canister_id() -> Principal {
-    EXCHANGE_RATE_CANISTER_ID.with_borrow(|p| *p)
+/// Makes a bounded-wait call to a method that has the same effect when it is repeated.
+/// The call is retried while an immediate retry might succeed, in particular after a timeout,
+/// as repeating the call is how the SRC learns the outcome of the previous attempt.
+async fn call_idempotent<A: CandidType>(
+    canister_id: Principal,
+    method: &str,
+    arg: &A,
+) -> Result<Response, CallFailed> {
+    let mut attempt = 1;
+    loop {
+        match bounded_wait(canister_id, method).with_arg(arg).await {
+            Err(err) if err.is_immediately_retryable() && attempt < MAX_CALL_ATTEMPTS => {
+                println!("Call to {canister_id}.{method} failed, retrying: {err}");
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
-/// Override/set the authorized subnetwork list of the CMC of a user to one specific subnet.
-pub async fn set_authorized_subnetwork_list(user: &Principal, subnet_id: &Principal) {
-    Call::unbounded_wait(
-        MAINNET_CYCLES_MINTING_CANISTER_ID,
-        "set_authorized_subnetwork_list",
-    )
-    .with_arg(SetAuthorizedSubnetworkListArgs {
+fn call_failed(canister_id: Principal, method: &str, err: impl Display) -> ExternalCallError {
+    ExternalCallError::CallFailed {
+        canister_id,
+        method: method.to_string(),
+        reason: err.to_string(),
+    }
+}
+
+fn outcome_unknown(canister_id: Principal, method: &str, err: impl Display) -> ExternalCallError {
+    ExternalCallError::OutcomeUnknown {
+        canister_id,
+        method: method.to_string(),
+        reason: err.to_string(),
+    }
+}
+
+/// Maps the failure of a call that may have changed the callee's state.
+fn mutating_call_failed(
+    canister_id: Principal,
+    method: &str,
+    err: CallFailed,
+) -> ExternalCallError {
+    if err.is_clean_reject() {
+        call_failed(canister_id, method, err)
+    } else {
+        outcome_unknown(canister_id, method, err)
+    }
+}
+
+/// Override/set the authorized subnetwork list of the CMC of a user to the given subnets.
+/// An empty list removes the user from the CMC's list.
+pub async fn set_authorized_subnetwork_list(
+    user: &Principal,
+    subnets: Vec<Principal>,
+) -> Result<(), ExternalCallError> {
+    let cmc = get_canister_ids().cmc;
+    let method = "set_authorized_subnetwork_list";
+    let arg = SetAuthorizedSubnetworkListArgs {
         who: Some(*user),
-        subnets: vec![*subnet_id], // TODO: Add to the current list, don't overwrite
+        subnets, // TODO: Add to the current list, don't overwrite
+    };
+    call_idempotent(cmc, method, &arg)
+        .await
+        .map(|_| ())
+        .map_err(|err| mutating_call_failed(cmc, method, err))
+}
+
+/// Notify the CMC about a transfer to it, so that it mints cycles for the SRC.
+/// The CMC deduplicates notifications by block index, so this can safely be retried.
+pub async fn notify_top_up(block_index: u64) -> Result<u128, ExternalCallError> {
+    let cmc = get_canister_ids().cmc;
+    let method = "notify_top_up";
+    let arg = NotifyTopUpArg {
+        block_index,
+        canister_id: ic_cdk::api::canister_self(),
+    };
+    call_idempotent(cmc, method, &arg)
+        .await
+        .map_err(|err| mutating_call_failed(cmc, method, err))?
+        .candid::<Result<u128, NotifyError>>()
+        .map_err(|err| outcome_unknown(cmc, method, err))?
+        .map_err(ExternalCallError::Notify)
+}
+
+/// Makes a ledger transfer. Since `created_at_time` is set, the ledger deduplicates identical
+/// transfers: a transfer with an unknown outcome is reconciled by repeating it, which either
+/// executes it or returns the block index of the earlier execution.
+///
+/// Once the ledger's deduplication window has passed, a repeated transfer is rejected as too
+/// old, which does not tell whether the earlier attempt was executed. In that case, the ledger's
+/// blocks are searched for it instead, continuing at `next_search_block_index` if given.
+async fn ledger_transfer(
+    args: &TransferArgs,
+    next_search_block_index: Option<u64>,
+) -> Result<u64, ExternalCallError> {
+    let ledger = get_canister_ids().ledger;
+    let method = "transfer";
+    let response = call_idempotent(ledger, method, args)
+        .await
+        .map_err(|err| mutating_call_failed(ledger, method, err))?;
+    match response.candid::<TransferResult>() {
+        Ok(Ok(block_index))
+        | Ok(Err(TransferError::TxDuplicate {
+            duplicate_of: block_index,
+        })) => Ok(block_index),
+        Ok(Err(e @ TransferError::TxTooOld { .. })) => {
+            match find_transfer(args, next_search_block_index).await? {
+                TransferSearch::Found(block_index) => Ok(block_index),
+                // The earlier attempt was not executed either, and can no longer be.
+                TransferSearch::NotExecuted => Err(ExternalCallError::Transfer(e)),
+                TransferSearch::Incomplete { next_block_index } => {
+                    Err(ExternalCallError::TransferSearchIncomplete { next_block_index })
+                }
+            }
+        }
+        // The ledger's clock is behind the SRC's, so the transfer can be repeated later.
+        Ok(Err(e @ TransferError::TxCreatedInFuture)) => {
+            Err(outcome_unknown(ledger, method, format!("{e:?}")))
+        }
+        Ok(Err(e)) => Err(ExternalCallError::Transfer(e)),
+        // The ledger handled the transfer, but its result is unknown.
+        Err(err) => Err(outcome_unknown(ledger, method, err)),
+    }
+}
+
+enum TransferSearch {
+    Found(u64),
+    NotExecuted,
+    Incomplete { next_block_index: u64 },
+}
+
+/// Searches the ledger's blocks for an executed transfer with the given arguments, which must
+/// be older than the ledger's deduplication window. The search starts at the first block of
+/// the transfer's time window, or at `start_block_index` to continue an incomplete search,
+/// and reads at most `MAX_SEARCH_CALLS` pages of blocks.
+async fn find_transfer(
+    args: &TransferArgs,
+    start_block_index: Option<u64>,
+) -> Result<TransferSearch, ExternalCallError> {
+    let created_at_time_nanos = args
+        .created_at_time
+        .expect("Transfers of the SRC set created_at_time")
+        .timestamp_nanos;
+    let from = AccountIdentifier::new(
+        &ic_cdk::api::canister_self(),
+        &args.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
+    );
+    // The ledger only executes a transfer within this time range after its `created_at_time`.
+    let first_timestamp_nanos = created_at_time_nanos.saturating_sub(LEDGER_PERMITTED_DRIFT_NANOS);
+    let last_timestamp_nanos =
+        created_at_time_nanos + LEDGER_TRANSACTION_WINDOW_NANOS + LEDGER_PERMITTED_DRIFT_NANOS;
+
+    let (chain_length, _) = get_blocks(0, 0).await?;
+    let mut index = match start_block_index {
+        Some(index) => index,
+        None => {
+            // Find the first block of the time range. Block timestamps are non-decreasing.
+            let (mut low, mut high) = (0, chain_length);
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if block_at(mid).await?.timestamp.timestamp_nanos < first_timestamp_nanos {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+            low
+        }
+    };
+
+    for _ in 0..MAX_SEARCH_CALLS {
+        if index >= chain_length {
+            return Ok(TransferSearch::NotExecuted);
+        }
+        let (_, blocks) = get_blocks(index, BLOCKS_PER_SEARCH_CALL).await?;
+        if blocks.is_empty() {
+            return Err(block_not_found(index));
+        }
+        for block in blocks {
+            if block.timestamp.timestamp_nanos > last_timestamp_nanos {
+                return Ok(TransferSearch::NotExecuted);
+            }
+            if is_transfer(&block, args, from) {
+                return Ok(TransferSearch::Found(index));
+            }
+            index += 1;
+        }
+    }
+    Ok(TransferSearch::Incomplete {
+        next_block_index: index,
     })
-    .await
-    .expect("Failed to call CMC");
 }
 
-async fn notify_top_up(block_index: u64) -> Result<u128, NotifyError> {
-    Call::unbounded_wait(MAINNET_CYCLES_MINTING_CANISTER_ID, "notify_top_up")
-        .with_arg(NotifyTopUpArg {
-            block_index,
-            canister_id: ic_cdk::api::canister_self(),
-        })
+/// Whether the block records the given transfer from the given account.
+fn is_transfer(block: &Block, args: &TransferArgs, from: AccountIdentifier) -> bool {
+    let transaction = &block.transaction;
+    transaction.memo == args.memo
+        && Some(transaction.created_at_time) == args.created_at_time
+        && matches!(
+            transaction.operation,
+            Some(Operation::Transfer { from: block_from, to, amount, .. })
+                if block_from == from && to == args.to && amount == args.amount
+        )
+}
+
+async fn block_at(index: u64) -> Result<Block, ExternalCallError> {
+    get_blocks(index, 1)
+        .await?
+        .1
+        .into_iter()
+        .next()
+        .ok_or_else(|| block_not_found(index))
+}
+
+fn block_not_found(index: u64) -> ExternalCallError {
+    call_failed(
+        get_canister_ids().ledger,
+        "query_blocks",
+        format!("Block {index} was not returned"),
+    )
+}
+
+/// Fetches up to `length` blocks starting at `start`, from the ledger or, if the blocks were
+/// archived, from the archive that holds them. Returns the length of the chain and the blocks,
+/// which may be fewer than requested.
+async fn get_blocks(start: u64, length: u64) -> Result<(u64, Vec<Block>), ExternalCallError> {
+    let ledger = get_canister_ids().ledger;
+    let method = "query_blocks";
+    let args = GetBlocksArgs { start, length };
+    let response = call_idempotent(ledger, method, &args)
         .await
-        .expect("Failed to call CMC")
-        .candid()
-        .expect("Failed to decode result")
+        .map_err(|err| call_failed(ledger, method, err))?
+        .candid::<QueryBlocksResponse>()
+        .map_err(|err| call_failed(ledger, method, err))?;
+    let chain_length = response.chain_length;
+
+    if let Some(range) = response
+        .archived_blocks
+        .into_iter()
+        .find(|range| range.start <= start && start < range.start + range.length)
+    {
+        let archive = Func::from(range.callback);
+        let args = GetBlocksArgs {
+            start,
+            length: length.min(range.start + range.length - start),
+        };
+        let blocks = call_idempotent(archive.principal, &archive.method, &args)
+            .await
+            .map_err(|err| call_failed(archive.principal, &archive.method, err))?
+            .candid::<GetBlocksResult>()
+            .map_err(|err| call_failed(archive.principal, &archive.method, err))?
+            .map_err(|err| call_failed(archive.principal, &archive.method, format!("{err:?}")))?
+            .blocks;
+        return Ok((chain_length, blocks));
+    }
+
+    let blocks = match start.checked_sub(response.first_block_index) {
+        Some(offset) => response.blocks.into_iter().skip(offset as usize).collect(),
+        None => vec![],
+    };
+    Ok((chain_length, blocks))
 }
 
-async fn transfer_to_cmc(amount: Tokens, source: Subaccount) -> Result<u64, TransferError> {
-    transfer(
-        MAINNET_LEDGER_CANISTER_ID,
+/// Transfers ICP from a user's SRC subaccount to the CMC. Repeating a transfer with the same
+/// `created_at_time_nanos` within the ledger's deduplication window returns the earlier block.
+/// After the window, the ledger is searched for the earlier block, see `ledger_transfer`.
+pub async fn transfer_to_cmc(
+    amount: Tokens,
+    source: Subaccount,
+    created_at_time_nanos: u64,
+    next_search_block_index: Option<u64>,
+) -> Result<u64, ExternalCallError> {
+    ledger_transfer(
         &TransferArgs {
             to: AccountIdentifier::new(
-                &MAINNET_CYCLES_MINTING_CANISTER_ID,
+                &get_canister_ids().cmc,
                 &Subaccount::from(ic_cdk::api::canister_self()),
             ),
             fee: DEFAULT_FEE,
             from_subaccount: Some(source),
             amount,
             memo: MEMO_TOP_UP_CANISTER,
-            created_at_time: None,
+            created_at_time: Some(Timestamp {
+                timestamp_nanos: created_at_time_nanos,
+            }),
+        },
+        next_search_block_index,
+    )
+    .await
+}
+
+/// Moves ICP from one user's SRC subaccount to another user's SRC subaccount.
+/// Like `transfer_to_cmc`, the transfer is identified by `created_at_time_nanos`.
+pub async fn transfer_between_subaccounts(
+    from: Principal,
+    to: Principal,
+    amount: Tokens,
+    created_at_time_nanos: u64,
+    next_search_block_index: Option<u64>,
+) -> Result<u64, ExternalCallError> {
+    ledger_transfer(
+        &TransferArgs {
+            to: AccountIdentifier::new(&ic_cdk::api::canister_self(), &Subaccount::from(to)),
+            fee: DEFAULT_FEE,
+            from_subaccount: Some(Subaccount::from(from)),
+            amount,
+            memo: Memo(0),
+            created_at_time: Some(Timestamp {
+                timestamp_nanos: created_at_time_nanos,
+            }),
         },
+        next_search_block_index,
     )
     .await
-    .expect("Failed to call ledger canister")
 }
 
-pub async fn refund_user(user_principal: Principal, amount: Tokens) -> Result<u64, TransferError> {
-    transfer(
-        MAINNET_LEDGER_CANISTER_ID,
+/// Refunds ICP from a user's SRC subaccount to the user's default account.
+/// Like `transfer_to_cmc`, the transfer is identified by `created_at_time_nanos`.
+pub async fn refund_user(
+    user_principal: Principal,
+    amount: Tokens,
+    created_at_time_nanos: u64,
+    next_search_block_index: Option<u64>,
+) -> Result<u64, ExternalCallError> {
+    ledger_transfer(
         &TransferArgs {
             to: AccountIdentifier::new(&user_principal, &DEFAULT_SUBACCOUNT),
             fee: DEFAULT_FEE,
             from_subaccount: Some(Subaccount::from(user_principal)),
             amount,
             memo: Memo(0),
-            created_at_time: None,
+            created_at_time: Some(Timestamp {
+                timestamp_nanos: created_at_time_nanos,
+            }),
         },
+        next_search_block_index,
     )
     .await
-    .expect("Failed to call ledger canister")
 }
 
-/// Query the XDR/ICP exchange rate at the given time in seconds since epoch.
-/// Returns (rate, decimals), where the rate is scaled by 10^decimals.
-/// This function attempts to read from the global RATES cache and updates it.
-pub async fn get_exchange_rate_icp_per_xdr_at_time(
+/// Query the XRC for the ICP/XDR exchange rate at the given time in seconds since epoch.
+/// The returned rate is not validated; see the `exchange_rate` module for that.
+pub async fn get_xrc_exchange_rate(
     time_secs_since_epoch: u64,
-) -> Result<(u64, u32), ExchangeRateError> {
-    // The SRC keeps a cache of exchange rates
-    if let Some(tup) = get_cached_rate(time_secs_since_epoch) {
-        return Ok(tup);
-    }
+) -> Result<GetExchangeRateResult, String> {
     let icp_asset = Asset {
         class: AssetClass::Cryptocurrency,
         symbol: String::from("ICP"),
@@ -113,74 +405,162 @@ pub async fn get_exchange_rate_icp_per_xdr_at_time(
         base_asset: icp_asset,
     };
 
-    // Since the SRC is not "privileged" on the XRC, we need to pay 1B cycles to call the XRC.
-    // See https://github.com/dfinity/exchange-rate-canister/blob/2f2a08f36fa6d043da9751d61d77952b36a59006/src/xrc/src/lib.rs#L56
-    // for the constant.
-    let response: GetExchangeRateResult =
-        Call::unbounded_wait(get_exchange_rate_canister_id(), "get_exchange_rate")
-            .with_arg(request)
-            .with_cycles(1_000_000_000)
-            .await
-            .expect("Failed to call ExchangeRateCanister")
-            .candid()
-            .expect("Failed to decode result");
-
-    match response {
-        GetExchangeRateResult::Ok(ExchangeRate {
-            metadata: ExchangeRateMetadata { decimals, .. },
-            rate,
-            ..
-        }) => {
-            cache_rate(time_secs_since_epoch, rate, decimals);
-            Ok((rate, decimals))
-        }
-        GetExchangeRateResult::Err(e) => Err(e),
-    }
+    bounded_wait(get_canister_ids().xrc, "get_exchange_rate")
+        .with_arg(request)
+        .with_cycles(XRC_CALL_CYCLES)
+        .await
+        .map_err(|err| err.to_string())?
+        .candid()
+        .map_err(|err| err.to_string())
+}
+
+/// Query the CMC for its current ICP/XDR conversion rate.
+pub async fn get_cmc_icp_xdr_conversion_rate() -> Result<IcpXdrConversionRate, String> {
+    bounded_wait(get_canister_ids().cmc, "get_icp_xdr_conversion_rate")
+        .await
+        .map_err(|err| err.to_string())?
+        .candid::<IcpXdrConversionRateResponse>()
+        .map(|response| response.data)
+        .map_err(|err| err.to_string())
 }
 
-/// Converts ICP from a user's SRC subaccount to cycles.
+/// Converts ICP from a user's SRC subaccount to cycles. `amount` includes the transfer fee.
+/// `created_at_time_nanos` identifies the transfer to the CMC, see `transfer_to_cmc`.
 /// Returns the block index of the transfer and the actual amount of cycles created.
 pub async fn convert_icp_to_cycles(
     amount: Tokens,
     source: Subaccount,
-) -> Result<(u64, u128), ExecuteProposalError> {
+    created_at_time_nanos: u64,
+) -> Result<(u64, u128), ConversionError> {
+    if amount <= DEFAULT_FEE {
+        return Err(ConversionError::AmountTooSmall { amount });
+    }
     // Transfer the ICP from the SRC to the CMC.
-    let transfer_to_cmc_result = transfer_to_cmc(amount - DEFAULT_FEE, source).await;
-    let Ok(block_index) = transfer_to_cmc_result else {
-        let e = transfer_to_cmc_result.unwrap_err();
-        println!("Transfer from SRC to CMC failed: {:?}", e);
-        return Err(ExecuteProposalError::TransferSrcToCmcError(e.to_string()));
-    };
+    let block_index = transfer_to_cmc(amount - DEFAULT_FEE, source, created_at_time_nanos, None)
+        .await
+        .map_err(|e| {
+            println!("Transfer from SRC to CMC failed: {:?}", e);
+            match e {
+                ExternalCallError::OutcomeUnknown { .. }
+                | ExternalCallError::TransferSearchIncomplete { .. } => {
+                    ConversionError::TransferOutcomeUnknown { error: e }
+                }
+                e => ConversionError::TransferFailed(e),
+            }
+        })?;
 
     // Notify CMC about the top-up. This is what triggers the exchange from ICP to cycles.
-    let notify_top_up_result = notify_top_up(block_index).await;
-    let Ok(actual_cycles) = notify_top_up_result else {
-        let e = notify_top_up_result.unwrap_err();
-        println!("Notify top-up failed: {:?}", e);
-        return Err(ExecuteProposalError::NotifyTopUpError(format!("{:?}", e)));
-    };
+    let actual_cycles = notify_top_up(block_index).await.map_err(|error| {
+        println!("Notify top-up failed: {:?}", error);
+        ConversionError::NotifyFailed { block_index, error }
+    })?;
     Ok((block_index, actual_cycles))
 }
 
 /// Check balance of a user's SRC subaccount.
-pub async fn check_subaccount_balance(subaccount: Subaccount) -> Tokens {
-    Call::unbounded_wait(MAINNET_LEDGER_CANISTER_ID, "account_balance")
+pub async fn check_subaccount_balance(subaccount: Subaccount) -> Result<Tokens, ExternalCallError> {
+    let ledger = get_canister_ids().ledger;
+    let method = "account_balance";
+    bounded_wait(ledger, method)
         .with_arg(AccountBalanceArgs {
             account: AccountIdentifier::new(&ic_cdk::api::canister_self(), &subaccount),
         })
         .await
-        .expect("Failed to call LedgerCanister")
+        .map_err(|err| call_failed(ledger, method, err))?
         .candid()
-        .expect("Failed to decode result")
+        .map_err(|err| call_failed(ledger, method, err))
 }
 
+/// Sets the admins of a subnet in the registry. Setting the same admins again has no effect,
+/// so the call is retried if its outcome is unknown.
 pub async fn update_subnet_admins(payload: UpdateSubnetAdminsPayload) -> Result<(), String> {
-    Call::unbounded_wait(
-        REGISTRY_CANISTER_ID.with_borrow(|p| *p),
-        "update_subnet_admins",
-    )
-    .with_arg(payload)
-    .await
-    .map(|_| ())
-    .map_err(|err| err.to_string())
+    let registry_canister_id = get_canister_ids().registry;
+    let method = "update_subnet_admins";
+    call_idempotent(registry_canister_id, method, &payload)
+        .await
+        .map(|_| ())
+        .map_err(|err| {
+            format!(
+                "{:?}",
+                mutating_call_failed(registry_canister_id, method, err)
+            )
+        })
+}
+
+/// List a page of executed proposals on NNS governance, most recent first.
+/// If `before_proposal` is given, only proposals with a smaller id are returned.
+pub async fn list_executed_proposals(
+    before_proposal: Option<u64>,
+    limit: u32,
+) -> Result<Vec<ProposalInfo>, String> {
+    bounded_wait(get_canister_ids().governance, "list_proposals")
+        .with_arg(ListProposalInfo {
+            limit,
+            before_proposal: before_proposal.map(|id| ProposalId { id }),
+            include_status: vec![PROPOSAL_STATUS_EXECUTED],
+            ..Default::default()
+        })
+        .await
+        .map_err(|err| err.to_string())?
+        .candid::<ListProposalInfoResponse>()
+        .map(|response| response.proposal_info)
+        .map_err(|err| err.to_string())
+}
+
+/// Make a one-way call to a renter's notification target. An error means that the call
+/// could not be enqueued; whether an enqueued call succeeds is never known.
+pub fn notify_renter(
+    canister_id: Principal,
+    method: &str,
+    notification: &RenterNotification,
+) -> Result<(), String> {
+    Call::unbounded_wait(canister_id, method)
+        .with_arg(notification)
+        .oneway()
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::Transaction;
+
+    #[test]
+    fn test_is_transfer_matches_all_arguments() {
+        let from = AccountIdentifier::new(&Principal::from_slice(b"src"), &DEFAULT_SUBACCOUNT);
+        let to = AccountIdentifier::new(&Principal::from_slice(b"user"), &DEFAULT_SUBACCOUNT);
+        let args = TransferArgs {
+            memo: Memo(0),
+            amount: Tokens::from_e8s(1_000),
+            fee: DEFAULT_FEE,
+            from_subaccount: None,
+            to,
+            created_at_time: Some(Timestamp {
+                timestamp_nanos: 42,
+            }),
+        };
+        let block = |amount: u64, created_at_time_nanos: u64| Block {
+            parent_hash: None,
+            transaction: Transaction {
+                memo: Memo(0),
+                operation: Some(Operation::Transfer {
+                    from,
+                    to,
+                    amount: Tokens::from_e8s(amount),
+                    fee: DEFAULT_FEE,
+                }),
+                created_at_time: Timestamp {
+                    timestamp_nanos: created_at_time_nanos,
+                },
+                icrc1_memo: None,
+            },
+            timestamp: Timestamp {
+                timestamp_nanos: 50,
+            },
+        };
+        assert!(is_transfer(&block(1_000, 42), &args, from));
+        assert!(!is_transfer(&block(999, 42), &args, from));
+        assert!(!is_transfer(&block(1_000, 43), &args, from));
+        assert!(!is_transfer(&block(1_000, 42), &args, to));
+    }
 }
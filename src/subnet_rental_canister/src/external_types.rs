@@ -119,3 +119,70 @@ impl From<crate::UpdateSubnetAdminsPayload> for UpdateSubnetAdminsPayload {
         }
     }
 }
+
+// ============================================================================
+// NNS Governance
+//
+// Only the subset of the governance candid interface that the SRC needs to find executed
+// CreateSubnet proposals is modelled here. Candid decoding ignores the record fields that
+// are omitted, and unknown `Action` variants decode to None thanks to the `opt` wrapper.
+
+/// The proposal status of an executed proposal on NNS governance.
+pub const PROPOSAL_STATUS_EXECUTED: i32 = 4;
+
+/// The NNS function id of a CreateSubnet proposal.
+pub const NNS_FUNCTION_CREATE_SUBNET: i32 = 1;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ProposalId {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ListProposalInfo {
+    pub limit: u32,
+    pub before_proposal: Option<ProposalId>,
+    pub exclude_topic: Vec<i32>,
+    pub include_reward_status: Vec<i32>,
+    pub include_status: Vec<i32>,
+    pub include_all_manage_neuron_proposals: Option<bool>,
+    pub omit_large_fields: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ListProposalInfoResponse {
+    pub proposal_info: Vec<ProposalInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProposalInfo {
+    pub id: Option<ProposalId>,
+    pub status: i32,
+    pub executed_timestamp_seconds: u64,
+    pub proposal: Option<Proposal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Proposal {
+    pub action: Option<Action>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Action {
+    ExecuteNnsFunction(ExecuteNnsFunction),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExecuteNnsFunction {
+    pub nns_function: i32,
+    pub payload: serde_bytes::ByteBuf,
+}
+
+/// The fields of a CreateSubnet proposal payload that link it to a rental request.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct CreateSubnetPayload {
+    /// The id of the subnet to be created.
+    pub subnet_id_override: Option<Principal>,
+    /// The id of the SubnetRentalRequest proposal that this subnet fulfills.
+    pub subnet_rental_request_proposal_id: Option<u64>,
+}
@@ -1,5 +1,10 @@
-use crate::{Principal, RentalConditionId, RentalConditions, RentalRequest};
-use candid::{CandidType, Decode, Encode};
+use crate::{
+    versioned::{self, Versioned},
+    AgreementRole, NotificationTarget, PendingAgreementTransfer, PendingConversion,
+    PendingTransfer, PriceQuote, Principal, RentalConditionId, RentalConditions, RentalRequest,
+    RenterNotification,
+};
+use candid::CandidType;
 use ic_ledger_types::Tokens;
 use ic_stable_structures::{storable::Bound, Storable};
 use serde::Deserialize;
@@ -16,16 +21,20 @@ pub struct Event {
 
 impl Storable for Event {
     fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(versioned::encode(self))
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        versioned::decode(&bytes).unwrap()
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
+impl Versioned for Event {
+    const VERSION: u16 = 1;
+}
+
 impl Event {
     pub fn event(&self) -> EventType {
         self.event.clone()
@@ -35,6 +44,11 @@ impl Event {
         self.time_nanos
     }
 
+    /// An event at the given time, for callers that already know the current time.
+    pub fn at_time(time_nanos: u64, event: EventType) -> Self {
+        Self { time_nanos, event }
+    }
+
     #[cfg(test)]
     pub fn _mk_event(time_nanos: u64, event: EventType) -> Self {
         Self { time_nanos, event }
@@ -58,6 +72,10 @@ pub enum EventType {
         rental_condition_id: RentalConditionId,
         rental_conditions: Option<RentalConditions>,
     },
+    /// A SubnetRentalRequest proposal was charged the price locked by the user's price quote.
+    PriceQuoteUsed {
+        price_quote: PriceQuote,
+    },
     /// A successful SubnetRentalRequest proposal execution leads to a RentalRequest
     RentalRequestCreated {
         rental_request: RentalRequest,
@@ -73,6 +91,15 @@ pub enum EventType {
     RentalRequestCancelled {
         rental_request: RentalRequest,
     },
+    /// When the governance canister rejects a pending rental request, e.g., because the
+    /// subnet creation proposal was rejected. The locked cycles are burned and the
+    /// remaining balance on the user subaccount is refunded, if possible.
+    RentalRequestRejected {
+        rental_request: RentalRequest,
+        subnet_creation_proposal_id: u64,
+        refunded_icp: Tokens,
+        refund_block_index: Option<u64>,
+    },
     /// After successfull polling for a CreateSubnet proposal, a RentalAgreement is created
     RentalAgreementCreated {
         user: Principal,
@@ -80,6 +107,13 @@ pub enum EventType {
         subnet_creation_proposal_id: Option<u64>,
         rental_condition_id: RentalConditionId,
     },
+    /// A rental request could not be turned into a rental agreement. The rental request is
+    /// kept, so that creating the rental agreement can be retried.
+    RentalAgreementCreationFailed {
+        user: Principal,
+        subnet_id: Principal,
+        reason: String,
+    },
     /// Not yet emitted. Reserved for future rental agreement termination logic.
     RentalAgreementTerminated {
         user: Principal,
@@ -92,6 +126,41 @@ pub enum EventType {
         amount: Tokens,
         block_index: u64,
     },
+    /// A failed refund from the SRC/user subaccount to the user. The ICP stays on the subaccount.
+    RefundFailed {
+        user: Principal,
+        reason: String,
+    },
+    /// ICP was transferred to the CMC, but the CMC did not mint cycles yet.
+    /// Minting is retried until it succeeds or the CMC reports a permanent error.
+    ConversionPending {
+        pending_conversion: PendingConversion,
+        reason: String,
+    },
+    /// The CMC minted the cycles of a pending conversion.
+    ConversionCompleted {
+        pending_conversion: PendingConversion,
+        cycles: u128,
+    },
+    /// The CMC permanently failed to mint the cycles of a pending conversion, e.g., because it
+    /// refunded the ICP to the user subaccount.
+    ConversionAbandoned {
+        pending_conversion: PendingConversion,
+        reason: String,
+    },
+    /// The outcome of a refund or sweep is unknown. The transfer is reconciled until the ledger
+    /// either executed it or definitely rejected it.
+    TransferPending {
+        pending_transfer: PendingTransfer,
+        reason: String,
+    },
+    /// A pending refund or sweep was reconciled: it was executed in `block_index`, or, if that
+    /// is `None`, rejected for `reason`, in which case the ICP stays on the subaccount.
+    TransferSettled {
+        pending_transfer: PendingTransfer,
+        block_index: Option<u64>,
+        reason: Option<String>,
+    },
     /// A successfull locking of 10% during the wait until subnet creation.
     LockingSuccess {
         user: Principal,
@@ -111,6 +180,49 @@ pub enum EventType {
         days_added: u64,
         new_paid_until_nanos: u64,
     },
+    /// The renter proposed to transfer the rental agreement to a new principal.
+    AgreementTransferProposed {
+        pending_transfer: PendingAgreementTransfer,
+    },
+    /// NNS governance approved the proposed transfer of the rental agreement.
+    AgreementTransferApproved {
+        pending_transfer: PendingAgreementTransfer,
+    },
+    /// The renter or the new principal cancelled the proposed transfer of the rental agreement.
+    AgreementTransferCancelled {
+        pending_transfer: PendingAgreementTransfer,
+    },
+    /// The proposed transfer of the rental agreement expired before the new principal accepted it.
+    AgreementTransferExpired {
+        pending_transfer: PendingAgreementTransfer,
+    },
+    /// The new principal accepted the transfer and now rents the subnet. The balance of the
+    /// old principal's SRC subaccount was moved to the new principal's, unless `sweep_error` is set.
+    RentalAgreementTransferred {
+        old_user: Principal,
+        new_user: Principal,
+        swept_icp: Tokens,
+        sweep_error: Option<String>,
+    },
+    /// The renter delegated roles on the rental agreement to a principal.
+    AgreementRolesGranted {
+        user: Principal,
+        principal: Principal,
+        roles: Vec<AgreementRole>,
+    },
+    /// The renter revoked roles on the rental agreement from a principal.
+    AgreementRolesRevoked {
+        user: Principal,
+        principal: Principal,
+        roles: Vec<AgreementRole>,
+    },
+    /// The renter or a delegate changed the admins of the subnet, or a new renter cleared them
+    /// when taking over the rental agreement. Only the actual changes are recorded.
+    SubnetAdminsUpdated {
+        user: Principal,
+        added: Vec<Principal>,
+        removed: Vec<Principal>,
+    },
     /// A failed top-up attempt for a rented subnet (insufficient funds or ICP-to-cycles conversion error).
     SubnetTopUpFailed {
         user: Principal,
@@ -126,6 +238,31 @@ pub enum EventType {
         old_paid_until_nanos: u64,
         new_paid_until_nanos: u64,
     },
+    /// A state migration was applied during an upgrade, see the `migration` module.
+    MigrationApplied {
+        id: u64,
+        name: String,
+        changes: Vec<String>,
+    },
+    /// The renter registered or removed (None) a notification target.
+    NotificationTargetChanged {
+        user: Principal,
+        target: Option<NotificationTarget>,
+    },
+    /// An attempt to notify the renter. `error` is set if the one-way call could not be made.
+    RenterNotified {
+        notification: RenterNotification,
+        canister_id: Principal,
+        method: String,
+        error: Option<String>,
+    },
+    /// Governance replaced a cached ICP/XDR exchange rate. Rates are (rate, decimals),
+    /// where the rate is scaled by 10^decimals.
+    ExchangeRateOverridden {
+        time_secs: u64,
+        old_rate: Option<(u64, u32)>,
+        new_rate: (u64, u32),
+    },
     /// Not yet emitted. Reserved for future subnet degradation.
     Degraded,
     /// Not yet emitted. Reserved for future subnet degradation recovery.
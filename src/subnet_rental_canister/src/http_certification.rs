@@ -0,0 +1,76 @@
+//! Lets the HTTP gateway serve the responses of `http_request` on the canister's normal domain.
+//!
+//! The pages are read-only views of public state, so instead of certifying every response, the
+//! canister certifies that all of its responses skip certification, as described in the HTTP
+//! gateway protocol. The certified data is the root hash of a tree with the skip expression
+//! for every path, and each response carries the certificate and that tree in its headers.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ic_certification::{labeled, leaf, HashTree};
+use sha2::{Digest, Sha256};
+
+/// The CEL expression that tells the HTTP gateway not to verify a response.
+const SKIP_CERTIFICATION_EXPRESSION: &str =
+    "default_certification(ValidationArgs{no_certification:Empty{}})";
+
+/// The path of the expression in the tree; `<*>` matches every request path.
+const EXPRESSION_PATH: [&str; 2] = ["http_expr", "<*>"];
+
+fn skip_certification_tree() -> HashTree {
+    let expression_hash = Sha256::digest(SKIP_CERTIFICATION_EXPRESSION.as_bytes()).to_vec();
+    labeled(
+        EXPRESSION_PATH[0],
+        labeled(EXPRESSION_PATH[1], labeled(expression_hash, leaf(vec![]))),
+    )
+}
+
+/// Sets the certified data to the root hash of the tree. Must be called in `init` and
+/// `post_upgrade`, since queries cannot change the certified data.
+pub fn certify_skipped_responses() {
+    ic_cdk::api::certified_data_set(skip_certification_tree().digest());
+}
+
+/// The headers that make the HTTP gateway accept a response without verifying it. Empty when
+/// the query is not called by the HTTP gateway, which is the only caller that gets a certificate.
+pub fn skip_certification_headers() -> Vec<(String, String)> {
+    let Some(certificate) = ic_cdk::api::data_certificate() else {
+        return vec![];
+    };
+    let tree = self_describing_cbor(&skip_certification_tree());
+    let expression_path = self_describing_cbor(&EXPRESSION_PATH);
+    vec![
+        (
+            "IC-Certificate".to_string(),
+            format!(
+                "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+                STANDARD.encode(certificate),
+                STANDARD.encode(tree),
+                STANDARD.encode(expression_path),
+            ),
+        ),
+        (
+            "IC-CertificateExpression".to_string(),
+            SKIP_CERTIFICATION_EXPRESSION.to_string(),
+        ),
+    ]
+}
+
+fn self_describing_cbor(value: &impl serde::Serialize) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer.self_describe().unwrap();
+    value.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expression_path_matches_every_path() {
+        assert_eq!(
+            STANDARD.encode(self_describing_cbor(&EXPRESSION_PATH)),
+            "2dn3gmlodHRwX2V4cHJjPCo+"
+        );
+    }
+}
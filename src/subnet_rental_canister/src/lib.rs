@@ -1,18 +1,34 @@
 use candid::{
     types::bounded_vec::{BoundedVec, UNBOUNDED},
-    CandidType, Decode, Deserialize, Encode, Principal,
+    CandidType, Deserialize, Principal,
 };
+use external_types::NotifyError;
 use history::Event;
-use ic_ledger_types::{Memo, Tokens};
+use ic_ledger_types::{
+    Memo, Tokens, TransferError, MAINNET_CYCLES_MINTING_CANISTER_ID,
+    MAINNET_GOVERNANCE_CANISTER_ID, MAINNET_LEDGER_CANISTER_ID,
+};
 use ic_stable_structures::{storable::Bound, Storable};
-use std::borrow::Cow;
+use ic_xrc_types::ExchangeRateMetadata;
+use std::{borrow::Cow, collections::BTreeSet};
+use versioned::Versioned;
 
 mod canister;
 mod canister_state;
+mod condition_switch;
+mod dashboard;
+mod exchange_rate;
 pub mod external_calls;
 pub mod external_types;
 mod history;
+mod http_certification;
+mod metrics;
 mod migration;
+mod notifications;
+mod pricing;
+mod statement;
+mod subnet_admins;
+mod versioned;
 
 pub use migration::TARGET_SUBNET as MIGRATION_TARGET_SUBNET;
 
@@ -22,6 +38,12 @@ pub const TRILLION: u128 = 1_000_000_000_000;
 pub const E8S: u64 = 100_000_000;
 const MAX_ALLOWED_SUBNET_ADMINS: usize = 10;
 const MEMO_TOP_UP_CANISTER: Memo = Memo(0x50555054); // == 'TPUP'
+/// uf6dk-hyaaa-aaaaq-qaaaq-cai
+pub const MAINNET_EXCHANGE_RATE_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 2, 16, 0, 1, 1, 1]);
+/// rwlgt-iiaaa-aaaaa-aaaaa-cai
+pub const MAINNET_REGISTRY_CANISTER_ID: Principal =
+    Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
 
 // ============================================================================
 // Types
@@ -50,14 +72,98 @@ pub struct RentalConditions {
 impl Storable for RentalConditions {
     const BOUND: Bound = Bound::Unbounded;
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(versioned::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        versioned::decode(&bytes).unwrap()
     }
 }
 
+impl Versioned for RentalConditions {
+    const VERSION: u16 = 1;
+}
+
+/// The optional argument of `init` and `post_upgrade`.
+/// Fields that are None leave the persisted configuration unchanged.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct InitArgs {
+    /// Whether the SRC polls NNS governance for executed CreateSubnet proposals
+    /// in order to turn rental requests into rental agreements on its own.
+    pub poll_governance: Option<bool>,
+    /// The canister ids of the SRC's dependencies, see `CanisterIds`.
+    pub ledger_canister_id: Option<Principal>,
+    pub cmc_canister_id: Option<Principal>,
+    pub governance_canister_id: Option<Principal>,
+    pub xrc_canister_id: Option<Principal>,
+    pub registry_canister_id: Option<Principal>,
+    /// Whether transfers of rental agreements to new principals must be approved by
+    /// NNS governance before the new principal can accept them.
+    pub agreement_transfers_require_governance: Option<bool>,
+}
+
+/// The canister configuration, persisted in stable memory across upgrades.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Config {
+    pub poll_governance: bool,
+    /// See `InitArgs`. None, as in configs persisted before this field existed, means false.
+    pub agreement_transfers_require_governance: Option<bool>,
+}
+
+impl Storable for Config {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for Config {
+    const VERSION: u16 = 1;
+}
+
+/// The canister ids of the SRC's dependencies, persisted in stable memory across upgrades.
+/// They default to the mainnet canister ids and can be changed via init and upgrade arguments,
+/// e.g., to run the SRC against stand-ins on a local replica or a testnet.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CanisterIds {
+    pub ledger: Principal,
+    pub cmc: Principal,
+    pub governance: Principal,
+    pub xrc: Principal,
+    pub registry: Principal,
+}
+
+impl Default for CanisterIds {
+    fn default() -> Self {
+        Self {
+            ledger: MAINNET_LEDGER_CANISTER_ID,
+            cmc: MAINNET_CYCLES_MINTING_CANISTER_ID,
+            governance: MAINNET_GOVERNANCE_CANISTER_ID,
+            xrc: MAINNET_EXCHANGE_RATE_CANISTER_ID,
+            registry: MAINNET_REGISTRY_CANISTER_ID,
+        }
+    }
+}
+
+impl Storable for CanisterIds {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for CanisterIds {
+    const VERSION: u16 = 1;
+}
+
 /// The governance canister calls the SRC's proposal execution method
 /// with this argument in case the proposal was valid and adopted.
 #[derive(Clone, CandidType, Deserialize)]
@@ -68,6 +174,8 @@ pub struct SubnetRentalProposalPayload {
     pub rental_condition_id: RentalConditionId,
     pub proposal_id: u64,
     pub proposal_creation_time_seconds: u64,
+    /// An optional binding price quote, see `create_price_quote`.
+    pub price_quote_id: Option<u64>,
 }
 
 /// The governance canister calls the SRC's method to turn the rental request into an agreement.
@@ -81,6 +189,16 @@ pub struct CreateRentalAgreementPayload {
     pub subnet_id: Principal,
 }
 
+/// The governance canister calls the SRC's method to reject a pending rental request,
+/// e.g., because the corresponding subnet creation proposal was rejected.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RejectRentalRequestPayload {
+    /// The user whose rental request is rejected.
+    pub user: Principal,
+    /// The proposal id of the rejected create subnet proposal.
+    pub proposal_id: u64,
+}
+
 /// Successful proposal execution leads to a RentalRequest.
 #[derive(Clone, CandidType, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize)]
 pub struct RentalRequest {
@@ -109,14 +227,18 @@ pub struct RentalRequest {
 impl Storable for RentalRequest {
     const BOUND: Bound = Bound::Unbounded;
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(versioned::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        versioned::decode(&bytes).unwrap()
     }
 }
 
+impl Versioned for RentalRequest {
+    const VERSION: u16 = 1;
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
 pub struct RentalAgreement {
     /// The principal which paid the deposit and will be whitelisted.
@@ -146,15 +268,257 @@ impl Storable for RentalAgreement {
     // should be bounded once we replace string with real type
     const BOUND: Bound = Bound::Unbounded;
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        Cow::Owned(versioned::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        versioned::decode(&bytes).unwrap()
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+impl Versioned for RentalAgreement {
+    const VERSION: u16 = 1;
+}
+
+/// The billing record of a rental agreement for one UTC day. Cycles burns are billed to
+/// the day in which they happen.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct BillingRecord {
+    pub subnet_id: Principal,
+    /// The UTC midnight at which the day starts, in seconds since epoch.
+    pub day_start_secs: u64,
+    /// The rental condition that applied during this day.
+    pub rental_condition_id: RentalConditionId,
+    /// The daily cost of the rental condition at the time of billing.
+    pub daily_cost_cycles: u128,
+    /// The cycles burned during this day, including `cycles_burned_past_due`.
+    pub cycles_burned: u128,
+    /// The cycles burned after the agreement's paid-for time had passed.
+    pub cycles_burned_past_due: u128,
+    /// The paid-for rental time billed during this day, in nanoseconds.
+    /// A fully covered day amounts to 24 hours.
+    pub covered_nanos: u64,
+    /// The time up to which rental time has been billed, in nanoseconds since epoch.
+    pub billed_until_nanos: u64,
+}
+
+impl Storable for BillingRecord {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for BillingRecord {
+    const VERSION: u16 = 1;
+}
+
+/// A payment into a rental agreement, as listed on a `Statement`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct StatementPayment {
+    pub time_nanos: u64,
+    pub icp: Tokens,
+    pub cycles: u128,
+}
+
+/// The account statement of a rental agreement for a range of UTC days, as returned by
+/// `get_statement`. Balances are the prepaid cycles that have not been burned yet, so
+/// `closing_balance_cycles = opening_balance_cycles + total_paid_cycles - cycles_burned`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct Statement {
+    pub subnet_id: Principal,
+    pub user: Principal,
+    /// The start of the first day, in seconds since epoch.
+    pub from_secs: u64,
+    /// The end of the last day (exclusive), in seconds since epoch.
+    pub to_secs: u64,
+    pub opening_balance_cycles: u128,
+    /// The initial payment and the top-ups made during the period.
+    pub payments: Vec<StatementPayment>,
+    pub total_paid_icp: Tokens,
+    pub total_paid_cycles: u128,
+    /// The cycles burned during the period, according to the daily billing records.
+    pub cycles_burned: u128,
+    pub closing_balance_cycles: u128,
+    /// The time until which the agreement was paid for at the end of the period.
+    pub paid_until_nanos: u64,
+}
+
+/// Where `get_statement` continues reading the history of a rental agreement, with the sums
+/// of what it has read so far. Only valid for the subnet and period it was returned for.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct StatementCursor {
+    /// The number of the subnet's next event to read.
+    pub next_seq: u64,
+    /// The day of the next billing record to read, in seconds since epoch.
+    pub next_record_day_secs: u64,
+    /// The rental condition the agreement was created with, once read.
+    pub initial_rental_condition_id: Option<RentalConditionId>,
+    /// The sums of all top-ups read so far.
+    pub top_up_icp: Tokens,
+    pub top_up_cycles: u128,
+    /// The cycles of the top-ups made before the period.
+    pub top_up_cycles_before: u128,
+    /// The top-ups made during the period.
+    pub top_ups: Vec<StatementPayment>,
+    /// The paid-until time set by the last top-up or condition switch before the end of the
+    /// period, if any.
+    pub paid_until_nanos: Option<u64>,
+    /// The cycles burned from the start of the period up to now.
+    pub cycles_burned_since: u128,
+    /// The cycles burned during the period.
+    pub cycles_burned: u128,
+}
+
+/// A page of `get_statement`. The statement is complete once the whole history of the
+/// agreement has been read, which may take several calls.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum StatementPage {
+    /// Call `get_statement` again with this cursor to read on.
+    Incomplete(StatementCursor),
+    Complete(Statement),
+}
+
+/// A page of `get_statement_csv`, see `StatementPage`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum StatementCsvPage {
+    /// Call `get_statement_csv` again with this cursor to read on.
+    Incomplete(StatementCursor),
+    Complete(String),
+}
+
+/// A canister method that receives `RenterNotification`s about a rental agreement
+/// via one-way calls.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct NotificationTarget {
+    pub canister_id: Principal,
+    pub method: String,
+    /// A notification is sent once the coverage drops below each of these numbers of days.
+    /// If empty, the defaults of 30, 7 and 1 days are used.
+    pub coverage_thresholds_days: Vec<u64>,
+}
+
+/// Register a notification target for a rented subnet, or remove it with `None`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct RegisterNotificationTargetPayload {
+    pub subnet_id: Principal,
+    pub target: Option<NotificationTarget>,
+}
+
+/// A registered notification target and the notifications already sent to it.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct NotificationRegistration {
+    pub target: NotificationTarget,
+    /// The lowest coverage threshold that the renter has been notified about since the
+    /// coverage last exceeded all thresholds.
+    pub lowest_notified_threshold_days: Option<u64>,
+    /// Whether the renter has been notified that the agreement is past due.
+    pub past_due_notified: bool,
+}
+
+impl Storable for NotificationRegistration {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for NotificationRegistration {
+    const VERSION: u16 = 1;
+}
+
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum NotificationKind {
+    CoverageBelowThreshold { threshold_days: u64 },
+    TopUpSucceeded { cycles_added: u128, days_added: u64 },
+    TopUpFailed { reason: String },
+    PastDue,
+}
+
+/// The argument of the one-way calls to a renter's notification target.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct RenterNotification {
+    pub subnet_id: Principal,
+    pub kind: NotificationKind,
+    pub paid_until_nanos: u64,
+    /// Full days until `paid_until_nanos`.
+    pub days_left: u64,
+}
+
+/// Where a cached ICP/XDR exchange rate came from.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub enum RateProvenance {
+    /// A validated rate from the XRC, along with the metadata it was returned with.
+    Xrc(ExchangeRateMetadata),
+    /// A rate set by governance to override a bad entry.
+    Governance,
+}
+
+impl Storable for RateProvenance {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for RateProvenance {
+    const VERSION: u16 = 1;
+}
+
+/// An entry of the exchange rate cache, as returned by `list_cached_rates`.
+#[derive(Debug, Clone, PartialEq, CandidType, Deserialize)]
+pub struct CachedRate {
+    /// UTC midnight in seconds since epoch.
+    pub time_secs: u64,
+    /// The ICP/XDR rate, scaled by 10^decimals.
+    pub rate: u64,
+    pub decimals: u32,
+    /// None for rates that were cached before their provenance was recorded.
+    pub provenance: Option<RateProvenance>,
+}
+
+/// The governance canister calls the SRC's method to approve a proposed transfer of a
+/// rental agreement, if such transfers require governance approval.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ApproveAgreementTransferPayload {
+    pub subnet_id: Principal,
+    /// Must match the proposed transfer, so that the approval does not carry over to another one.
+    pub new_user: Principal,
+}
+
+/// The governance canister calls the SRC's method to move a rental agreement to another
+/// rental condition, e.g., after a topology change of the subnet.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SwitchRentalConditionPayload {
+    pub subnet_id: Principal,
+    pub new_condition_id: RentalConditionId,
+}
+
+/// The governance canister calls the SRC's method to replace a bad cached exchange rate.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct OverrideExchangeRatePayload {
+    /// UTC midnight in seconds since epoch.
+    pub time_secs: u64,
+    /// The ICP/XDR rate, scaled by 10^decimals.
+    pub rate: u64,
+    pub decimals: u32,
+}
+
+/// Errors of the methods called by NNS governance. Governance only distinguishes replies from
+/// rejections, so these errors are part of the reject message, as candid values.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
 pub enum ExecuteProposalError {
     CallGovernanceFailed,
     CallXRCFailed(String),
@@ -165,10 +529,247 @@ pub enum ExecuteProposalError {
     SubnetAlreadyRequested,
     UnauthorizedCaller,
     InsufficientFunds { have: Tokens, need: Tokens },
-    TransferSrcToCmcError(String),
-    NotifyTopUpError(String),
+    ConversionFailed(ConversionError),
+    ExternalCallFailed(ExternalCallError),
     SubnetNotRented,
     RentalRequestNotFound,
+    ConcurrentCall,
+    InvalidExchangeRate,
+    PriceQuoteNotFound,
+    PriceQuoteExpired,
+    PriceQuoteMismatch,
+    AgreementTransferNotFound,
+    InvalidRentalConditionSwitch(String),
+}
+
+/// Errors of `get_todays_price`, `get_price_at` and `get_price_range`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum PriceError {
+    /// The rental condition does not exist.
+    NotFound,
+    /// Another call is fetching the exchange rate. Try again.
+    Busy,
+    ExchangeRateUnavailable {
+        reason: String,
+    },
+    CalculationFailed(PriceCalculationData),
+    /// Prices can only be determined for the past.
+    TimeInFuture,
+    /// The range ends before it starts or spans more than `max_days` days.
+    InvalidRange {
+        max_days: u64,
+    },
+    /// Exchange rates of days before today are not cached. Fetching them from the XRC costs
+    /// `XRC_CALL_CYCLES` per day, so the caller must attach `need` cycles.
+    InsufficientCycles {
+        need: u128,
+        attached: u128,
+    },
+}
+
+/// Errors of `create_price_quote`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum CreatePriceQuoteError {
+    AnonymousCaller,
+    /// The caller has `open` unexpired quotes, which is the maximum.
+    TooManyOpenQuotes {
+        open: u64,
+    },
+    /// All callers together have `open` unexpired quotes, which is the maximum. Try again later.
+    TooManyOpenQuotesOverall {
+        open: u64,
+    },
+    Price(PriceError),
+}
+
+/// Errors of `rental_agreement_status`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum RentalAgreementStatusError {
+    NotFound,
+}
+
+/// Errors of `refund`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum RefundError {
+    /// Another refund or request of the caller is in progress. Try again.
+    Busy,
+    InsufficientFunds {
+        have: Tokens,
+        need: Tokens,
+    },
+    ExternalCallFailed(ExternalCallError),
+}
+
+/// Errors of `top_up_subnet`, `top_up_subnet_for_days` and `subnet_top_up_estimate`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum TopUpError {
+    /// There is no rental agreement for the subnet.
+    NotFound,
+    /// Another operation on the rental agreement is in progress. Try again.
+    Busy,
+    /// The number of days must be positive.
+    InvalidDays,
+    InsufficientFunds {
+        have: Tokens,
+        need: Tokens,
+    },
+    ExchangeRateUnavailable {
+        reason: String,
+    },
+    /// The required amount of ICP overflowed.
+    CalculationFailed,
+    ExternalCallFailed(ExternalCallError),
+    /// Converting the ICP to cycles via the CMC failed.
+    ConversionFailed(ConversionError),
+    /// The caller does not hold the `TopUp` role for the subnet.
+    Unauthorized,
+}
+
+/// A failed call to the ledger or the CMC.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum ExternalCallError {
+    /// The call was rejected without any effect, or its reply could not be decoded.
+    CallFailed {
+        canister_id: Principal,
+        method: String,
+        reason: String,
+    },
+    /// The call timed out or failed in a way that leaves open whether it took effect.
+    OutcomeUnknown {
+        canister_id: Principal,
+        method: String,
+        reason: String,
+    },
+    /// The ledger did not execute the transfer.
+    Transfer(TransferError),
+    /// The CMC did not mint cycles for the transferred ICP.
+    Notify(NotifyError),
+    /// The ledger's blocks were searched for a transfer up to `next_block_index` without
+    /// finding it. Its outcome stays unknown until the search continues there.
+    TransferSearchIncomplete { next_block_index: u64 },
+}
+
+/// Errors of converting ICP from a user's SRC subaccount to cycles.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum ConversionError {
+    /// The amount does not cover the fee of the transfer to the CMC.
+    AmountTooSmall { amount: Tokens },
+    /// The ICP was not transferred to the CMC.
+    TransferFailed(ExternalCallError),
+    /// It is unknown whether the ICP was transferred to the CMC.
+    /// The conversion is recorded as pending and reconciled against the ledger later.
+    TransferOutcomeUnknown { error: ExternalCallError },
+    /// The ICP was transferred to the CMC in block `block_index`, but no cycles were minted yet.
+    /// The conversion is recorded as pending and completed later.
+    NotifyFailed {
+        block_index: u64,
+        error: ExternalCallError,
+    },
+}
+
+impl ConversionError {
+    /// Whether the ICP may have left the user's subaccount, in which case the conversion
+    /// is pending.
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self,
+            Self::TransferOutcomeUnknown { .. } | Self::NotifyFailed { .. }
+        )
+    }
+}
+
+/// ICP that was transferred from a user's SRC subaccount to the CMC, but for which the CMC has
+/// not minted cycles yet.
+///
+/// The ICP is accounted for when it leaves the subaccount, the cycles once they are minted:
+/// a conversion for a top-up (`subnet_id` is set) extends the rental agreement upon completion,
+/// any other conversion adds its cycles to the user's rental request or rental agreement.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct PendingConversion {
+    pub user: Principal,
+    pub subnet_id: Option<Principal>,
+    /// The amount that left the user's subaccount, including the transfer fee.
+    pub amount: Tokens,
+    /// The `created_at_time` of the transfer to the CMC, which identifies it on the ledger.
+    pub created_at_time_nanos: u64,
+    /// The block of the transfer to the CMC, or `None` while the outcome of the transfer
+    /// is unknown.
+    pub block_index: Option<u64>,
+    /// Where the search of the ledger's blocks for the transfer continues, once it started.
+    pub next_search_block_index: Option<u64>,
+}
+
+impl Storable for PendingConversion {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for PendingConversion {
+    const VERSION: u16 = 1;
+}
+
+/// A refund or sweep out of a user's SRC subaccount whose outcome is unknown. Like a pending
+/// conversion, it is reconciled by repeating the transfer with the same `created_at_time`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct PendingTransfer {
+    /// The user whose SRC subaccount the ICP is transferred from.
+    pub user: Principal,
+    pub kind: PendingTransferKind,
+    /// The amount to arrive, excluding the transfer fee. A retry transfers exactly this amount.
+    pub amount: Tokens,
+    /// The `created_at_time` of the transfer, which identifies it on the ledger.
+    pub created_at_time_nanos: u64,
+    /// Where the search of the ledger's blocks for the transfer continues, once it started.
+    pub next_search_block_index: Option<u64>,
+}
+
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum PendingTransferKind {
+    /// A refund to the user's default account.
+    Refund,
+    /// A sweep to the SRC subaccount of the new renter of `subnet_id`, after the rental
+    /// agreement was transferred to `new_user`.
+    Sweep {
+        subnet_id: Principal,
+        new_user: Principal,
+    },
+}
+
+impl Storable for PendingTransfer {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for PendingTransfer {
+    const VERSION: u16 = 1;
+}
+
+/// Errors of `get_statement` and `get_statement_csv`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum StatementError {
+    /// There is no rental agreement for the subnet.
+    NotFound,
+    /// The period ends before it starts.
+    InvalidRange,
+}
+
+/// Errors of `register_notification_target`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum RegisterNotificationTargetError {
+    CallerNotRentingSubnet,
+    InvalidTarget { reason: String },
 }
 
 /// The data in this struct was used in a failed attempt to calculate an ICP/XDR
@@ -185,12 +786,84 @@ pub struct PriceCalculationData {
     decimals: u32,
 }
 
+/// The price of the initial rental period of a rental condition at a given UTC midnight,
+/// as returned by `get_price_at` and `get_price_range`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct HistoricalPrice {
+    pub rental_condition_id: RentalConditionId,
+    /// The UTC midnight whose exchange rate was used, in seconds since epoch.
+    pub time_secs: u64,
+    /// The cost of the initial rental period in cycles.
+    pub cycles: u128,
+    /// The cost of the initial rental period in ICP.
+    pub icp: Tokens,
+    /// The exchange rate is a positive integer scaled by 10^decimals.
+    pub scaled_exchange_rate_xdr_per_icp: u64,
+    /// Scale factor for the exchange rate.
+    pub decimals: u32,
+}
+
+/// A binding price for the initial rental period of a rental condition, created via
+/// `create_price_quote`. A SubnetRentalRequest proposal that references the quote is
+/// charged the quoted amount, regardless of exchange rate movements since.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct PriceQuote {
+    pub id: u64,
+    /// The principal that requested the quote. Only rental requests for this user can use it.
+    pub user: Principal,
+    pub rental_condition_id: RentalConditionId,
+    /// The cost of the initial rental period in cycles.
+    pub cycles: u128,
+    /// The cost of the initial rental period in ICP.
+    pub icp: Tokens,
+    /// The locked exchange rate, a positive integer scaled by 10^decimals.
+    pub scaled_exchange_rate_xdr_per_icp: u64,
+    /// Scale factor for the exchange rate.
+    pub decimals: u32,
+    /// Quote creation time in nanoseconds since epoch.
+    pub creation_time_nanos: u64,
+    /// Only proposals created before this time in nanoseconds since epoch can use the quote.
+    pub expiry_time_nanos: u64,
+}
+
+impl Storable for PriceQuote {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for PriceQuote {
+    const VERSION: u16 = 1;
+}
+
 #[derive(CandidType, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize)]
 pub struct TopUpSummary {
     /// A human-readable description of the topup
     pub description: String,
     pub cycles_added: u128,
+    /// Full days added, rounded down. The exact extension is given by `paid_until_nanos`.
     pub days_added: u64,
+    /// The ICP that is converted to cycles.
+    pub icp_converted: Tokens,
+    /// The ledger fees deducted before conversion.
+    pub fees: Tokens,
+    /// The XDR/ICP exchange rate, scaled by 10^decimals. For an estimate, this is the rate of
+    /// the previous UTC midnight. For an actual top-up, it is the rate at which the CMC converted.
+    pub scaled_exchange_rate_xdr_per_icp: u64,
+    pub decimals: u32,
+    /// The time until which the rental agreement is paid after the top-up.
+    pub paid_until_nanos: u64,
+    /// Bounds on the outcome of an estimate, allowing for the CMC's rate to deviate from the
+    /// rate used by up to 5%. For an actual top-up, the bounds equal the outcome.
+    pub min_cycles_added: u128,
+    pub max_cycles_added: u128,
+    pub min_paid_until_nanos: u64,
+    pub max_paid_until_nanos: u64,
 }
 
 #[derive(CandidType, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize)]
@@ -217,6 +890,217 @@ pub struct EventPage {
 #[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
 pub struct EmptyRecord {}
 
+/// A transfer of a rental agreement that the renter proposed and the new user has not
+/// accepted yet, see `propose_agreement_transfer`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct PendingAgreementTransfer {
+    pub subnet_id: Principal,
+    pub user: Principal,
+    pub new_user: Principal,
+    pub proposal_time_nanos: u64,
+    /// Only relevant if agreement transfers require governance approval.
+    pub approved_by_governance: bool,
+}
+
+impl Storable for PendingAgreementTransfer {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for PendingAgreementTransfer {
+    const VERSION: u16 = 1;
+}
+
+/// Payload of `propose_agreement_transfer`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct ProposeAgreementTransferPayload {
+    pub subnet_id: Principal,
+    pub new_user: Principal,
+}
+
+/// Errors of `propose_agreement_transfer`, `accept_agreement_transfer` and
+/// `cancel_agreement_transfer`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum AgreementTransferError {
+    CallerNotRentingSubnet,
+    /// Agreements cannot be transferred to the anonymous principal or to the renter.
+    InvalidPrincipal,
+    NewUserAlreadyHasAgreement,
+    NewUserAlreadyRequestingSubnetRental,
+    /// There is no proposed transfer of the subnet's rental agreement.
+    NotFound,
+    /// The caller is neither the renter nor the new user of the proposed transfer.
+    UnauthorizedCaller,
+    /// The transfer requires approval by NNS governance, which has not been given yet.
+    NotApprovedByGovernance,
+    /// The proposed transfer expired and was removed. The renter can propose it again.
+    Expired,
+    /// Another operation on the rental agreement or the new user is in progress. Try again.
+    Busy,
+    /// Clearing the subnet admins in the registry failed. The agreement was not transferred.
+    RegistryError(String),
+    /// Updating the principals authorized on the CMC failed. The agreement was not transferred.
+    ExternalCallFailed(ExternalCallError),
+}
+
+/// The result of decoding every entry in stable memory after the last upgrade,
+/// as reported by `get_storage_check` and `get_canister_status`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct StorageCheckReport {
+    /// When the check started.
+    pub time_nanos: u64,
+    /// When the check finished, or None while it is still running.
+    pub finished_time_nanos: Option<u64>,
+    pub entries_checked: u64,
+    pub failures: Vec<StorageCheckFailure>,
+}
+
+/// An entry in stable memory that does not decode.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct StorageCheckFailure {
+    pub memory_id: u8,
+    /// The key of the entry, or empty for a cell.
+    pub key: String,
+    pub error: String,
+}
+
+/// The health of the canister, as reported by `get_canister_status`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct CanisterStatus {
+    pub timers: Vec<TimerStatus>,
+    pub cycle_balance: u128,
+    /// The size of the canister's stable memory, including the memory manager's own pages.
+    pub stable_memory_pages: u64,
+    pub memory_regions: Vec<MemoryRegionUsage>,
+    /// The storage check since the last upgrade, or None if the canister was not upgraded.
+    pub storage_check: Option<StorageCheckReport>,
+}
+
+/// The last iterations of a timer since the canister was installed or upgraded.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct TimerStatus {
+    pub name: String,
+    pub last_start_time_nanos: u64,
+    /// None while the first iteration is still running, or if it trapped.
+    pub last_end_time_nanos: Option<u64>,
+    /// Entries the last iteration skipped because their `CallerGuard` lock was held.
+    pub skipped_entries_last_run: u64,
+    /// Entries skipped because their `CallerGuard` lock was held, over all iterations.
+    pub skipped_entries_total: u64,
+    pub last_error: Option<TimerError>,
+}
+
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct TimerError {
+    pub time_nanos: u64,
+    pub message: String,
+}
+
+/// The stable memory used by the data stored under a `MemoryId`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct MemoryRegionUsage {
+    pub memory_id: u8,
+    pub size_pages: u64,
+}
+
+/// A registered state migration, as reported by `list_migrations`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct MigrationReport {
+    pub id: u64,
+    pub name: String,
+    /// When the migration was applied, or None if it is pending.
+    pub applied_time_nanos: Option<u64>,
+    /// For a pending migration, what it would do if it ran now, or why it would fail.
+    pub dry_run: Option<Result<MigrationOutcome, String>>,
+}
+
+/// What a migration does when it runs.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum MigrationOutcome {
+    /// The migration applies, making these changes.
+    Changes(Vec<String>),
+    /// The migration does not apply to the current state yet, for the given reason. It stays
+    /// pending without holding back later migrations.
+    NotApplicableYet(String),
+}
+
+/// A right on a rental agreement that the renter can delegate to other principals.
+/// The renter implicitly holds all roles.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+pub enum AgreementRole {
+    /// Top up the rental agreement from the delegate's own SRC subaccount via
+    /// `top_up_subnet_as_delegate`.
+    TopUp,
+    /// Update the subnet admins via `update_subnet_admins`.
+    ManageSubnetAdmins,
+}
+
+/// The roles that the renter of a subnet granted to a principal.
+#[derive(Clone, Eq, PartialEq, Debug, Default, CandidType, Deserialize)]
+pub struct DelegatedRoles {
+    pub roles: BTreeSet<AgreementRole>,
+}
+
+impl Storable for DelegatedRoles {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for DelegatedRoles {
+    const VERSION: u16 = 1;
+}
+
+/// Payload of `grant_agreement_roles` and `revoke_agreement_roles`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub struct AgreementRolesPayload {
+    pub subnet_id: Principal,
+    /// The delegate whose roles change.
+    pub principal: Principal,
+    pub roles: Vec<AgreementRole>,
+}
+
+/// Errors of `grant_agreement_roles` and `revoke_agreement_roles`.
+#[derive(CandidType, Debug, PartialEq, Eq, Clone, Deserialize)]
+pub enum AgreementRolesError {
+    CallerNotRentingSubnet,
+    /// Roles cannot be delegated to the anonymous principal or to the renter.
+    InvalidPrincipal,
+    RolesEmpty,
+}
+
+/// The admins of a rented subnet, as last set via `update_subnet_admins`.
+#[derive(Clone, Eq, PartialEq, Debug, Default, CandidType, Deserialize)]
+pub struct SubnetAdmins {
+    pub admins: BTreeSet<Principal>,
+}
+
+impl Storable for SubnetAdmins {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(versioned::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        versioned::decode(&bytes).unwrap()
+    }
+}
+
+impl Versioned for SubnetAdmins {
+    const VERSION: u16 = 1;
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
 pub enum OperationType {
     Add(BoundedVec<MAX_ALLOWED_SUBNET_ADMINS, UNBOUNDED, UNBOUNDED, Principal>),
@@ -237,6 +1121,13 @@ pub enum UpdateSubnetAdminsError {
     ConcurrentChange(candid::Reserved),
     UnknownOperationType(candid::Reserved),
     RegistryError(String),
+    /// The principal list contains this principal more than once, or it is the anonymous principal.
+    InvalidPrincipal(Principal),
+    /// The operation would leave the subnet with more than `max` admins.
+    TooManyAdmins {
+        max: u64,
+        resulting: u64,
+    },
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, CandidType, Deserialize)]
@@ -244,3 +1135,19 @@ pub enum UpdateSubnetAdminsResult {
     Ok(candid::Reserved),
     Err(Option<UpdateSubnetAdminsError>),
 }
+
+/// A request to the canister's HTTP interface, as passed on by the HTTP gateway.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: serde_bytes::ByteBuf,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: serde_bytes::ByteBuf,
+}
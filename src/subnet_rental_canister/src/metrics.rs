@@ -0,0 +1,291 @@
+//! Metrics of the canister in the Prometheus text exposition format, served via `http_request`.
+//!
+//! All metrics are gauges computed from the current state on every scrape.
+
+use crate::{
+    RentalAgreement, RentalConditionId, RentalRequest, TimerStatus, BILLION, SECONDS_PER_DAY,
+};
+use std::{collections::BTreeMap, fmt::Display, fmt::Write};
+
+/// The state the metrics are computed from.
+pub struct Snapshot {
+    pub now_nanos: u64,
+    pub cycle_balance: u128,
+    pub rental_condition_ids: Vec<RentalConditionId>,
+    pub rental_requests: Vec<RentalRequest>,
+    pub rental_agreements: Vec<RentalAgreement>,
+    pub cached_rates: u64,
+    pub timers: Vec<TimerStatus>,
+    /// `CallerGuard` tags mapped to the number of locks currently held with them.
+    pub held_locks: BTreeMap<&'static str, u64>,
+}
+
+pub fn encode(snapshot: &Snapshot) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "subnet_rental_requests",
+        "Number of open rental requests per rental condition.",
+    );
+    for condition_id in &snapshot.rental_condition_ids {
+        let count = snapshot
+            .rental_requests
+            .iter()
+            .filter(|request| request.rental_condition_id == *condition_id)
+            .count();
+        let condition = format!("{condition_id:?}");
+        sample(
+            &mut out,
+            "subnet_rental_requests",
+            &[("condition", &condition)],
+            count,
+        );
+    }
+
+    header(
+        &mut out,
+        "subnet_rental_agreements",
+        "Number of rental agreements per rental condition.",
+    );
+    for condition_id in &snapshot.rental_condition_ids {
+        let count = snapshot
+            .rental_agreements
+            .iter()
+            .filter(|agreement| agreement.rental_condition_id == *condition_id)
+            .count();
+        let condition = format!("{condition_id:?}");
+        sample(
+            &mut out,
+            "subnet_rental_agreements",
+            &[("condition", &condition)],
+            count,
+        );
+    }
+
+    header(
+        &mut out,
+        "subnet_rental_icp_received_e8s",
+        "ICP received for open rental requests and existing rental agreements, in e8s.",
+    );
+    let requests_icp: u64 = snapshot
+        .rental_requests
+        .iter()
+        .map(|request| request.initial_cost_icp.e8s())
+        .sum();
+    let agreements_icp: u64 = snapshot
+        .rental_agreements
+        .iter()
+        .map(|agreement| agreement.total_icp_paid.e8s())
+        .sum();
+    sample(
+        &mut out,
+        "subnet_rental_icp_received_e8s",
+        &[("source", "requests")],
+        requests_icp,
+    );
+    sample(
+        &mut out,
+        "subnet_rental_icp_received_e8s",
+        &[("source", "agreements")],
+        agreements_icp,
+    );
+
+    header(
+        &mut out,
+        "subnet_rental_cycles_created",
+        "Cycles created for all rental agreements.",
+    );
+    let cycles_created: u128 = snapshot
+        .rental_agreements
+        .iter()
+        .map(|agreement| agreement.total_cycles_created)
+        .sum();
+    sample(
+        &mut out,
+        "subnet_rental_cycles_created",
+        &[],
+        cycles_created,
+    );
+
+    header(
+        &mut out,
+        "subnet_rental_cycles_burned",
+        "Cycles burned for all rental agreements.",
+    );
+    let cycles_burned: u128 = snapshot
+        .rental_agreements
+        .iter()
+        .map(|agreement| agreement.total_cycles_burned)
+        .sum();
+    sample(&mut out, "subnet_rental_cycles_burned", &[], cycles_burned);
+
+    header(
+        &mut out,
+        "subnet_rental_canister_cycle_balance",
+        "Cycle balance of the canister.",
+    );
+    sample(
+        &mut out,
+        "subnet_rental_canister_cycle_balance",
+        &[],
+        snapshot.cycle_balance,
+    );
+
+    // Without agreements, there is nothing that can lapse.
+    if let Some(paid_until_nanos) = snapshot
+        .rental_agreements
+        .iter()
+        .map(|agreement| agreement.paid_until_nanos)
+        .min()
+    {
+        header(
+            &mut out,
+            "subnet_rental_earliest_lapse_days",
+            "Days until the earliest rental agreement lapses, negative if it is past due.",
+        );
+        let days = (paid_until_nanos as f64 - snapshot.now_nanos as f64)
+            / (SECONDS_PER_DAY * BILLION) as f64;
+        sample(&mut out, "subnet_rental_earliest_lapse_days", &[], days);
+    }
+
+    header(
+        &mut out,
+        "subnet_rental_xrc_cached_rates",
+        "Number of cached ICP/XDR exchange rates.",
+    );
+    sample(
+        &mut out,
+        "subnet_rental_xrc_cached_rates",
+        &[],
+        snapshot.cached_rates,
+    );
+
+    header(
+        &mut out,
+        "subnet_rental_timer_last_run_seconds",
+        "Time in seconds since epoch at which each timer last ran.",
+    );
+    for timer in &snapshot.timers {
+        sample(
+            &mut out,
+            "subnet_rental_timer_last_run_seconds",
+            &[("timer", &timer.name)],
+            timer.last_start_time_nanos / BILLION,
+        );
+    }
+
+    header(
+        &mut out,
+        "subnet_rental_held_locks",
+        "Number of currently held caller locks per tag.",
+    );
+    for (tag, count) in &snapshot.held_locks {
+        sample(&mut out, "subnet_rental_held_locks", &[("tag", tag)], count);
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} gauge").unwrap();
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape_label_value(value)))
+            .collect();
+        write!(out, "{{{}}}", labels.join(",")).unwrap();
+    }
+    writeln!(out, " {value}").unwrap();
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::Tokens;
+
+    fn agreement(paid_until_nanos: u64) -> RentalAgreement {
+        RentalAgreement {
+            user: Principal::from_slice(b"user"),
+            rental_request_proposal_id: 1,
+            subnet_creation_proposal_id: None,
+            subnet_id: Principal::from_slice(b"subnet"),
+            rental_condition_id: RentalConditionId::App13CH,
+            creation_time_nanos: 0,
+            paid_until_nanos,
+            total_icp_paid: Tokens::from_e8s(500),
+            total_cycles_created: 1_000,
+            total_cycles_burned: 400,
+        }
+    }
+
+    #[test]
+    fn test_encode_metrics() {
+        let day_nanos = SECONDS_PER_DAY * BILLION;
+        let snapshot = Snapshot {
+            now_nanos: 10 * day_nanos,
+            cycle_balance: 42,
+            rental_condition_ids: vec![RentalConditionId::App13CH, RentalConditionId::App7CH],
+            rental_requests: vec![],
+            rental_agreements: vec![agreement(40 * day_nanos), agreement(12 * day_nanos)],
+            cached_rates: 3,
+            timers: vec![TimerStatus {
+                name: "locking".to_string(),
+                last_start_time_nanos: 5 * BILLION,
+                last_end_time_nanos: None,
+                skipped_entries_last_run: 0,
+                skipped_entries_total: 0,
+                last_error: None,
+            }],
+            held_locks: BTreeMap::from([("agreement", 2)]),
+        };
+        let text = encode(&snapshot);
+        for line in [
+            "# TYPE subnet_rental_agreements gauge",
+            "subnet_rental_agreements{condition=\"App13CH\"} 2",
+            "subnet_rental_agreements{condition=\"App7CH\"} 0",
+            "subnet_rental_requests{condition=\"App13CH\"} 0",
+            "subnet_rental_icp_received_e8s{source=\"agreements\"} 1000",
+            "subnet_rental_cycles_created 2000",
+            "subnet_rental_cycles_burned 800",
+            "subnet_rental_canister_cycle_balance 42",
+            "subnet_rental_earliest_lapse_days 2",
+            "subnet_rental_xrc_cached_rates 3",
+            "subnet_rental_timer_last_run_seconds{timer=\"locking\"} 5",
+            "subnet_rental_held_locks{tag=\"agreement\"} 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_lapse_without_agreements() {
+        let snapshot = Snapshot {
+            now_nanos: 0,
+            cycle_balance: 0,
+            rental_condition_ids: vec![],
+            rental_requests: vec![],
+            rental_agreements: vec![],
+            cached_rates: 0,
+            timers: vec![],
+            held_locks: BTreeMap::new(),
+        };
+        assert!(!encode(&snapshot).contains("earliest_lapse"));
+    }
+}
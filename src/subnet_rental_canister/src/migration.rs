@@ -1,32 +1,39 @@
-//! One-shot state migrations that run in `post_upgrade`.
+//! State migrations that run in `post_upgrade`.
 //!
 //! # Why a migration and not an endpoint
 //!
-//! Moving a rental agreement to a different rental condition is not something the renter
-//! decides unilaterally: the SRC does not enforce subnet topology, so the billing change
-//! only makes sense alongside a registry change that shrinks the subnet. Exposing it as a
-//! public endpoint would let a renter reprice their agreement without the corresponding
-//! topology change, so instead the switch is performed once, as part of the upgrade that
-//! accompanies an NNS proposal.
+//! Some state changes are not something a renter decides unilaterally and have no governance
+//! endpoint either. Such changes ship as a migration in the upgrade that accompanies the NNS
+//! proposal. Rental condition switches used to be such a change, but are now executed by
+//! governance via `execute_switch_rental_condition`.
 //!
-//! # Idempotency
+//! # Registry
 //!
-//! Each migration keys off state that the migration itself changes, so re-running it is a
-//! no-op. No migration-version counter is stored: a second upgrade simply finds nothing
-//! left to do. This matters because `post_upgrade` runs on *every* upgrade, not just the
-//! one that ships the migration.
+//! Every migration is registered in `MIGRATIONS` under a unique id, and migrations run in the
+//! order of their ids. The ids of applied migrations are kept in stable memory, so that each
+//! migration runs once even though `post_upgrade` runs on *every* upgrade. If a migration
+//! fails, it and all later migrations stay pending and are retried on the next upgrade.
+//! A migration must therefore check everything that can fail before it changes any state.
+//!
+//! A migration that waits for some state to exist, such as a rental agreement, reports
+//! `MigrationOutcome::NotApplicableYet` instead of failing. It stays pending and is retried on
+//! the next upgrade, but later migrations still run. Such a migration may thus end up running
+//! after migrations with higher ids, and must not depend on their order.
+//!
+//! `list_migrations` reports what pending migrations would change if they ran now.
 //!
 //! # Lifetime
 //!
-//! These functions are expected to be deleted once the migration has been applied on
-//! mainnet and the result has been verified.
+//! A migration can be deleted once it has been applied on mainnet and the result has been
+//! verified. Its id must not be reused.
 
 use crate::{
     canister_state::{
-        get_rental_agreement, get_rental_conditions, persist_event, update_rental_agreement,
+        get_migration_applied_time, get_rental_agreement, persist_event, set_migration_applied,
     },
-    history::EventType,
-    RentalConditionId, BILLION, SECONDS_PER_DAY,
+    condition_switch,
+    history::{Event, EventType},
+    MigrationOutcome, MigrationReport, RentalConditionId,
 };
 use candid::Principal;
 use ic_cdk::println;
@@ -34,145 +41,229 @@ use ic_cdk::println;
 /// The Swiss Subnet, whose rental agreement moves from App13CH to App7CH.
 pub const TARGET_SUBNET: &str = "3zsyy-cnoqf-tvlun-ymf55-tkpca-ox7uw-kfxoh-7khwq-2gz43-wafem-lqe";
 
+pub struct Migration {
+    /// Unique; migrations run in ascending order of their ids.
+    pub id: u64,
+    pub name: &'static str,
+    /// Returns a description of each change. Only changes state if `apply` is set.
+    pub run: fn(apply: bool, now_nanos: u64) -> Result<MigrationOutcome, String>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    id: 1,
+    name: "app13ch_to_app7ch",
+    run: app13ch_to_app7ch,
+}];
+
+/// Runs the pending migrations in order, stopping at the first failure. Migrations that do not
+/// apply yet are skipped.
+pub fn run_pending_migrations(now_nanos: u64) {
+    run_migrations(MIGRATIONS, now_nanos);
+}
+
+fn run_migrations(migrations: &[Migration], now_nanos: u64) {
+    let mut migrations: Vec<&Migration> = migrations.iter().collect();
+    migrations.sort_by_key(|migration| migration.id);
+    for migration in migrations {
+        if get_migration_applied_time(migration.id).is_some() {
+            continue;
+        }
+        match (migration.run)(true, now_nanos) {
+            Ok(MigrationOutcome::Changes(changes)) => {
+                set_migration_applied(migration.id, now_nanos);
+                println!(
+                    "Applied migration {} ({}): {:?}",
+                    migration.id, migration.name, changes
+                );
+                persist_event(
+                    Event::at_time(
+                        now_nanos,
+                        EventType::MigrationApplied {
+                            id: migration.id,
+                            name: migration.name.to_string(),
+                            changes,
+                        },
+                    ),
+                    None,
+                );
+            }
+            Ok(MigrationOutcome::NotApplicableYet(reason)) => {
+                println!(
+                    "Migration {} ({}) does not apply yet and stays pending: {reason}",
+                    migration.id, migration.name
+                );
+            }
+            Err(e) => {
+                println!(
+                    "Migration {} ({}) failed, later migrations stay pending: {e}",
+                    migration.id, migration.name
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Reports all registered migrations. Pending ones are dry-run, without changing any state.
+pub fn list_migrations(now_nanos: u64) -> Vec<MigrationReport> {
+    report_migrations(MIGRATIONS, now_nanos)
+}
+
+fn report_migrations(migrations: &[Migration], now_nanos: u64) -> Vec<MigrationReport> {
+    let mut reports: Vec<MigrationReport> = migrations
+        .iter()
+        .map(|migration| {
+            let applied_time_nanos = get_migration_applied_time(migration.id);
+            MigrationReport {
+                id: migration.id,
+                name: migration.name.to_string(),
+                applied_time_nanos,
+                dry_run: applied_time_nanos
+                    .is_none()
+                    .then(|| (migration.run)(false, now_nanos)),
+            }
+        })
+        .collect();
+    reports.sort_by_key(|report| report.id);
+    reports
+}
+
 /// Moves `TARGET_SUBNET`'s rental agreement from App13CH to App7CH, repricing the
 /// cycles they have already paid for but not yet burned.
 ///
-/// Idempotent through the condition id itself: only agreements still on App13CH are
-/// touched, so the second upgrade finds none.
-///
-/// No payment is involved: the unburned cycles just buy more days at App7CH's cheaper rate.
-/// The new deadline is `now + remaining_cycles / new_daily_cost` rather than a scaling of the
-/// old one, because cycles burned before the migration were already charged at the old rate.
-pub fn app13ch_to_app7ch() {
-    let Ok(subnet_id) = Principal::from_text(TARGET_SUBNET) else {
-        println!("Migration to App7CH skipped: TARGET_SUBNET is not a valid principal");
-        return;
-    };
+/// Does not apply while the subnet is not rented yet. There is nothing to do if the agreement
+/// is no longer on App13CH.
+fn app13ch_to_app7ch(apply: bool, now_nanos: u64) -> Result<MigrationOutcome, String> {
+    let subnet_id = Principal::from_text(TARGET_SUBNET)
+        .map_err(|e| format!("TARGET_SUBNET is not a valid principal: {e}"))?;
 
     let Some(agreement) = get_rental_agreement(&subnet_id) else {
-        println!("Migration to App7CH skipped: no rental agreement for subnet {subnet_id}");
-        return;
+        return Ok(MigrationOutcome::NotApplicableYet(format!(
+            "No rental agreement for subnet {subnet_id} yet"
+        )));
     };
-
     if agreement.rental_condition_id != RentalConditionId::App13CH {
-        println!("Migration to App7CH already applied or not applicable; nothing to do");
-        return;
-    }
-
-    let Some(new_conditions) = get_rental_conditions(RentalConditionId::App7CH) else {
-        println!("Migration to App7CH skipped: App7CH rental conditions not found");
-        return;
-    };
-
-    let cycles_remaining = agreement
-        .total_cycles_created
-        .saturating_sub(agreement.total_cycles_burned);
-    let old_paid_until_nanos = agreement.paid_until_nanos;
-
-    let now_nanos = ic_cdk::api::time();
-    let new_paid_until_nanos = reprice(
-        cycles_remaining,
-        new_conditions.daily_cost_cycles,
-        now_nanos,
-    );
-
-    if let Err(e) = update_rental_agreement(subnet_id, |mut agreement| {
-        agreement.rental_condition_id = RentalConditionId::App7CH;
-        agreement.paid_until_nanos = new_paid_until_nanos;
-        agreement
-    }) {
-        println!("Migration of subnet {subnet_id} to App7CH failed: {e}");
-        return;
+        println!("Migration to App7CH: subnet {subnet_id} is not on App13CH");
+        return Ok(MigrationOutcome::Changes(vec![]));
     }
 
-    persist_event(
-        EventType::RentalConditionSwitched {
-            user: agreement.user,
-            old_condition_id: RentalConditionId::App13CH,
-            new_condition_id: RentalConditionId::App7CH,
-            cycles_remaining,
-            old_paid_until_nanos,
-            new_paid_until_nanos,
-        },
-        Some(subnet_id),
-    );
-    println!(
-        "Migrated subnet {subnet_id} from App13CH to App7CH: {cycles_remaining} cycles now \
-        paid until {new_paid_until_nanos} (was {old_paid_until_nanos})"
-    );
-}
-
-/// How long `cycles_remaining` lasts at `daily_cost_cycles`, as a deadline from `now_nanos`.
-///
-/// Truncating to a per-second cost makes a day cost marginally less than
-/// `daily_cost_cycles`, so the deadline is fractionally generous. Same rounding as the
-/// top-up path.
-fn reprice(cycles_remaining: u128, daily_cost_cycles: u128, now_nanos: u64) -> u64 {
-    let cost_cycles_per_second = daily_cost_cycles / (SECONDS_PER_DAY as u128);
-    if cost_cycles_per_second == 0 {
-        return u64::MAX;
+    let switch = condition_switch::plan(&agreement, RentalConditionId::App7CH, now_nanos)?;
+    if apply {
+        condition_switch::apply(&switch)?;
     }
-    let seconds_covered = cycles_remaining / cost_cycles_per_second;
-    (seconds_covered.saturating_mul(BILLION as u128))
-        .try_into()
-        .map_or(u64::MAX, |nanos: u64| now_nanos.saturating_add(nanos))
+    Ok(MigrationOutcome::Changes(vec![switch.describe()]))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::TRILLION;
+    use std::cell::Cell;
 
-    const APP13CH_DAILY: u128 = 820 * TRILLION;
-    const APP7CH_DAILY: u128 = 440 * TRILLION;
-    const DAY_NANOS: u64 = SECONDS_PER_DAY * BILLION;
+    thread_local! {
+        static FAIL: Cell<bool> = const { Cell::new(true) };
+    }
 
-    #[test]
-    fn reprice_is_exact_for_whole_days() {
-        assert_eq!(reprice(10 * APP7CH_DAILY, APP7CH_DAILY, 0), 10 * DAY_NANOS);
+    fn succeeds(_apply: bool, _now_nanos: u64) -> Result<MigrationOutcome, String> {
+        Ok(MigrationOutcome::Changes(vec!["change".to_string()]))
     }
 
-    #[test]
-    fn reprice_offsets_from_now() {
-        let now = 12_345 * DAY_NANOS;
-        let cycles = 10 * APP7CH_DAILY;
-        assert_eq!(reprice(cycles, APP7CH_DAILY, now), now + 10 * DAY_NANOS);
+    fn fails_once(apply: bool, _now_nanos: u64) -> Result<MigrationOutcome, String> {
+        if FAIL.get() {
+            if apply {
+                FAIL.set(false);
+            }
+            return Err("failed".to_string());
+        }
+        Ok(MigrationOutcome::Changes(vec![]))
     }
 
-    #[test]
-    fn cheaper_condition_buys_more_time() {
-        // 180 * 820 / 440 = 335.45
-        let days = reprice(180 * APP13CH_DAILY, APP7CH_DAILY, 0) / DAY_NANOS;
-        assert_eq!(days, 335);
+    fn not_applicable(_apply: bool, _now_nanos: u64) -> Result<MigrationOutcome, String> {
+        Ok(MigrationOutcome::NotApplicableYet("waiting".to_string()))
     }
 
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            id: 103,
+            name: "third",
+            run: succeeds,
+        },
+        Migration {
+            id: 101,
+            name: "first",
+            run: succeeds,
+        },
+        Migration {
+            id: 102,
+            name: "second",
+            run: fails_once,
+        },
+    ];
+
     #[test]
-    fn reprice_credits_only_whole_seconds() {
-        let cost_per_second = APP7CH_DAILY / (SECONDS_PER_DAY as u128);
-        // A truncated per-second cost undercharges the day, so just under a day's
-        // worth still covers the full day.
-        assert!(cost_per_second * (SECONDS_PER_DAY as u128) < APP7CH_DAILY);
-        assert_eq!(reprice(APP7CH_DAILY - 1, APP7CH_DAILY, 0), DAY_NANOS);
-
-        let cycles = cost_per_second * (SECONDS_PER_DAY as u128 - 1);
-        assert_eq!(reprice(cycles, APP7CH_DAILY, 0), DAY_NANOS - BILLION);
-
-        let cycles = APP7CH_DAILY + APP7CH_DAILY / 2;
-        assert_eq!(reprice(cycles, APP7CH_DAILY, 0) / DAY_NANOS, 1);
+    fn migrations_run_in_order_and_once() {
+        let reports = report_migrations(TEST_MIGRATIONS, 0);
+        assert_eq!(
+            reports.iter().map(|r| r.id).collect::<Vec<_>>(),
+            vec![101, 102, 103]
+        );
+        assert_eq!(
+            reports[0].dry_run,
+            Some(Ok(MigrationOutcome::Changes(vec!["change".to_string()])))
+        );
+        assert_eq!(reports[1].dry_run, Some(Err("failed".to_string())));
+        // A dry run changes nothing.
+        assert!(FAIL.get());
+
+        // The failing migration blocks the ones after it.
+        run_migrations(TEST_MIGRATIONS, 5);
+        assert_eq!(get_migration_applied_time(101), Some(5));
+        assert_eq!(get_migration_applied_time(102), None);
+        assert_eq!(get_migration_applied_time(103), None);
+
+        run_migrations(TEST_MIGRATIONS, 7);
+        assert_eq!(get_migration_applied_time(101), Some(5));
+        assert_eq!(get_migration_applied_time(102), Some(7));
+        assert_eq!(get_migration_applied_time(103), Some(7));
+
+        let reports = report_migrations(TEST_MIGRATIONS, 9);
+        assert!(reports
+            .iter()
+            .all(|r| r.applied_time_nanos.is_some() && r.dry_run.is_none()));
     }
 
     #[test]
-    fn no_cycles_left_means_no_time_left() {
-        let now = 99 * DAY_NANOS;
-        assert_eq!(reprice(0, APP7CH_DAILY, 0), 0);
-        assert_eq!(reprice(0, APP7CH_DAILY, now), now);
+    fn migrations_that_do_not_apply_yet_do_not_block_later_ones() {
+        const MIGRATIONS: &[Migration] = &[
+            Migration {
+                id: 201,
+                name: "waiting",
+                run: not_applicable,
+            },
+            Migration {
+                id: 202,
+                name: "later",
+                run: succeeds,
+            },
+        ];
+        run_migrations(MIGRATIONS, 5);
+        assert_eq!(get_migration_applied_time(201), None);
+        assert_eq!(get_migration_applied_time(202), Some(5));
+        let reports = report_migrations(MIGRATIONS, 7);
+        assert_eq!(
+            reports[0].dry_run,
+            Some(Ok(MigrationOutcome::NotApplicableYet(
+                "waiting".to_string()
+            )))
+        );
     }
 
     #[test]
-    fn reprice_saturates_instead_of_overflowing() {
-        assert_eq!(reprice(u128::MAX, APP7CH_DAILY, 0), u64::MAX);
-        assert_eq!(reprice(10 * APP7CH_DAILY, APP7CH_DAILY, u64::MAX), u64::MAX);
-        // A daily cost below one cycle per second would divide by zero.
-        assert_eq!(reprice(APP7CH_DAILY, 1, 0), u64::MAX);
+    fn app7ch_migration_waits_for_the_rental_agreement() {
+        for apply in [false, true] {
+            assert!(matches!(
+                app13ch_to_app7ch(apply, 0),
+                Ok(MigrationOutcome::NotApplicableYet(_))
+            ));
+        }
     }
 }
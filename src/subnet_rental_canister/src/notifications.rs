@@ -0,0 +1,233 @@
+//! Notifications to renters about their rental agreements.
+//!
+//! Renters register a canister method that the SRC calls one-way whenever the coverage of
+//! their agreement drops below a threshold, a top-up succeeds or fails, or the agreement
+//! becomes past due. One-way calls never block the SRC on a slow or malicious receiver,
+//! but their delivery is best effort. Every attempt is recorded in the subnet's history.
+
+use crate::{
+    canister_state::{
+        get_canister_ids, get_notification_registration, get_rental_agreement,
+        iter_notification_registrations, persist_event, set_notification_registration,
+    },
+    external_calls::notify_renter,
+    history::EventType,
+    CanisterIds, NotificationKind, NotificationRegistration, NotificationTarget,
+    RenterNotification, BILLION, SECONDS_PER_DAY,
+};
+use candid::Principal;
+use ic_cdk::println;
+
+/// The coverage thresholds used if a renter does not specify any.
+pub const DEFAULT_COVERAGE_THRESHOLDS_DAYS: [u64; 3] = [30, 7, 1];
+pub const MAX_COVERAGE_THRESHOLDS: usize = 10;
+pub const MAX_METHOD_NAME_LENGTH: usize = 100;
+
+/// The SRC and its dependencies, which must not be notified: the SRC pays for the calls, so a
+/// renter could otherwise call their methods on the SRC's behalf.
+pub fn reserved_canister_ids() -> Vec<Principal> {
+    let CanisterIds {
+        ledger,
+        cmc,
+        governance,
+        xrc,
+        registry,
+    } = get_canister_ids();
+    vec![
+        ic_cdk::api::canister_self(),
+        ledger,
+        cmc,
+        governance,
+        xrc,
+        registry,
+    ]
+}
+
+/// Checks a target and normalizes its thresholds into descending order without duplicates.
+/// `reserved_canister_ids` are rejected like the anonymous principal and the management canister.
+pub fn validate_target(
+    mut target: NotificationTarget,
+    reserved_canister_ids: &[Principal],
+) -> Result<NotificationTarget, String> {
+    if target.canister_id == Principal::anonymous()
+        || target.canister_id == Principal::management_canister()
+        || reserved_canister_ids.contains(&target.canister_id)
+    {
+        return Err(format!("Cannot notify {}", target.canister_id));
+    }
+    if target.method.is_empty() || target.method.len() > MAX_METHOD_NAME_LENGTH {
+        return Err(format!(
+            "The method name must have between 1 and {MAX_METHOD_NAME_LENGTH} bytes"
+        ));
+    }
+    if target.coverage_thresholds_days.len() > MAX_COVERAGE_THRESHOLDS {
+        return Err(format!(
+            "At most {MAX_COVERAGE_THRESHOLDS} coverage thresholds are allowed"
+        ));
+    }
+    // Coverage drops below a threshold of 0 days only once the agreement is past due,
+    // which has a notification of its own.
+    if target.coverage_thresholds_days.contains(&0) {
+        return Err("Coverage thresholds must be at least 1 day".to_string());
+    }
+    if target.coverage_thresholds_days.is_empty() {
+        target.coverage_thresholds_days = DEFAULT_COVERAGE_THRESHOLDS_DAYS.to_vec();
+    }
+    target
+        .coverage_thresholds_days
+        .sort_unstable_by(|a, b| b.cmp(a));
+    target.coverage_thresholds_days.dedup();
+    Ok(target)
+}
+
+/// The lowest threshold that the coverage has dropped below, if any.
+pub fn lowest_crossed_threshold(thresholds_days: &[u64], days_left: u64) -> Option<u64> {
+    thresholds_days
+        .iter()
+        .copied()
+        .filter(|threshold| days_left < *threshold)
+        .min()
+}
+
+/// Notifies the renter of a subnet, if they registered a notification target.
+pub fn notify(subnet_id: Principal, kind: NotificationKind) {
+    let Some(registration) = get_notification_registration(&subnet_id) else {
+        return;
+    };
+    let Some(agreement) = get_rental_agreement(&subnet_id) else {
+        return;
+    };
+    let now_nanos = ic_cdk::api::time();
+    let notification = RenterNotification {
+        subnet_id,
+        kind,
+        paid_until_nanos: agreement.paid_until_nanos,
+        days_left: agreement.paid_until_nanos.saturating_sub(now_nanos)
+            / (SECONDS_PER_DAY * BILLION),
+    };
+    let NotificationTarget {
+        canister_id,
+        method,
+        ..
+    } = registration.target;
+    let error = notify_renter(canister_id, &method, &notification).err();
+    if let Some(error) = &error {
+        println!("Failed to notify {canister_id} about subnet {subnet_id}: {error}");
+    }
+    persist_event(
+        EventType::RenterNotified {
+            notification,
+            canister_id,
+            method,
+            error,
+        },
+        Some(subnet_id),
+    );
+}
+
+/// Sends the coverage and past due notifications that have become due since the last check.
+pub fn check_coverage() {
+    let now_nanos = ic_cdk::api::time();
+    for (subnet_id, registration) in iter_notification_registrations() {
+        let Some(agreement) = get_rental_agreement(&subnet_id) else {
+            continue;
+        };
+        let days_left =
+            agreement.paid_until_nanos.saturating_sub(now_nanos) / (SECONDS_PER_DAY * BILLION);
+        let crossed =
+            lowest_crossed_threshold(&registration.target.coverage_thresholds_days, days_left);
+        let past_due = now_nanos >= agreement.paid_until_nanos;
+
+        let notify_threshold = match (crossed, registration.lowest_notified_threshold_days) {
+            (Some(threshold), None) => Some(threshold),
+            (Some(threshold), Some(notified)) if threshold < notified => Some(threshold),
+            _ => None,
+        };
+        let notify_past_due = past_due && !registration.past_due_notified;
+
+        // Update the state before notifying, so that a notification is attempted only once.
+        set_notification_registration(
+            subnet_id,
+            Some(NotificationRegistration {
+                lowest_notified_threshold_days: crossed,
+                past_due_notified: past_due,
+                ..registration
+            }),
+        );
+        if let Some(threshold_days) = notify_threshold {
+            notify(
+                subnet_id,
+                NotificationKind::CoverageBelowThreshold { threshold_days },
+            );
+        }
+        if notify_past_due {
+            notify(subnet_id, NotificationKind::PastDue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds_are_normalized() {
+        let target = NotificationTarget {
+            canister_id: Principal::from_slice(b"target"),
+            method: "notify".to_string(),
+            coverage_thresholds_days: vec![],
+        };
+        let reserved = [
+            Principal::from_slice(b"src"),
+            Principal::from_slice(b"ledger"),
+        ];
+        let validated = validate_target(target.clone(), &reserved).unwrap();
+        assert_eq!(validated.coverage_thresholds_days, vec![30, 7, 1]);
+
+        let validated = validate_target(
+            NotificationTarget {
+                coverage_thresholds_days: vec![7, 60, 7, 1],
+                ..target.clone()
+            },
+            &reserved,
+        )
+        .unwrap();
+        assert_eq!(validated.coverage_thresholds_days, vec![60, 7, 1]);
+
+        assert!(validate_target(
+            NotificationTarget {
+                coverage_thresholds_days: vec![7, 0],
+                ..target.clone()
+            },
+            &reserved,
+        )
+        .is_err());
+        assert!(validate_target(
+            NotificationTarget {
+                method: String::new(),
+                ..target.clone()
+            },
+            &reserved,
+        )
+        .is_err());
+        for canister_id in [Principal::anonymous(), reserved[0], reserved[1]] {
+            assert!(validate_target(
+                NotificationTarget {
+                    canister_id,
+                    ..target.clone()
+                },
+                &reserved,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn lowest_crossed_threshold_is_most_urgent() {
+        let thresholds = [30, 7, 1];
+        assert_eq!(lowest_crossed_threshold(&thresholds, 30), None);
+        assert_eq!(lowest_crossed_threshold(&thresholds, 29), Some(30));
+        assert_eq!(lowest_crossed_threshold(&thresholds, 6), Some(7));
+        assert_eq!(lowest_crossed_threshold(&thresholds, 0), Some(1));
+    }
+}
@@ -0,0 +1,180 @@
+//! Pricing math for subnet top-ups.
+//!
+//! `subnet_top_up_estimate` and `top_up_subnet` both derive cycles and rental time through
+//! this module. An estimate therefore matches the actual top-up exactly whenever the CMC
+//! converts at the rate the estimate was made with. Since the CMC rate moves independently
+//! of the previous midnight's XRC rate, an estimate also comes with explicit bounds.
+
+use crate::{BILLION, SECONDS_PER_DAY};
+use ic_cdk::println;
+use ic_ledger_types::{Tokens, DEFAULT_FEE};
+
+/// The fees deducted from a subaccount balance before the rest is converted to cycles:
+/// `top_up_subnet` keeps one ledger fee back and the transfer to the CMC costs another.
+pub const TOP_UP_FEES: Tokens = Tokens::from_e8s(2 * DEFAULT_FEE.e8s());
+/// The relative deviation of the CMC rate from the estimated rate that the bounds of an
+/// estimate account for, in units of 1/10_000.
+pub const RATE_TOLERANCE_PERMYRIAD: u64 = 500;
+/// The number of decimals of the effective rate reported for an actual top-up.
+pub const EFFECTIVE_RATE_DECIMALS: u32 = 9;
+
+/// The effect of converting some amount of cycles on a rental agreement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopUpCalculation {
+    pub cycles_added: u128,
+    /// The rental time that the cycles cover, rounded down to the second.
+    pub seconds_added: u128,
+    /// The full days contained in `seconds_added`.
+    pub days_added: u64,
+    pub new_paid_until_nanos: u64,
+}
+
+/// An estimate of a top-up, together with the outcomes at the lowest and highest rate
+/// within `RATE_TOLERANCE_PERMYRIAD` of the rate used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopUpEstimate {
+    pub expected: TopUpCalculation,
+    pub lower: TopUpCalculation,
+    pub upper: TopUpCalculation,
+}
+
+/// The ICP that is converted to cycles when topping up from the given subaccount balance,
+/// or `None` if the balance does not cover the fees.
+pub fn icp_to_convert(balance: Tokens) -> Option<Tokens> {
+    (balance > TOP_UP_FEES).then(|| balance - TOP_UP_FEES)
+}
+
+/// Converts ICP to cycles at the given XDR/ICP rate, scaled by 10^decimals.
+pub fn cycles_for_icp(icp: Tokens, scaled_exchange_rate_xdr_per_icp: u64, decimals: u32) -> u128 {
+    // Factor 10_000 to go from trillion cycles (10^12) to e8s (10^8).
+    icp.e8s() as u128 * scaled_exchange_rate_xdr_per_icp as u128 * 10_000 / u128::pow(10, decimals)
+}
+
+/// The ICP needed to obtain at least `cycles` at the given XDR/ICP rate, scaled by 10^decimals,
+/// or `None` on overflow or a zero rate.
+pub fn icp_for_cycles(
+    cycles: u128,
+    scaled_exchange_rate_xdr_per_icp: u64,
+    decimals: u32,
+) -> Option<Tokens> {
+    if scaled_exchange_rate_xdr_per_icp == 0 {
+        return None;
+    }
+    // Factor 10_000 to go from trillion cycles (10^12) to e8s (10^8).
+    let e8s = cycles
+        .checked_mul(u128::pow(10, decimals))?
+        .div_ceil(scaled_exchange_rate_xdr_per_icp as u128 * 10_000);
+    e8s.try_into().ok().map(Tokens::from_e8s)
+}
+
+/// The XDR/ICP rate at which the CMC converted `icp` into `cycles`,
+/// scaled by 10^`EFFECTIVE_RATE_DECIMALS`.
+pub fn effective_rate(icp: Tokens, cycles: u128) -> u64 {
+    if icp.e8s() == 0 {
+        return 0;
+    }
+    let rate = cycles * u128::pow(10, EFFECTIVE_RATE_DECIMALS) / 10_000 / icp.e8s() as u128;
+    rate.try_into().unwrap_or(u64::MAX)
+}
+
+/// Applies converted cycles to an agreement that is paid until `paid_until_nanos`.
+pub fn calculate_top_up(
+    cycles_added: u128,
+    daily_cost_cycles: u128,
+    paid_until_nanos: u64,
+) -> TopUpCalculation {
+    // Convert cost to cycles per second, rounding down.
+    let cost_cycles_per_second = (daily_cost_cycles / SECONDS_PER_DAY as u128).max(1);
+    let seconds_added = cycles_added / cost_cycles_per_second;
+    let new_paid_until_nanos =
+        (paid_until_nanos as u128).saturating_add(seconds_added.saturating_mul(BILLION as u128));
+    let new_paid_until_nanos = new_paid_until_nanos.try_into().unwrap_or_else(|_| {
+        // At the year 2554, u64 is too small to represent the number of nanoseconds since 1970.
+        println!(
+            "Warning: Top-up of {cycles_added} cycles caused a u64 overflow, \
+            capping at maximum possible u64 value"
+        );
+        u64::MAX
+    });
+    TopUpCalculation {
+        cycles_added,
+        seconds_added,
+        // Until the year 2554, u64 is enough to represent the number of days.
+        days_added: (seconds_added / SECONDS_PER_DAY as u128) as u64,
+        new_paid_until_nanos,
+    }
+}
+
+/// Estimates a top-up of `icp` converted at the given rate.
+pub fn estimate_top_up(
+    icp: Tokens,
+    scaled_exchange_rate_xdr_per_icp: u64,
+    decimals: u32,
+    daily_cost_cycles: u128,
+    paid_until_nanos: u64,
+) -> TopUpEstimate {
+    let deviation = (scaled_exchange_rate_xdr_per_icp as u128 * RATE_TOLERANCE_PERMYRIAD as u128
+        / 10_000) as u64;
+    let at_rate = |rate| {
+        calculate_top_up(
+            cycles_for_icp(icp, rate, decimals),
+            daily_cost_cycles,
+            paid_until_nanos,
+        )
+    };
+    TopUpEstimate {
+        expected: at_rate(scaled_exchange_rate_xdr_per_icp),
+        lower: at_rate(scaled_exchange_rate_xdr_per_icp - deviation),
+        upper: at_rate(scaled_exchange_rate_xdr_per_icp.saturating_add(deviation)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TRILLION;
+
+    #[test]
+    fn actual_top_up_matches_estimate_at_same_rate() {
+        let rate = 4_103_000_000; // 1 ICP = 4.103 XDR
+        let daily_cost_cycles = 835 * TRILLION;
+        let icp = icp_to_convert(Tokens::from_e8s(1_000 * crate::E8S)).unwrap();
+        let estimate = estimate_top_up(icp, rate, 9, daily_cost_cycles, BILLION);
+
+        let cycles = cycles_for_icp(icp, rate, 9);
+        let actual = calculate_top_up(cycles, daily_cost_cycles, BILLION);
+        assert_eq!(estimate.expected, actual);
+        assert_eq!(effective_rate(icp, cycles), rate);
+
+        assert!(estimate.lower.cycles_added < actual.cycles_added);
+        assert!(estimate.upper.cycles_added > actual.cycles_added);
+        assert!(estimate.lower.new_paid_until_nanos <= actual.new_paid_until_nanos);
+        assert!(estimate.upper.new_paid_until_nanos >= actual.new_paid_until_nanos);
+    }
+
+    #[test]
+    fn icp_for_cycles_covers_the_cycles() {
+        let rate = 4_103_000_000;
+        let cycles = 90 * 835 * TRILLION;
+        let icp = icp_for_cycles(cycles, rate, 9).unwrap();
+        assert!(cycles_for_icp(icp, rate, 9) >= cycles);
+        assert!(cycles_for_icp(icp - Tokens::from_e8s(1), rate, 9) < cycles);
+        assert_eq!(icp_for_cycles(cycles, 0, 9), None);
+        assert_eq!(icp_for_cycles(u128::MAX, rate, 9), None);
+    }
+
+    #[test]
+    fn fees_must_be_covered() {
+        assert_eq!(icp_to_convert(TOP_UP_FEES), None);
+        assert_eq!(
+            icp_to_convert(TOP_UP_FEES + Tokens::from_e8s(1)),
+            Some(Tokens::from_e8s(1))
+        );
+    }
+
+    #[test]
+    fn paid_until_saturates() {
+        let calculation = calculate_top_up(u128::MAX, 1, u64::MAX - 1);
+        assert_eq!(calculation.new_paid_until_nanos, u64::MAX);
+    }
+}
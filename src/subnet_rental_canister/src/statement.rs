@@ -0,0 +1,313 @@
+//! Account statements of rental agreements.
+//!
+//! Top-ups and repricings are reconstructed from the subnet's history, and burns from the
+//! daily billing records. The initial payment is not recorded under the subnet, so it is
+//! derived from the agreement's counters minus all top-ups. Burns from before billing
+//! records existed are attributed to the time before the first record.
+//!
+//! A statement needs the agreement's whole history, so it is read in pages. A
+//! `StatementCursor` carries the sums of what has been read from one page to the next.
+
+use crate::{
+    history::{Event, EventType},
+    BillingRecord, RentalAgreement, Statement, StatementCursor, StatementPayment, BILLION,
+    SECONDS_PER_DAY,
+};
+use ic_ledger_types::Tokens;
+use std::fmt::Write;
+
+/// Returns the start of the day of `from_secs` and the end of the day of `to_secs`.
+pub fn period(from_secs: u64, to_secs: u64) -> (u64, u64) {
+    (
+        from_secs - from_secs % SECONDS_PER_DAY,
+        (to_secs - to_secs % SECONDS_PER_DAY).saturating_add(SECONDS_PER_DAY),
+    )
+}
+
+/// Returns a cursor that starts reading at the subnet's event `first_seq`, which must be the
+/// first one of the agreement, and at the billing record of the period's first day.
+pub fn start(first_seq: u64, from_secs: u64) -> StatementCursor {
+    StatementCursor {
+        next_seq: first_seq,
+        next_record_day_secs: from_secs,
+        initial_rental_condition_id: None,
+        top_up_icp: Tokens::from_e8s(0),
+        top_up_cycles: 0,
+        top_up_cycles_before: 0,
+        top_ups: vec![],
+        paid_until_nanos: None,
+        cycles_burned_since: 0,
+        cycles_burned: 0,
+    }
+}
+
+/// Adds the subnet's events read in order from `cursor.next_seq` to the cursor, for the
+/// period from `from_secs` to `to_secs` as returned by `period`.
+pub fn read_events(cursor: &mut StatementCursor, events: &[Event], from_secs: u64, to_secs: u64) {
+    let from_nanos = from_secs.saturating_mul(BILLION);
+    let to_nanos = to_secs.saturating_mul(BILLION);
+    for event in events {
+        match event.event() {
+            EventType::RentalAgreementCreated {
+                rental_condition_id,
+                ..
+            } => {
+                cursor
+                    .initial_rental_condition_id
+                    .get_or_insert(rental_condition_id);
+            }
+            EventType::SubnetTopUp {
+                icp_amount,
+                cycles_added,
+                new_paid_until_nanos,
+                ..
+            } => {
+                cursor.top_up_icp =
+                    Tokens::from_e8s(cursor.top_up_icp.e8s().saturating_add(icp_amount.e8s()));
+                cursor.top_up_cycles = cursor.top_up_cycles.saturating_add(cycles_added);
+                if event.time_nanos() < from_nanos {
+                    cursor.top_up_cycles_before =
+                        cursor.top_up_cycles_before.saturating_add(cycles_added);
+                } else if event.time_nanos() < to_nanos {
+                    cursor.top_ups.push(StatementPayment {
+                        time_nanos: event.time_nanos(),
+                        icp: icp_amount,
+                        cycles: cycles_added,
+                    });
+                }
+                if event.time_nanos() < to_nanos {
+                    cursor.paid_until_nanos = Some(new_paid_until_nanos);
+                }
+            }
+            EventType::RentalConditionSwitched {
+                new_paid_until_nanos,
+                ..
+            } if event.time_nanos() < to_nanos => {
+                cursor.paid_until_nanos = Some(new_paid_until_nanos)
+            }
+            _ => {}
+        }
+    }
+    cursor.next_seq += events.len() as u64;
+}
+
+/// Adds the subnet's billing records read in order from `cursor.next_record_day_secs` to
+/// the cursor, for a period ending at `to_secs`.
+pub fn read_billing_records(cursor: &mut StatementCursor, records: &[BillingRecord], to_secs: u64) {
+    for record in records {
+        cursor.cycles_burned_since = cursor
+            .cycles_burned_since
+            .saturating_add(record.cycles_burned);
+        if record.day_start_secs < to_secs {
+            cursor.cycles_burned = cursor.cycles_burned.saturating_add(record.cycles_burned);
+        }
+        cursor.next_record_day_secs = record.day_start_secs.saturating_add(SECONDS_PER_DAY);
+    }
+}
+
+/// Builds the statement for the period from `from_secs` to `to_secs` as returned by `period`,
+/// once the cursor has read all events and billing records. `initial_period_nanos` is the
+/// initial rental period of the agreement's initial rental condition.
+pub fn finish(
+    agreement: &RentalAgreement,
+    cursor: StatementCursor,
+    initial_period_nanos: u64,
+    from_secs: u64,
+    to_secs: u64,
+) -> Statement {
+    let from_nanos = from_secs.saturating_mul(BILLION);
+    let to_nanos = to_secs.saturating_mul(BILLION);
+    let initial_payment = StatementPayment {
+        time_nanos: agreement.creation_time_nanos,
+        icp: Tokens::from_e8s(
+            agreement
+                .total_icp_paid
+                .e8s()
+                .saturating_sub(cursor.top_up_icp.e8s()),
+        ),
+        cycles: agreement
+            .total_cycles_created
+            .saturating_sub(cursor.top_up_cycles),
+    };
+
+    let mut paid_before = cursor.top_up_cycles_before;
+    if initial_payment.time_nanos < from_nanos {
+        paid_before = paid_before.saturating_add(initial_payment.cycles);
+    }
+    let burned_before = agreement
+        .total_cycles_burned
+        .saturating_sub(cursor.cycles_burned_since);
+
+    let payments: Vec<_> = std::iter::once(initial_payment)
+        .filter(|payment| (from_nanos..to_nanos).contains(&payment.time_nanos))
+        .chain(cursor.top_ups)
+        .collect();
+    let total_paid_icp = Tokens::from_e8s(payments.iter().map(|p| p.icp.e8s()).sum());
+    let total_paid_cycles: u128 = payments.iter().map(|p| p.cycles).sum();
+    let opening_balance_cycles = paid_before.saturating_sub(burned_before);
+    let closing_balance_cycles =
+        (opening_balance_cycles + total_paid_cycles).saturating_sub(cursor.cycles_burned);
+
+    Statement {
+        subnet_id: agreement.subnet_id,
+        user: agreement.user,
+        from_secs,
+        to_secs,
+        opening_balance_cycles,
+        payments,
+        total_paid_icp,
+        total_paid_cycles,
+        cycles_burned: cursor.cycles_burned,
+        closing_balance_cycles,
+        paid_until_nanos: cursor.paid_until_nanos.unwrap_or(
+            agreement
+                .creation_time_nanos
+                .saturating_add(initial_period_nanos),
+        ),
+    }
+}
+
+/// Renders a statement as CSV with one line per entry.
+pub fn to_csv(statement: &Statement) -> String {
+    let mut csv = "time_nanos,entry,icp_e8s,cycles\n".to_string();
+    let from_nanos = statement.from_secs.saturating_mul(BILLION);
+    let to_nanos = statement.to_secs.saturating_mul(BILLION);
+    // Writing to a String cannot fail.
+    let _ = writeln!(
+        csv,
+        "{from_nanos},opening_balance,,{}",
+        statement.opening_balance_cycles
+    );
+    for payment in &statement.payments {
+        let _ = writeln!(
+            csv,
+            "{},payment,{},{}",
+            payment.time_nanos,
+            payment.icp.e8s(),
+            payment.cycles
+        );
+    }
+    let _ = writeln!(csv, "{to_nanos},cycles_burned,,{}", statement.cycles_burned);
+    let _ = writeln!(
+        csv,
+        "{to_nanos},closing_balance,,{}",
+        statement.closing_balance_cycles
+    );
+    let _ = writeln!(csv, "{},paid_until,,", statement.paid_until_nanos);
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RentalConditionId, TRILLION};
+    use candid::Principal;
+
+    const DAY_NANOS: u64 = SECONDS_PER_DAY * BILLION;
+
+    fn record(day: u64, cycles_burned: u128) -> BillingRecord {
+        BillingRecord {
+            subnet_id: Principal::anonymous(),
+            day_start_secs: day * SECONDS_PER_DAY,
+            rental_condition_id: RentalConditionId::App13CH,
+            daily_cost_cycles: 10 * TRILLION,
+            cycles_burned,
+            cycles_burned_past_due: 0,
+            covered_nanos: DAY_NANOS,
+            billed_until_nanos: (day + 1) * DAY_NANOS,
+        }
+    }
+
+    /// Reads the events and records one at a time, like `get_statement` with tiny pages.
+    fn build_statement(
+        agreement: &RentalAgreement,
+        events: &[Event],
+        records: &[BillingRecord],
+        initial_period_nanos: u64,
+        from_secs: u64,
+        to_secs: u64,
+    ) -> Statement {
+        let (from_secs, to_secs) = period(from_secs, to_secs);
+        let mut cursor = start(0, from_secs);
+        for event in events {
+            read_events(&mut cursor, std::slice::from_ref(event), from_secs, to_secs);
+        }
+        assert_eq!(cursor.next_seq, events.len() as u64);
+        for record in records {
+            if record.day_start_secs >= cursor.next_record_day_secs {
+                read_billing_records(&mut cursor, std::slice::from_ref(record), to_secs);
+            }
+        }
+        finish(agreement, cursor, initial_period_nanos, from_secs, to_secs)
+    }
+
+    #[test]
+    fn statement_balances_add_up() {
+        // Created at day 100 with 1800 T cycles for 180 days, topped up at day 102 with 100 T.
+        let agreement = RentalAgreement {
+            user: Principal::anonymous(),
+            rental_request_proposal_id: 1,
+            subnet_creation_proposal_id: Some(2),
+            subnet_id: Principal::anonymous(),
+            rental_condition_id: RentalConditionId::App13CH,
+            creation_time_nanos: 100 * DAY_NANOS,
+            paid_until_nanos: 290 * DAY_NANOS,
+            total_icp_paid: Tokens::from_e8s(1_900),
+            total_cycles_created: 1_900 * TRILLION,
+            total_cycles_burned: 40 * TRILLION,
+        };
+        let events = vec![Event::_mk_event(
+            102 * DAY_NANOS + 5,
+            EventType::SubnetTopUp {
+                user: Principal::anonymous(),
+                icp_amount: Tokens::from_e8s(100),
+                cycles_added: 100 * TRILLION,
+                days_added: 10,
+                new_paid_until_nanos: 290 * DAY_NANOS,
+            },
+        )];
+        let records: Vec<_> = (100..104).map(|day| record(day, 10 * TRILLION)).collect();
+
+        // days 101 and 102
+        let statement = build_statement(
+            &agreement,
+            &events,
+            &records,
+            180 * DAY_NANOS,
+            101 * SECONDS_PER_DAY + 7,
+            102 * SECONDS_PER_DAY,
+        );
+        assert_eq!(statement.from_secs, 101 * SECONDS_PER_DAY);
+        assert_eq!(statement.to_secs, 103 * SECONDS_PER_DAY);
+        assert_eq!(statement.opening_balance_cycles, 1_790 * TRILLION);
+        assert_eq!(statement.payments.len(), 1);
+        assert_eq!(statement.total_paid_icp, Tokens::from_e8s(100));
+        assert_eq!(statement.total_paid_cycles, 100 * TRILLION);
+        assert_eq!(statement.cycles_burned, 20 * TRILLION);
+        assert_eq!(statement.closing_balance_cycles, 1_870 * TRILLION);
+        assert_eq!(statement.paid_until_nanos, 290 * DAY_NANOS);
+
+        // day 100 only contains the initial payment
+        let statement = build_statement(
+            &agreement,
+            &events,
+            &records,
+            180 * DAY_NANOS,
+            100 * SECONDS_PER_DAY,
+            100 * SECONDS_PER_DAY,
+        );
+        assert_eq!(statement.opening_balance_cycles, 0);
+        assert_eq!(statement.total_paid_icp, Tokens::from_e8s(1_800));
+        assert_eq!(statement.total_paid_cycles, 1_800 * TRILLION);
+        assert_eq!(statement.closing_balance_cycles, 1_790 * TRILLION);
+        assert_eq!(statement.paid_until_nanos, 280 * DAY_NANOS);
+
+        let csv = to_csv(&statement);
+        assert_eq!(csv.lines().count(), 6);
+        assert!(csv.contains(&format!(
+            "{},payment,1800,{}",
+            100 * DAY_NANOS,
+            1_800 * TRILLION
+        )));
+    }
+}
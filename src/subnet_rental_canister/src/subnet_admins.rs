@@ -0,0 +1,146 @@
+//! The admins of rented subnets.
+//!
+//! The registry is the source of truth for the admins of a subnet. The SRC keeps the admin set
+//! that results from each successful `update_subnet_admins` call, so that renters can look up
+//! their admins without querying the registry, and so that `MAX_ALLOWED_SUBNET_ADMINS` can be
+//! enforced on the resulting set.
+//!
+//! A subnet can have admins that the SRC does not know about, set when the subnet was created
+//! or before the SRC tracked admins. The SRC cannot read them from the registry, so the first
+//! update of a subnet's admins clears them in the registry before applying the operation.
+//! From then on, the tracked set and the registry agree. Transferring a rental agreement also
+//! clears the admins.
+
+use crate::{OperationType, UpdateSubnetAdminsError, MAX_ALLOWED_SUBNET_ADMINS};
+use candid::Principal;
+use std::collections::BTreeSet;
+
+/// Checks that the principal list of `operation` contains neither duplicates nor the anonymous
+/// principal, and that the resulting admin set does not exceed `MAX_ALLOWED_SUBNET_ADMINS`.
+/// Returns the resulting admin set.
+pub fn validate_operation(
+    admins: &BTreeSet<Principal>,
+    operation: &OperationType,
+) -> Result<BTreeSet<Principal>, UpdateSubnetAdminsError> {
+    if let OperationType::Add(principals) | OperationType::Remove(principals) = operation {
+        let mut seen = BTreeSet::new();
+        for principal in principals.get() {
+            if *principal == Principal::anonymous() || !seen.insert(principal) {
+                return Err(UpdateSubnetAdminsError::InvalidPrincipal(*principal));
+            }
+        }
+    }
+    let new_admins = apply_operation(admins, operation);
+    if new_admins.len() > MAX_ALLOWED_SUBNET_ADMINS {
+        return Err(UpdateSubnetAdminsError::TooManyAdmins {
+            max: MAX_ALLOWED_SUBNET_ADMINS as u64,
+            resulting: new_admins.len() as u64,
+        });
+    }
+    Ok(new_admins)
+}
+
+/// Returns the admin set that results from applying `operation` to `admins`.
+pub fn apply_operation(
+    admins: &BTreeSet<Principal>,
+    operation: &OperationType,
+) -> BTreeSet<Principal> {
+    match operation {
+        OperationType::Add(principals) => admins
+            .iter()
+            .chain(principals.get().iter())
+            .copied()
+            .collect(),
+        OperationType::Remove(principals) => {
+            let removed: BTreeSet<_> = principals.get().iter().collect();
+            admins
+                .iter()
+                .filter(|admin| !removed.contains(admin))
+                .copied()
+                .collect()
+        }
+        OperationType::Clear(_) => BTreeSet::new(),
+    }
+}
+
+/// Returns the principals that were added and removed, respectively, to get from `old` to `new`.
+pub fn diff(
+    old: &BTreeSet<Principal>,
+    new: &BTreeSet<Principal>,
+) -> (Vec<Principal>, Vec<Principal>) {
+    (
+        new.difference(old).copied().collect(),
+        old.difference(new).copied().collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EmptyRecord;
+    use candid::types::bounded_vec::BoundedVec;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn operations_result_in_expected_sets() {
+        let admins = BTreeSet::from([principal(1), principal(2)]);
+
+        let added = apply_operation(
+            &admins,
+            &OperationType::Add(BoundedVec::new(vec![principal(2), principal(3)])),
+        );
+        assert_eq!(
+            added,
+            BTreeSet::from([principal(1), principal(2), principal(3)])
+        );
+        assert_eq!(diff(&admins, &added), (vec![principal(3)], vec![]));
+
+        let removed = apply_operation(
+            &admins,
+            &OperationType::Remove(BoundedVec::new(vec![principal(1), principal(4)])),
+        );
+        assert_eq!(removed, BTreeSet::from([principal(2)]));
+        assert_eq!(diff(&admins, &removed), (vec![], vec![principal(1)]));
+
+        let cleared = apply_operation(&admins, &OperationType::Clear(EmptyRecord {}));
+        assert!(cleared.is_empty());
+        assert_eq!(
+            diff(&admins, &cleared),
+            (vec![], vec![principal(1), principal(2)])
+        );
+    }
+
+    #[test]
+    fn invalid_operations_are_rejected() {
+        let admins: BTreeSet<_> = (1..=9).map(principal).collect();
+
+        let duplicate = OperationType::Add(BoundedVec::new(vec![principal(10), principal(10)]));
+        assert_eq!(
+            validate_operation(&admins, &duplicate),
+            Err(UpdateSubnetAdminsError::InvalidPrincipal(principal(10)))
+        );
+
+        let anonymous = OperationType::Remove(BoundedVec::new(vec![Principal::anonymous()]));
+        assert_eq!(
+            validate_operation(&admins, &anonymous),
+            Err(UpdateSubnetAdminsError::InvalidPrincipal(
+                Principal::anonymous()
+            ))
+        );
+
+        let at_limit = OperationType::Add(BoundedVec::new(vec![principal(1), principal(10)]));
+        assert_eq!(validate_operation(&admins, &at_limit).unwrap().len(), 10);
+
+        let over_limit = OperationType::Add(BoundedVec::new(vec![principal(10), principal(11)]));
+        assert_eq!(
+            validate_operation(&admins, &over_limit),
+            Err(UpdateSubnetAdminsError::TooManyAdmins {
+                max: 10,
+                resulting: 11
+            })
+        );
+    }
+}
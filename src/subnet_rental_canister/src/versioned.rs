@@ -0,0 +1,122 @@
+//! Versioned encoding of the types kept in stable memory.
+//!
+//! Every stored type is encoded as an envelope of `MAGIC`, the version of the type as a
+//! little-endian u16, and the candid encoding of the value. Bytes without the magic were
+//! written before envelopes were introduced; they are plain candid and treated as version 0.
+//!
+//! Candid decodes values written by an older type as long as the change is compatible, e.g.,
+//! a new optional field. For any other change, bump the type's `VERSION` and decode the
+//! older versions in its `upgrade` function, so that existing entries keep decoding after
+//! the upgrade instead of trapping.
+
+use candid::{CandidType, Decode, Encode};
+use serde::de::DeserializeOwned;
+
+/// Distinct from the magic "DIDL" that candid encodings start with.
+const MAGIC: &[u8; 4] = b"SRCV";
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+pub trait Versioned: CandidType + DeserializeOwned {
+    /// The version that `encode` writes.
+    const VERSION: u16;
+
+    /// Decodes the candid `payload` of an older `version`. The default decodes it as the
+    /// current type, which is correct for versions that only differ in compatible ways.
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        decode_candid(payload).map_err(|e| format!("Failed to upgrade from version {version}: {e}"))
+    }
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(64);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+    bytes.extend_from_slice(&Encode!(value).unwrap());
+    bytes
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Result<T, String> {
+    if !bytes.starts_with(MAGIC) {
+        return T::upgrade(0, bytes);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err("Truncated envelope".to_string());
+    }
+    let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+    let payload = &bytes[HEADER_LEN..];
+    if version == T::VERSION {
+        decode_candid(payload)
+    } else if version < T::VERSION {
+        T::upgrade(version, payload)
+    } else {
+        Err(format!(
+            "Version {version} is newer than the supported version {}",
+            T::VERSION
+        ))
+    }
+}
+
+fn decode_candid<T: CandidType + DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    Decode!(payload, T).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Deserialize;
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct RecordV0 {
+        days: u64,
+    }
+
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct Record {
+        days: u64,
+        hours: u64,
+    }
+
+    impl Versioned for Record {
+        const VERSION: u16 = 2;
+
+        fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+            match version {
+                0 | 1 => {
+                    let RecordV0 { days } = decode_candid(payload)?;
+                    Ok(Record {
+                        days,
+                        hours: days * 24,
+                    })
+                }
+                _ => Err(format!("Unknown version {version}")),
+            }
+        }
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let record = Record { days: 2, hours: 7 };
+        assert_eq!(decode::<Record>(&encode(&record)), Ok(record));
+    }
+
+    #[test]
+    fn older_versions_are_upgraded() {
+        // Plain candid from before envelopes existed.
+        let legacy = Encode!(&RecordV0 { days: 3 }).unwrap();
+        assert_eq!(decode::<Record>(&legacy), Ok(Record { days: 3, hours: 72 }));
+
+        let mut v1 = MAGIC.to_vec();
+        v1.extend_from_slice(&1u16.to_le_bytes());
+        v1.extend_from_slice(&legacy);
+        assert_eq!(decode::<Record>(&v1), Ok(Record { days: 3, hours: 72 }));
+    }
+
+    #[test]
+    fn newer_and_broken_encodings_are_errors() {
+        let mut newer = encode(&Record { days: 1, hours: 1 });
+        newer[MAGIC.len()] = 3;
+        assert!(decode::<Record>(&newer).is_err());
+        assert!(decode::<Record>(&MAGIC[..]).is_err());
+        assert!(decode::<Record>(b"garbage").is_err());
+    }
+}
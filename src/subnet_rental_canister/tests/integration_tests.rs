@@ -1,5 +1,7 @@
 use candid::{
-    decode_one, encode_args, encode_one, types::bounded_vec::BoundedVec, utils::ArgumentEncoder,
+    decode_one, encode_args, encode_one,
+    types::{bounded_vec::BoundedVec, value::IDLValue},
+    utils::ArgumentEncoder,
     CandidType, Principal,
 };
 use ic_ledger_types::{
@@ -15,27 +17,37 @@ use pocket_ic::{
     PocketIc, PocketIcBuilder, Time,
 };
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
 use std::{
     collections::{HashMap, HashSet},
     fs,
     time::Duration,
 };
 use subnet_rental_canister::{
-    external_calls::get_exchange_rate_canister_id,
     external_types::{
         CmcInitPayload, ExchangeRateCanister, FeatureFlags, NnsLedgerCanisterInitPayload,
         NnsLedgerCanisterPayload, PrincipalsAuthorizedToCreateCanistersToSubnetsResponse,
     },
-    CreateRentalAgreementPayload, EmptyRecord, EventPage, ExecuteProposalError, OperationType,
-    RentalAgreement, RentalAgreementStatus, RentalConditionId, RentalConditions, RentalRequest,
-    SubnetRentalProposalPayload, TopUpSummary, UpdateSubnetAdminsError, UpdateSubnetAdminsPayload,
-    UpdateSubnetAdminsResult, E8S, MIGRATION_TARGET_SUBNET, TRILLION,
+    AgreementRole, AgreementRolesError, AgreementRolesPayload, AgreementTransferError,
+    ApproveAgreementTransferPayload, BillingRecord, CachedRate, CanisterIds, CanisterStatus,
+    CreatePriceQuoteError, CreateRentalAgreementPayload, EmptyRecord, EventPage,
+    ExecuteProposalError, HistoricalPrice, HttpRequest, HttpResponse, InitArgs, MigrationOutcome,
+    MigrationReport, NotificationRegistration, NotificationTarget, OperationType,
+    OverrideExchangeRatePayload, PendingAgreementTransfer, PriceError, PriceQuote,
+    ProposeAgreementTransferPayload, RateProvenance, RefundError, RegisterNotificationTargetError,
+    RegisterNotificationTargetPayload, RejectRentalRequestPayload, RentalAgreement,
+    RentalAgreementStatus, RentalAgreementStatusError, RentalConditionId, RentalConditions,
+    RentalRequest, Statement, StatementCsvPage, StatementCursor, StatementError, StatementPage,
+    StorageCheckReport, SubnetRentalProposalPayload, SwitchRentalConditionPayload, TopUpError,
+    TopUpSummary, UpdateSubnetAdminsError, UpdateSubnetAdminsPayload, UpdateSubnetAdminsResult,
+    E8S, MAINNET_EXCHANGE_RATE_CANISTER_ID, MIGRATION_TARGET_SUBNET, TRILLION,
 };
 
 const SRC_WASM: &str = "../../subnet_rental_canister.wasm.gz";
 const LEDGER_WASM: &str = "./tests/ledger-canister.wasm.gz";
 const CMC_WASM: &str = "./tests/cycles-minting-canister.wasm.gz";
 const XRC_WASM: &str = "./tests/exchange-rate-canister.wasm";
+const GOVERNANCE_WASM: &str = "./tests/governance-canister.wasm";
 const SRC_ID: Principal = Principal::from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x0D\x01\x01"); // qvhpv-4qaaa-aaaaa-aaagq-cai
 const NANOS_PER_SECOND: u64 = 1_000_000_000;
 const SUBNET_FOR_RENT: Principal = Principal::from_slice(b"\xBA\x58\xB2\x11\x25\x38\x1B\x05\x67\xE6\x1F\x3F\x2E\xCD\x65\xF3\x77\x10\x31\x60\x84\xEE\x79\x1C\xDF\xDB\x4A\x1A\x02"); // fuqsr-in2lc-zbcjj-ydmcw-pzq7h-4xm2z-pto4i-dcyee-5z4rz-x63ji-nae
@@ -48,11 +60,11 @@ const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
 
 fn install_xrc_and_cmc(pic: &PocketIc) {
     // install XRC
-    pic.create_canister_with_id(None, None, get_exchange_rate_canister_id())
+    pic.create_canister_with_id(None, None, MAINNET_EXCHANGE_RATE_CANISTER_ID)
         .unwrap();
     let xrc_wasm =
         fs::read(XRC_WASM).expect("Get the Wasm dependencies with ./scripts/get_wasms.sh");
-    pic.install_canister(get_exchange_rate_canister_id(), xrc_wasm, vec![], None);
+    pic.install_canister(MAINNET_EXCHANGE_RATE_CANISTER_ID, xrc_wasm, vec![], None);
 
     // install CMC
     pic.create_canister_with_id(None, None, MAINNET_CYCLES_MINTING_CANISTER_ID)
@@ -65,7 +77,7 @@ fn install_xrc_and_cmc(pic: &PocketIc) {
         minting_account_id: minter.to_string(),
         ledger_canister_id: Some(MAINNET_LEDGER_CANISTER_ID),
         last_purged_notification: None,
-        exchange_rate_canister: Some(ExchangeRateCanister::Set(get_exchange_rate_canister_id())),
+        exchange_rate_canister: Some(ExchangeRateCanister::Set(MAINNET_EXCHANGE_RATE_CANISTER_ID)),
         cycles_ledger_canister_id: None,
     };
     pic.install_canister(
@@ -107,6 +119,20 @@ fn install_ledger(pic: &PocketIc) {
     );
 }
 
+/// Installs the governance mock, which serves executed CreateSubnet proposals.
+fn install_governance(pic: &PocketIc) {
+    pic.create_canister_with_id(None, None, MAINNET_GOVERNANCE_CANISTER_ID)
+        .unwrap();
+    let governance_wasm =
+        fs::read(GOVERNANCE_WASM).expect("Get the Wasm dependencies with ./scripts/get_wasms.sh");
+    pic.install_canister(
+        MAINNET_GOVERNANCE_CANISTER_ID,
+        governance_wasm,
+        vec![],
+        None,
+    );
+}
+
 fn setup_helper(pic: PocketIc) -> PocketIc {
     pic.set_time(Time::from_nanos_since_unix_epoch(
         1_620_633_600 * 1_000_000_000,
@@ -118,7 +144,12 @@ fn setup_helper(pic: PocketIc) -> PocketIc {
     // Install subnet rental canister.
     let subnet_rental_canister = pic.create_canister_with_id(None, None, SRC_ID).unwrap();
     let src_wasm = fs::read(SRC_WASM).expect("Build the wasm with ./scripts/build.sh");
-    pic.install_canister(subnet_rental_canister, src_wasm, vec![], None);
+    pic.install_canister(
+        subnet_rental_canister,
+        src_wasm,
+        encode_one(None::<InitArgs>).unwrap(),
+        None,
+    );
     pic.add_cycles(subnet_rental_canister, INITIAL_SRC_CYCLES_BALANCE);
     pic
 }
@@ -167,18 +198,28 @@ fn get_todays_price(pic: &PocketIc) -> Tokens {
         .find(|id| *id == RentalConditionId::App13CH)
         .expect("App13CH condition missing");
     // user finds current price by consulting SRC
-    update::<Result<Tokens, String>>(pic, SRC_ID, None, "get_todays_price", rental_condition_id)
+    update::<Result<Tokens, PriceError>>(pic, SRC_ID, None, "get_todays_price", rental_condition_id)
         .unwrap()
         .unwrap()
 }
 
 /// Transfers `amount` to the SRC subaccount of `user_principal`.
 fn pay_src(pic: &PocketIc, user_principal: Principal, amount: Tokens) -> Tokens {
+    pay_src_for(pic, user_principal, user_principal, amount)
+}
+
+/// Pays from the payer's account into the beneficiary's SRC subaccount.
+fn pay_src_for(
+    pic: &PocketIc,
+    payer: Principal,
+    user_principal: Principal,
+    amount: Tokens,
+) -> Tokens {
     // user finds the correct subaccount via SRC
     let account_hex = update::<String>(
         pic,
         SRC_ID,
-        Some(user_principal),
+        Some(payer),
         "get_payment_account",
         user_principal,
     )
@@ -196,7 +237,7 @@ fn pay_src(pic: &PocketIc, user_principal: Principal, amount: Tokens) -> Tokens
     let _res = update::<TransferResult>(
         pic,
         MAINNET_LEDGER_CANISTER_ID,
-        Some(user_principal),
+        Some(payer),
         "transfer",
         transfer_args,
     )
@@ -209,7 +250,7 @@ fn set_xrc_exchange_rate_last_midnight(pic: &PocketIc, exchange_rate_xdr_per_icp
     let midnight = now - now % SECONDS_PER_DAY;
     update::<()>(
         pic,
-        get_exchange_rate_canister_id(),
+        MAINNET_EXCHANGE_RATE_CANISTER_ID,
         None,
         "set_exchange_rate_data",
         vec![(midnight, exchange_rate_xdr_per_icp)],
@@ -226,7 +267,7 @@ fn set_cmc_exchange_rate(pic: &PocketIc, exchange_rate_xdr_per_icp: u64) {
 
     update::<()>(
         pic,
-        get_exchange_rate_canister_id(),
+        MAINNET_EXCHANGE_RATE_CANISTER_ID,
         None,
         "set_exchange_rate_data",
         vec![(fetch_time, exchange_rate_xdr_per_icp)],
@@ -282,6 +323,7 @@ fn test_initial_proposal() {
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 999,
         proposal_creation_time_seconds: now,
+        price_quote_id: None,
     };
 
     // advance time by one day
@@ -352,7 +394,7 @@ fn test_initial_proposal() {
     assert_eq!(rental_request.initial_cost_icp, final_subnet_price);
 
     // get refund as anonymous principal (should fail)
-    let res = update::<Result<u64, String>>(&pic, SRC_ID, None, "refund", ());
+    let res = update::<Result<u64, RefundError>>(&pic, SRC_ID, None, "refund", ());
     assert!(res.unwrap().is_err());
 
     // transfer some more funds to the SRC subaccount
@@ -361,7 +403,7 @@ fn test_initial_proposal() {
 
     // get refund on behalf of the actual renter
     let balance_before_refund = check_balance(&pic, user_principal, DEFAULT_SUBACCOUNT);
-    let res = update::<Result<u64, String>>(&pic, SRC_ID, Some(user_principal), "refund", ());
+    let res = update::<Result<u64, RefundError>>(&pic, SRC_ID, Some(user_principal), "refund", ());
     assert!(res.unwrap().is_ok());
 
     let immediately_locked_amount = Tokens::from_e8s(final_subnet_price.e8s() / 10); // 10% of ICP are locked immediately
@@ -392,6 +434,394 @@ fn test_initial_proposal() {
     assert!(rental_requests.is_empty());
 }
 
+#[test]
+fn test_price_falls_back_to_cmc_rate() {
+    let pic = setup();
+
+    let cmc_exchange_rate = 3_497_900_000; // 1 ICP = 3.4979 XDR
+    set_cmc_exchange_rate(&pic, cmc_exchange_rate);
+
+    // a wildly different XRC rate that is backed by too few sources
+    set_xrc_exchange_rate_last_midnight(&pic, 1_000_000_000); // 1 ICP = 1 XDR
+    update::<()>(
+        &pic,
+        MAINNET_EXCHANGE_RATE_CANISTER_ID,
+        None,
+        "set_base_asset_num_received_rates",
+        1_u64,
+    )
+    .unwrap();
+
+    // the price is based on the CMC rate, which has a precision of 4 decimal places
+    let rental_condition = get_rental_condition(&pic, RentalConditionId::App13CH);
+    let needed_cycles =
+        rental_condition.daily_cost_cycles * rental_condition.initial_rental_period_days as u128;
+    let expected_price = Tokens::from_e8s((needed_cycles / 34_979) as u64);
+    assert_eq!(get_todays_price(&pic), expected_price);
+
+    // once the XRC is backed by enough sources again, its rate is used
+    update::<()>(
+        &pic,
+        MAINNET_EXCHANGE_RATE_CANISTER_ID,
+        None,
+        "set_base_asset_num_received_rates",
+        5_u64,
+    )
+    .unwrap();
+    let expected_price = Tokens::from_e8s((needed_cycles / 10_000) as u64);
+    assert_eq!(get_todays_price(&pic), expected_price);
+}
+
+#[test]
+fn test_exchange_rate_cache_override() {
+    let pic = setup();
+
+    set_xrc_exchange_rate_last_midnight(&pic, 5_000_000_000); // 1 ICP = 5 XDR
+    let price = get_todays_price(&pic);
+
+    let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+    let midnight = now - now % SECONDS_PER_DAY;
+    let cached_rates = query_multi_arg::<Vec<CachedRate>>(
+        &pic,
+        SRC_ID,
+        None,
+        "list_cached_rates",
+        (0_u64, u64::MAX),
+    );
+    assert_eq!(cached_rates.len(), 1);
+    let cached_rate = cached_rates.first().unwrap();
+    assert_eq!(cached_rate.time_secs, midnight);
+    assert_eq!(cached_rate.rate, 5_000_000_000);
+    assert_eq!(cached_rate.decimals, 9);
+    assert!(matches!(
+        cached_rate.provenance,
+        Some(RateProvenance::Xrc(_))
+    ));
+
+    let payload = OverrideExchangeRatePayload {
+        time_secs: midnight,
+        rate: 10_000_000_000, // 1 ICP = 10 XDR
+        decimals: 9,
+    };
+
+    // only governance may override a rate
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(USER_1),
+        "execute_override_exchange_rate",
+        payload.clone(),
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::UnauthorizedCaller)));
+
+    // rates are keyed by UTC midnight
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_override_exchange_rate",
+        OverrideExchangeRatePayload {
+            time_secs: midnight + 1,
+            ..payload.clone()
+        },
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::InvalidExchangeRate)));
+
+    // rates with more decimals than a price calculation can handle are rejected
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_override_exchange_rate",
+        OverrideExchangeRatePayload {
+            decimals: 20,
+            ..payload.clone()
+        },
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::InvalidExchangeRate)));
+
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_override_exchange_rate",
+        payload,
+    )
+    .unwrap();
+
+    // the price halves with the doubled rate
+    let new_price = get_todays_price(&pic);
+    assert_eq!(new_price.e8s(), price.e8s() / 2);
+
+    let cached_rates = query_multi_arg::<Vec<CachedRate>>(
+        &pic,
+        SRC_ID,
+        None,
+        "list_cached_rates",
+        (midnight, midnight),
+    );
+    assert_eq!(
+        cached_rates,
+        vec![CachedRate {
+            time_secs: midnight,
+            rate: 10_000_000_000,
+            decimals: 9,
+            provenance: Some(RateProvenance::Governance),
+        }]
+    );
+
+    // the override is recorded in the global history
+    let src_history = query_multi_arg::<EventPage>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_rental_conditions_history_page",
+        (None::<Option<u64>>,),
+    );
+    let condition_count = query::<Vec<(RentalConditionId, RentalConditions)>>(
+        &pic,
+        SRC_ID,
+        None,
+        "list_rental_conditions",
+        (),
+    )
+    .len();
+    assert_eq!(src_history.events.len(), condition_count + 1);
+}
+
+#[test]
+fn test_historical_prices() {
+    let pic = setup();
+
+    // three consecutive days with different exchange rates, each cached by pricing it that day
+    let rates = [5_000_000_000, 10_000_000_000, 12_503_823_284];
+    let first_day = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+    for (i, rate) in rates.iter().enumerate() {
+        if i > 0 {
+            pic.advance_time(Duration::from_secs(SECONDS_PER_DAY));
+        }
+        set_xrc_exchange_rate_last_midnight(&pic, *rate);
+        get_todays_price(&pic);
+    }
+    let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+
+    let prices = update_multi_arg::<Result<Vec<HistoricalPrice>, PriceError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_price_range",
+        (RentalConditionId::App13CH, first_day, now),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(prices.len(), 3);
+    let rental_condition = get_rental_condition(&pic, RentalConditionId::App13CH);
+    for (i, (price, rate)) in prices.iter().zip(rates).enumerate() {
+        assert_eq!(
+            price.time_secs,
+            first_day - first_day % SECONDS_PER_DAY + i as u64 * SECONDS_PER_DAY
+        );
+        assert_eq!(price.scaled_exchange_rate_xdr_per_icp, rate);
+        assert_eq!(price.decimals, 9);
+        assert_eq!(
+            price.cycles,
+            rental_condition.daily_cost_cycles
+                * rental_condition.initial_rental_period_days as u128
+        );
+    }
+    // ICP got more expensive, so the subnet got cheaper in ICP
+    assert!(prices[0].icp > prices[1].icp);
+    assert!(prices[1].icp > prices[2].icp);
+
+    // a single day is priced the same way, and today's price agrees
+    let price = update_multi_arg::<Result<HistoricalPrice, PriceError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_price_at",
+        (RentalConditionId::App13CH, first_day),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(price, prices[0]);
+    assert_eq!(get_todays_price(&pic), prices[2].icp);
+
+    // the future cannot be priced
+    let res = update_multi_arg::<Result<HistoricalPrice, PriceError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_price_at",
+        (RentalConditionId::App13CH, now + SECONDS_PER_DAY),
+    )
+    .unwrap();
+    assert_eq!(res, Err(PriceError::TimeInFuture));
+
+    // past days whose rate is not cached are only fetched from the XRC if the caller pays
+    let res = update_multi_arg::<Result<HistoricalPrice, PriceError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_price_at",
+        (RentalConditionId::App13CH, first_day - SECONDS_PER_DAY),
+    )
+    .unwrap();
+    assert_eq!(
+        res,
+        Err(PriceError::InsufficientCycles {
+            need: 1_000_000_000,
+            attached: 0
+        })
+    );
+    let res = update_multi_arg::<Result<Vec<HistoricalPrice>, PriceError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_price_range",
+        (
+            RentalConditionId::App13CH,
+            first_day - 2 * SECONDS_PER_DAY,
+            now,
+        ),
+    )
+    .unwrap();
+    assert_eq!(
+        res,
+        Err(PriceError::InsufficientCycles {
+            need: 2_000_000_000,
+            attached: 0
+        })
+    );
+
+    // ranges are bounded
+    let res = update_multi_arg::<Result<Vec<HistoricalPrice>, PriceError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_price_range",
+        (RentalConditionId::App13CH, now - 31 * SECONDS_PER_DAY, now),
+    )
+    .unwrap();
+    assert!(matches!(res, Err(PriceError::InvalidRange { .. })));
+}
+
+#[test]
+fn test_binding_price_quote() {
+    let pic = setup();
+
+    set_xrc_exchange_rate_last_midnight(&pic, 5_000_000_000); // 1 ICP = 5 XDR
+    let price_quote = update::<Result<PriceQuote, CreatePriceQuoteError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_1),
+        "create_price_quote",
+        RentalConditionId::App13CH,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(price_quote.user, USER_1);
+    assert_eq!(price_quote.icp, get_todays_price(&pic));
+    assert_eq!(
+        query::<Option<PriceQuote>>(&pic, SRC_ID, None, "get_price_quote", price_quote.id),
+        Some(price_quote.clone())
+    );
+
+    // the anonymous principal cannot create quotes
+    let res = update::<Result<PriceQuote, CreatePriceQuoteError>>(
+        &pic,
+        SRC_ID,
+        Some(Principal::anonymous()),
+        "create_price_quote",
+        RentalConditionId::App13CH,
+    )
+    .unwrap();
+    assert_eq!(res, Err(CreatePriceQuoteError::AnonymousCaller));
+
+    // the proposal is created while the quote is valid, but the price in ICP rises before execution
+    let proposal_creation_time_seconds =
+        pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+    pic.advance_time(Duration::from_secs(SECONDS_PER_DAY));
+    set_xrc_exchange_rate_last_midnight(&pic, 2_500_000_000); // 1 ICP = 2.5 XDR
+    assert!(get_todays_price(&pic) > price_quote.icp);
+    pay_src(&pic, USER_1, price_quote.icp);
+
+    // a quote of another user cannot be referenced
+    let payload = SubnetRentalProposalPayload {
+        user: USER_2,
+        rental_condition_id: RentalConditionId::App13CH,
+        proposal_id: 998,
+        proposal_creation_time_seconds,
+        price_quote_id: Some(price_quote.id),
+    };
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_rental_request_proposal",
+        payload,
+    )
+    .unwrap_err();
+
+    // the quoted amount is charged
+    let payload = SubnetRentalProposalPayload {
+        user: USER_1,
+        rental_condition_id: RentalConditionId::App13CH,
+        proposal_id: 999,
+        proposal_creation_time_seconds,
+        price_quote_id: Some(price_quote.id),
+    };
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_rental_request_proposal",
+        payload,
+    )
+    .unwrap();
+    let rental_requests =
+        query::<Vec<RentalRequest>>(&pic, SRC_ID, None, "list_rental_requests", ());
+    assert_eq!(rental_requests.len(), 1);
+    assert_eq!(rental_requests[0].initial_cost_icp, price_quote.icp);
+
+    // a quote can only be used once
+    assert_eq!(
+        query::<Option<PriceQuote>>(&pic, SRC_ID, None, "get_price_quote", price_quote.id),
+        None
+    );
+
+    // a proposal created after the quote expired is rejected
+    let price_quote = update::<Result<PriceQuote, CreatePriceQuoteError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_2),
+        "create_price_quote",
+        RentalConditionId::App13CH,
+    )
+    .unwrap()
+    .unwrap();
+    let payload = SubnetRentalProposalPayload {
+        user: USER_2,
+        rental_condition_id: RentalConditionId::App13CH,
+        proposal_id: 1000,
+        proposal_creation_time_seconds: price_quote.expiry_time_nanos / NANOS_PER_SECOND,
+        price_quote_id: Some(price_quote.id),
+    };
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_rental_request_proposal",
+        payload,
+    )
+    .unwrap_err();
+}
+
 #[test]
 fn test_create_rental_agreement() {
     let pic = setup();
@@ -411,6 +841,7 @@ fn test_create_rental_agreement() {
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 136408,
         proposal_creation_time_seconds: now,
+        price_quote_id: None,
     };
 
     // 1 day passes ...
@@ -614,7 +1045,7 @@ fn test_create_rental_agreement() {
 
     // try a refund, should give back the 100 ICP extra - fee, and leave the agreement in place
     let user_icp_balance_before_refund = check_balance(&pic, USER_1, DEFAULT_SUBACCOUNT);
-    update::<Result<u64, String>>(&pic, SRC_ID, Some(USER_1), "refund", ())
+    update::<Result<u64, RefundError>>(&pic, SRC_ID, Some(USER_1), "refund", ())
         .unwrap()
         .unwrap();
     let user_icp_balance_after_refund = check_balance(&pic, USER_1, DEFAULT_SUBACCOUNT);
@@ -695,7 +1126,7 @@ fn test_create_rental_agreement() {
     let user_balance_before_topup = check_balance(&pic, USER_1, DEFAULT_SUBACCOUNT);
 
     // get estimate for topup
-    let estimate = update_multi_arg::<Result<TopUpSummary, String>>(
+    let estimate = update_multi_arg::<Result<TopUpSummary, TopUpError>>(
         &pic,
         SRC_ID,
         None,
@@ -716,7 +1147,7 @@ fn test_create_rental_agreement() {
     let subnet_events_before = subnet_history_before.events.len();
 
     // try to do topup with insufficient funds
-    let res = update::<Result<TopUpSummary, String>>(
+    let res = update::<Result<TopUpSummary, TopUpError>>(
         &pic,
         SRC_ID,
         None,
@@ -726,7 +1157,10 @@ fn test_create_rental_agreement() {
     .unwrap();
 
     // the conversion should fail as the user does not have any ICP
-    assert!(res.unwrap_err().contains("insufficient funds"));
+    assert!(matches!(
+        res.unwrap_err(),
+        TopUpError::InsufficientFunds { .. }
+    ));
 
     // the failed top-up should persist a SubnetTopUpFailed event on the subnet
     let subnet_history_after_failure = query_multi_arg::<EventPage>(
@@ -745,7 +1179,7 @@ fn test_create_rental_agreement() {
     let topup = Tokens::from_e8s(1_000 * E8S);
     pay_src(&pic, USER_1, topup);
 
-    let actual_topup = update::<Result<TopUpSummary, String>>(
+    let actual_topup = update::<Result<TopUpSummary, TopUpError>>(
         &pic,
         SRC_ID,
         None,
@@ -795,6 +1229,25 @@ fn test_create_rental_agreement() {
 
     assert_eq!(actual_topup.cycles_added, estimate.cycles_added);
     assert_eq!(actual_topup.days_added, estimate.days_added);
+    assert_eq!(actual_topup.paid_until_nanos, estimate.paid_until_nanos);
+    assert!(estimate.min_cycles_added < actual_topup.cycles_added);
+    assert!(estimate.max_cycles_added > actual_topup.cycles_added);
+
+    // the summary shows how the top-up was computed
+    assert_eq!(actual_topup.fees, DEFAULT_FEE + DEFAULT_FEE);
+    assert_eq!(
+        actual_topup.icp_converted,
+        topup - DEFAULT_FEE - DEFAULT_FEE
+    );
+    assert_eq!(
+        actual_topup.scaled_exchange_rate_xdr_per_icp,
+        exchange_rate_for_topup
+    );
+    assert_eq!(actual_topup.decimals, 9);
+    assert_eq!(
+        actual_topup.paid_until_nanos,
+        get_rental_agreement(&pic, SUBNET_FOR_RENT).paid_until_nanos
+    );
 
     // check status of subnet rental
     let subnet_status = check_subnet_status(&pic);
@@ -876,6 +1329,7 @@ fn test_failed_initial_proposal() {
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 999,
         proposal_creation_time_seconds: now,
+        price_quote_id: None,
     };
     // run proposal
     update::<()>(
@@ -923,6 +1377,7 @@ fn test_duplicate_request_fails() {
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 999,
         proposal_creation_time_seconds: now,
+        price_quote_id: None,
     };
     // run proposal
     update::<()>(
@@ -942,48 +1397,310 @@ fn test_duplicate_request_fails() {
         "execute_rental_request_proposal",
         payload,
     );
-    assert!(res.unwrap_err().contains(&format!(
-        "{:?}",
+    assert!(res.unwrap_err().contains(&proposal_error(
         ExecuteProposalError::UserAlreadyRequestingSubnetRental
     )));
 }
 
 #[test]
-fn test_locking() {
+fn test_reject_rental_request() {
     let pic = setup();
     let user_principal = USER_1;
+    let initial_user_balance = check_balance(&pic, user_principal, DEFAULT_SUBACCOUNT);
+
     // set an exchange rate for the current time on the XRC mock
     set_xrc_exchange_rate_last_midnight(&pic, 12_503_823_284); // 1 ICP = 12.503823284 XDR
 
-    // transfer the initial payment
     let initial_payment = get_todays_price(&pic);
-    let payment_on_top = Tokens::from_e8s(100 * E8S); // Users might send more than is actually needed
-    pay_src(&pic, user_principal, initial_payment + payment_on_top);
+    pay_src(&pic, user_principal, initial_payment);
 
-    // user creates proposal
     let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
     let payload = SubnetRentalProposalPayload {
         user: user_principal,
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 999,
         proposal_creation_time_seconds: now,
+        price_quote_id: None,
     };
-
-    // run proposal
     update::<()>(
         &pic,
         SRC_ID,
         Some(MAINNET_GOVERNANCE_CANISTER_ID),
         "execute_rental_request_proposal",
-        payload.clone(),
+        payload,
     )
     .unwrap();
 
-    // check that 10% of the initial amount has been locked
-    let rental_request =
-        query::<Vec<RentalRequest>>(&pic, SRC_ID, None, "list_rental_requests", ())
-            .pop()
-            .unwrap();
+    let reject_payload = RejectRentalRequestPayload {
+        user: user_principal,
+        proposal_id: 1000,
+    };
+
+    // only governance may reject a rental request
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(user_principal),
+        "execute_reject_rental_request",
+        reject_payload.clone(),
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::UnauthorizedCaller)));
+
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_reject_rental_request",
+        reject_payload.clone(),
+    )
+    .unwrap();
+
+    // the rental request is gone
+    let rental_requests =
+        query::<Vec<RentalRequest>>(&pic, SRC_ID, None, "list_rental_requests", ());
+    assert!(rental_requests.is_empty());
+
+    // everything but the locked 10% has been refunded
+    let locked_amount = Tokens::from_e8s(initial_payment.e8s() / 10);
+    assert_eq!(
+        check_balance(&pic, user_principal, DEFAULT_SUBACCOUNT),
+        initial_user_balance
+            - locked_amount
+            - DEFAULT_FEE // initial transfer to SRC
+            - DEFAULT_FEE // refund
+    );
+    assert_eq!(
+        check_balance(&pic, SRC_ID, Subaccount::from(user_principal)),
+        Tokens::from_e8s(0)
+    );
+
+    // 1 RentalRequestCreated + 1 TransferSuccess (initial lock) +
+    // 1 TransferSuccess (refund) + 1 RentalRequestRejected
+    let user_history = query_multi_arg::<EventPage>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_history_page",
+        (user_principal, None::<Option<u64>>),
+    );
+    assert_eq!(user_history.events.len(), 4);
+
+    // a second rejection fails, as there is no rental request anymore
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_reject_rental_request",
+        reject_payload,
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::RentalRequestNotFound)));
+}
+
+#[test]
+fn test_configurable_canister_ids() {
+    let pic = setup();
+
+    // mainnet canister ids by default
+    let canister_ids = query::<CanisterIds>(&pic, SRC_ID, None, "get_canister_ids", ());
+    assert_eq!(canister_ids, CanisterIds::default());
+    assert_eq!(canister_ids.ledger, MAINNET_LEDGER_CANISTER_ID);
+    assert_eq!(canister_ids.xrc, MAINNET_EXCHANGE_RATE_CANISTER_ID);
+
+    // replace the governance canister
+    let src_wasm = fs::read(SRC_WASM).expect("Build the wasm with ./scripts/build.sh");
+    let init_args = InitArgs {
+        governance_canister_id: Some(USER_2),
+        ..Default::default()
+    };
+    pic.upgrade_canister(
+        SRC_ID,
+        src_wasm.clone(),
+        encode_one(Some(init_args)).unwrap(),
+        None,
+    )
+    .unwrap();
+    let canister_ids = query::<CanisterIds>(&pic, SRC_ID, None, "get_canister_ids", ());
+    assert_eq!(
+        canister_ids,
+        CanisterIds {
+            governance: USER_2,
+            ..CanisterIds::default()
+        }
+    );
+
+    // the canister ids survive upgrades without arguments
+    pic.upgrade_canister(
+        SRC_ID,
+        src_wasm,
+        encode_one(None::<InitArgs>).unwrap(),
+        None,
+    )
+    .unwrap();
+    let canister_ids = query::<CanisterIds>(&pic, SRC_ID, None, "get_canister_ids", ());
+    assert_eq!(canister_ids.governance, USER_2);
+
+    // only the configured governance canister may execute proposals
+    let midnight = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND / SECONDS_PER_DAY
+        * SECONDS_PER_DAY;
+    let payload = OverrideExchangeRatePayload {
+        time_secs: midnight,
+        rate: 10_000_000_000,
+        decimals: 9,
+    };
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_override_exchange_rate",
+        payload.clone(),
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::UnauthorizedCaller)));
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(USER_2),
+        "execute_override_exchange_rate",
+        payload,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_governance_polling_creates_rental_agreement() {
+    let pic = setup();
+    install_governance(&pic);
+
+    // enable polling
+    let src_wasm = fs::read(SRC_WASM).expect("Build the wasm with ./scripts/build.sh");
+    let init_args = InitArgs {
+        poll_governance: Some(true),
+        ..Default::default()
+    };
+    pic.upgrade_canister(SRC_ID, src_wasm, encode_one(Some(init_args)).unwrap(), None)
+        .unwrap();
+
+    // set an exchange rate for the current time on the XRC mock
+    set_xrc_exchange_rate_last_midnight(&pic, 3_593_382_591); // 1 ICP = 3.593382591 XDR
+    let initial_payment = get_todays_price(&pic);
+    pay_src(&pic, USER_1, initial_payment);
+
+    let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+    let payload = SubnetRentalProposalPayload {
+        user: USER_1,
+        rental_condition_id: RentalConditionId::App13CH,
+        proposal_id: 136408,
+        proposal_creation_time_seconds: now,
+        price_quote_id: None,
+    };
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_rental_request_proposal",
+        payload,
+    )
+    .unwrap();
+
+    // an unrelated CreateSubnet proposal must not be picked up
+    update_multi_arg::<()>(
+        &pic,
+        MAINNET_GOVERNANCE_CANISTER_ID,
+        None,
+        "add_executed_create_subnet_proposal",
+        (137000_u64, Some(Principal::anonymous()), Some(1_u64)),
+    )
+    .unwrap();
+    pic.advance_time(Duration::from_secs(10 * 60));
+    for _ in 0..3 {
+        pic.tick();
+    }
+    let rental_agreements =
+        query::<Vec<RentalAgreement>>(&pic, SRC_ID, None, "list_rental_agreements", ());
+    assert!(rental_agreements.is_empty());
+
+    // the CreateSubnet proposal referencing the rental request is executed
+    update_multi_arg::<()>(
+        &pic,
+        MAINNET_GOVERNANCE_CANISTER_ID,
+        None,
+        "add_executed_create_subnet_proposal",
+        (137322_u64, Some(SUBNET_FOR_RENT), Some(136408_u64)),
+    )
+    .unwrap();
+    pic.advance_time(Duration::from_secs(10 * 60));
+    for _ in 0..3 {
+        pic.tick();
+    }
+
+    let rental_requests =
+        query::<Vec<RentalRequest>>(&pic, SRC_ID, None, "list_rental_requests", ());
+    assert!(rental_requests.is_empty());
+    let rental_agreement = get_rental_agreement(&pic, SUBNET_FOR_RENT);
+    assert_eq!(rental_agreement.user, USER_1);
+    assert_eq!(rental_agreement.rental_request_proposal_id, 136408);
+    assert_eq!(rental_agreement.subnet_creation_proposal_id, Some(137322));
+
+    // a late call by governance fails, as the rental request is already fulfilled
+    let payload = CreateRentalAgreementPayload {
+        user: USER_1,
+        subnet_id: SUBNET_FOR_RENT,
+        proposal_id: 137322,
+    };
+    let res = update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_create_rental_agreement",
+        payload,
+    );
+    assert!(res
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::RentalRequestNotFound)));
+}
+
+#[test]
+fn test_locking() {
+    let pic = setup();
+    let user_principal = USER_1;
+    // set an exchange rate for the current time on the XRC mock
+    set_xrc_exchange_rate_last_midnight(&pic, 12_503_823_284); // 1 ICP = 12.503823284 XDR
+
+    // transfer the initial payment
+    let initial_payment = get_todays_price(&pic);
+    let payment_on_top = Tokens::from_e8s(100 * E8S); // Users might send more than is actually needed
+    pay_src(&pic, user_principal, initial_payment + payment_on_top);
+
+    // user creates proposal
+    let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+    let payload = SubnetRentalProposalPayload {
+        user: user_principal,
+        rental_condition_id: RentalConditionId::App13CH,
+        proposal_id: 999,
+        proposal_creation_time_seconds: now,
+        price_quote_id: None,
+    };
+
+    // run proposal
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_rental_request_proposal",
+        payload.clone(),
+    )
+    .unwrap();
+
+    // check that 10% of the initial amount has been locked
+    let rental_request =
+        query::<Vec<RentalRequest>>(&pic, SRC_ID, None, "list_rental_requests", ())
+            .pop()
+            .unwrap();
 
     let should_lock_amount_icp = Tokens::from_e8s(initial_payment.e8s() / 10);
 
@@ -1052,6 +1769,7 @@ fn test_accept_rental_agreement_cannot_be_called_by_non_governance() {
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 999,
         proposal_creation_time_seconds: 999,
+        price_quote_id: None,
     };
     let res = update::<()>(
         &pic,
@@ -1062,7 +1780,7 @@ fn test_accept_rental_agreement_cannot_be_called_by_non_governance() {
     );
     assert!(res
         .unwrap_err()
-        .contains(&format!("{:?}", ExecuteProposalError::UnauthorizedCaller)));
+        .contains(&proposal_error(ExecuteProposalError::UnauthorizedCaller)));
 }
 
 #[test]
@@ -1162,6 +1880,11 @@ fn can_update_subnet_admins_when_renting_subnet() {
         payload,
     );
     assert_eq!(res.unwrap(), UpdateSubnetAdminsResult::Ok(candid::Reserved));
+    let admins =
+        query::<Option<Vec<Principal>>>(&pic, SRC_ID, None, "get_subnet_admins", subnet_id);
+    let mut expected = vec![renting_principal, USER_2];
+    expected.sort();
+    assert_eq!(admins, Some(expected));
 
     let payload = UpdateSubnetAdminsPayload {
         subnet_id,
@@ -1170,11 +1893,364 @@ fn can_update_subnet_admins_when_renting_subnet() {
     let res = update::<UpdateSubnetAdminsResult>(
         &pic,
         SRC_ID,
-        Some(renting_principal),
-        "update_subnet_admins",
-        payload,
-    );
-    assert_eq!(res.unwrap(), UpdateSubnetAdminsResult::Ok(candid::Reserved));
+        Some(renting_principal),
+        "update_subnet_admins",
+        payload,
+    );
+    assert_eq!(res.unwrap(), UpdateSubnetAdminsResult::Ok(candid::Reserved));
+    let admins =
+        query::<Option<Vec<Principal>>>(&pic, SRC_ID, None, "get_subnet_admins", subnet_id);
+    assert_eq!(admins, Some(vec![renting_principal]));
+
+    // Subnets that are not rented have no admins tracked by the SRC.
+    let admins = query::<Option<Vec<Principal>>>(&pic, SRC_ID, None, "get_subnet_admins", USER_2);
+    assert_eq!(admins, None);
+}
+
+#[test]
+fn subnet_admin_limits_are_enforced_on_resulting_set() {
+    let pic = setup_with_rented_subnet();
+
+    // There should only be one application subnet that has a free
+    // cost schedule returned by `setup_with_rented_subnet()`.
+    let subnet_id = *pic.topology().get_app_subnets().first().unwrap();
+
+    let renting_principal = USER_1;
+    rent_subnet_helper(&pic, subnet_id, renting_principal);
+
+    let update_admins = |principals: Vec<Principal>| {
+        let payload = UpdateSubnetAdminsPayload {
+            subnet_id,
+            operation_type: Some(OperationType::Add(BoundedVec::new(principals))),
+        };
+        update::<UpdateSubnetAdminsResult>(
+            &pic,
+            SRC_ID,
+            Some(renting_principal),
+            "update_subnet_admins",
+            payload,
+        )
+        .unwrap()
+    };
+    let admin = |i: u64| Principal::from_slice(&i.to_be_bytes());
+
+    assert_eq!(
+        update_admins(vec![USER_2, USER_2]),
+        UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::InvalidPrincipal(USER_2)))
+    );
+    assert_eq!(
+        update_admins(vec![Principal::anonymous()]),
+        UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::InvalidPrincipal(
+            Principal::anonymous()
+        )))
+    );
+
+    // Each payload is within the bound, but the second one would exceed it in total.
+    assert_eq!(
+        update_admins((0..6).map(admin).collect()),
+        UpdateSubnetAdminsResult::Ok(candid::Reserved)
+    );
+    assert_eq!(
+        update_admins((6..11).map(admin).collect()),
+        UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::TooManyAdmins {
+            max: 10,
+            resulting: 11
+        }))
+    );
+    let admins =
+        query::<Option<Vec<Principal>>>(&pic, SRC_ID, None, "get_subnet_admins", subnet_id);
+    assert_eq!(admins.unwrap().len(), 6);
+}
+
+#[test]
+fn test_delegated_agreement_roles() {
+    let pic = setup_with_rented_subnet();
+
+    // There should only be one application subnet that has a free
+    // cost schedule returned by `setup_with_rented_subnet()`.
+    let subnet_id = *pic.topology().get_app_subnets().first().unwrap();
+    rent_subnet_helper(&pic, subnet_id, USER_1);
+
+    let change_roles = |method: &str, sender: Principal, roles: Vec<AgreementRole>| {
+        update::<Result<(), AgreementRolesError>>(
+            &pic,
+            SRC_ID,
+            Some(sender),
+            method,
+            AgreementRolesPayload {
+                subnet_id,
+                principal: USER_2,
+                roles,
+            },
+        )
+        .unwrap()
+    };
+    let update_admins = |sender: Principal| {
+        update::<UpdateSubnetAdminsResult>(
+            &pic,
+            SRC_ID,
+            Some(sender),
+            "update_subnet_admins",
+            UpdateSubnetAdminsPayload {
+                subnet_id,
+                operation_type: Some(OperationType::Add(BoundedVec::new(vec![USER_2]))),
+            },
+        )
+        .unwrap()
+    };
+
+    // only the renter can delegate
+    assert_eq!(
+        change_roles(
+            "grant_agreement_roles",
+            USER_2,
+            vec![AgreementRole::ManageSubnetAdmins]
+        ),
+        Err(AgreementRolesError::CallerNotRentingSubnet)
+    );
+    assert_eq!(
+        update_admins(USER_2),
+        UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::CallerNotRentingSubnet(
+            candid::Reserved
+        )))
+    );
+
+    assert_eq!(
+        change_roles(
+            "grant_agreement_roles",
+            USER_1,
+            vec![AgreementRole::ManageSubnetAdmins]
+        ),
+        Ok(())
+    );
+    assert_eq!(
+        query::<Vec<(Principal, Vec<AgreementRole>)>>(
+            &pic,
+            SRC_ID,
+            None,
+            "list_agreement_roles",
+            subnet_id
+        ),
+        vec![(USER_2, vec![AgreementRole::ManageSubnetAdmins])]
+    );
+    assert_eq!(
+        update_admins(USER_2),
+        UpdateSubnetAdminsResult::Ok(candid::Reserved)
+    );
+
+    // the delegate tops up from their own subaccount once they hold the role
+    set_cmc_exchange_rate(&pic, 4_103_000_000); // 1 ICP = 4.103 XDR
+                                                // USER_2 has hardly any ICP of its own
+    pay_src_for(&pic, USER_1, USER_2, Tokens::from_e8s(10 * E8S));
+    let res = update::<Result<TopUpSummary, TopUpError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_2),
+        "top_up_subnet_as_delegate",
+        subnet_id,
+    )
+    .unwrap();
+    assert_eq!(res, Err(TopUpError::Unauthorized));
+    assert_eq!(
+        change_roles("grant_agreement_roles", USER_1, vec![AgreementRole::TopUp]),
+        Ok(())
+    );
+    let paid_until_nanos = get_rental_agreement(&pic, subnet_id).paid_until_nanos;
+    let summary = update::<Result<TopUpSummary, TopUpError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_2),
+        "top_up_subnet_as_delegate",
+        subnet_id,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(summary.cycles_added > 0);
+    assert!(get_rental_agreement(&pic, subnet_id).paid_until_nanos > paid_until_nanos);
+
+    assert_eq!(
+        change_roles(
+            "revoke_agreement_roles",
+            USER_1,
+            vec![AgreementRole::ManageSubnetAdmins, AgreementRole::TopUp]
+        ),
+        Ok(())
+    );
+    assert!(query::<Vec<(Principal, Vec<AgreementRole>)>>(
+        &pic,
+        SRC_ID,
+        None,
+        "list_agreement_roles",
+        subnet_id
+    )
+    .is_empty());
+    assert_eq!(
+        update_admins(USER_2),
+        UpdateSubnetAdminsResult::Err(Some(UpdateSubnetAdminsError::CallerNotRentingSubnet(
+            candid::Reserved
+        )))
+    );
+}
+
+#[test]
+fn test_agreement_transfer() {
+    let pic = setup_with_rented_subnet();
+
+    // There should only be one application subnet that has a free
+    // cost schedule returned by `setup_with_rented_subnet()`.
+    let subnet_id = *pic.topology().get_app_subnets().first().unwrap();
+    rent_subnet_helper(&pic, subnet_id, USER_1);
+
+    // require governance approval for transfers
+    let src_wasm = fs::read(SRC_WASM).expect("Build the wasm with ./scripts/build.sh");
+    let init_args = InitArgs {
+        agreement_transfers_require_governance: Some(true),
+        ..Default::default()
+    };
+    pic.upgrade_canister(SRC_ID, src_wasm, encode_one(Some(init_args)).unwrap(), None)
+        .unwrap();
+
+    let accept = |sender: Principal| {
+        update::<Result<(), AgreementTransferError>>(
+            &pic,
+            SRC_ID,
+            Some(sender),
+            "accept_agreement_transfer",
+            subnet_id,
+        )
+        .unwrap()
+    };
+    let propose = |sender: Principal, new_user: Principal| {
+        update::<Result<(), AgreementTransferError>>(
+            &pic,
+            SRC_ID,
+            Some(sender),
+            "propose_agreement_transfer",
+            ProposeAgreementTransferPayload {
+                subnet_id,
+                new_user,
+            },
+        )
+        .unwrap()
+    };
+
+    assert_eq!(accept(USER_2), Err(AgreementTransferError::NotFound));
+    assert_eq!(
+        propose(USER_2, USER_2),
+        Err(AgreementTransferError::CallerNotRentingSubnet)
+    );
+    assert_eq!(
+        propose(USER_1, USER_1),
+        Err(AgreementTransferError::InvalidPrincipal)
+    );
+    assert_eq!(propose(USER_1, USER_2), Ok(()));
+    let pending_transfer = query::<Option<PendingAgreementTransfer>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_pending_agreement_transfer",
+        subnet_id,
+    )
+    .unwrap();
+    assert_eq!(pending_transfer.user, USER_1);
+    assert_eq!(pending_transfer.new_user, USER_2);
+
+    assert_eq!(
+        accept(USER_1),
+        Err(AgreementTransferError::UnauthorizedCaller)
+    );
+    assert_eq!(
+        accept(USER_2),
+        Err(AgreementTransferError::NotApprovedByGovernance)
+    );
+    update::<()>(
+        &pic,
+        SRC_ID,
+        Some(MAINNET_GOVERNANCE_CANISTER_ID),
+        "execute_approve_agreement_transfer",
+        ApproveAgreementTransferPayload {
+            subnet_id,
+            new_user: USER_2,
+        },
+    )
+    .unwrap();
+
+    // the old renter's admins do not stay on
+    assert_eq!(
+        update::<UpdateSubnetAdminsResult>(
+            &pic,
+            SRC_ID,
+            Some(USER_1),
+            "update_subnet_admins",
+            UpdateSubnetAdminsPayload {
+                subnet_id,
+                operation_type: Some(OperationType::Add(BoundedVec::new(vec![USER_1]))),
+            },
+        )
+        .unwrap(),
+        UpdateSubnetAdminsResult::Ok(candid::Reserved)
+    );
+
+    // the remaining balance of the old renter moves along
+    pay_src(&pic, USER_1, Tokens::from_e8s(5 * E8S));
+    let old_balance = check_balance(&pic, SRC_ID, Subaccount::from(USER_1));
+    let new_balance = check_balance(&pic, SRC_ID, Subaccount::from(USER_2));
+    assert_eq!(accept(USER_2), Ok(()));
+
+    assert_eq!(get_rental_agreement(&pic, subnet_id).user, USER_2);
+    assert_eq!(
+        check_balance(&pic, SRC_ID, Subaccount::from(USER_1)),
+        Tokens::from_e8s(0)
+    );
+    assert_eq!(
+        check_balance(&pic, SRC_ID, Subaccount::from(USER_2)),
+        new_balance + old_balance - DEFAULT_FEE
+    );
+    let cmc_whitelisted_subnets = query::<PrincipalsAuthorizedToCreateCanistersToSubnetsResponse>(
+        &pic,
+        MAINNET_CYCLES_MINTING_CANISTER_ID,
+        None,
+        "get_principals_authorized_to_create_canisters_to_subnets",
+        (),
+    );
+    assert_eq!(
+        cmc_whitelisted_subnets.data,
+        vec![(USER_2, vec![subnet_id])]
+    );
+    assert!(query::<Option<PendingAgreementTransfer>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_pending_agreement_transfer",
+        subnet_id,
+    )
+    .is_none());
+
+    assert_eq!(
+        query::<Option<Vec<Principal>>>(&pic, SRC_ID, None, "get_subnet_admins", subnet_id),
+        Some(vec![])
+    );
+
+    // the new renter is in charge now
+    assert_eq!(
+        propose(USER_1, USER_1),
+        Err(AgreementTransferError::CallerNotRentingSubnet)
+    );
+
+    // a proposed transfer that is not accepted in time expires
+    assert_eq!(propose(USER_2, USER_1), Ok(()));
+    pic.advance_time(Duration::from_secs(8 * SECONDS_PER_DAY));
+    pic.tick();
+    assert!(query::<Option<PendingAgreementTransfer>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_pending_agreement_transfer",
+        subnet_id,
+    )
+    .is_none());
+    assert_eq!(accept(USER_1), Err(AgreementTransferError::Expired));
+    assert_eq!(accept(USER_1), Err(AgreementTransferError::NotFound));
 }
 
 #[test]
@@ -1300,7 +2376,7 @@ fn test_top_up_estimate_round_trip_consistency() {
     let price_for_180_days = get_todays_price(&pic);
 
     // Now ask: how many days would that ICP amount buy?
-    let estimate = update_multi_arg::<Result<TopUpSummary, String>>(
+    let estimate = update_multi_arg::<Result<TopUpSummary, TopUpError>>(
         &pic,
         SRC_ID,
         None,
@@ -1310,12 +2386,495 @@ fn test_top_up_estimate_round_trip_consistency() {
     .unwrap()
     .unwrap();
 
-    // The estimate should give back exactly 180 days (the initial rental period).
+    // The fees leave the estimate just short of the initial rental period of 180 days,
+    // which is well within the bounds of the estimate.
     assert_eq!(
-        estimate.days_added, 180,
-        "Expected 180 days but got {} days for {} ICP",
+        estimate.days_added, 179,
+        "Expected 179 days but got {} days for {} ICP",
         estimate.days_added, price_for_180_days,
     );
+    let paid_until_nanos = get_rental_agreement(&pic, SUBNET_FOR_RENT).paid_until_nanos;
+    let after_180_days = paid_until_nanos + 180 * SECONDS_PER_DAY * NANOS_PER_SECOND;
+    assert!(estimate.paid_until_nanos < after_180_days);
+    assert!(estimate.max_paid_until_nanos > after_180_days);
+}
+
+#[test]
+fn test_top_up_for_days() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+
+    let exchange_rate = 4_103_000_000; // 1 ICP = 4.103 XDR
+    set_cmc_exchange_rate(&pic, exchange_rate);
+    set_xrc_exchange_rate_last_midnight(&pic, exchange_rate);
+
+    let rental_condition = get_rental_condition(&pic, RentalConditionId::App13CH);
+    let needed_cycles = 90 * rental_condition.daily_cost_cycles;
+    // Round up, so that the cycles cover at least 90 days.
+    let needed_icp =
+        Tokens::from_e8s((needed_cycles * 100_000).div_ceil(exchange_rate as u128) as u64)
+            + DEFAULT_FEE;
+
+    // a short balance fails early with the exact amount missing
+    let balance = check_balance(&pic, SRC_ID, Subaccount::from(USER_1));
+    let missing = needed_icp - balance;
+    let err = update_multi_arg::<Result<TopUpSummary, TopUpError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "top_up_subnet_for_days",
+        (SUBNET_FOR_RENT, 90_u64),
+    )
+    .unwrap()
+    .unwrap_err();
+    assert_eq!(
+        err,
+        TopUpError::InsufficientFunds {
+            have: balance,
+            need: needed_icp,
+        }
+    );
+
+    // only the ICP for 90 days is converted, the surplus stays in the subaccount
+    let surplus = Tokens::from_e8s(50 * E8S);
+    pay_src(&pic, USER_1, missing + surplus);
+    let paid_until_before = get_rental_agreement(&pic, SUBNET_FOR_RENT).paid_until_nanos;
+    let summary = update_multi_arg::<Result<TopUpSummary, TopUpError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "top_up_subnet_for_days",
+        (SUBNET_FOR_RENT, 90_u64),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(summary.days_added, 90);
+    assert_eq!(summary.fees, DEFAULT_FEE);
+    assert_eq!(summary.icp_converted, needed_icp - DEFAULT_FEE);
+    assert!(summary.cycles_added >= needed_cycles);
+    assert_eq!(
+        check_balance(&pic, SRC_ID, Subaccount::from(USER_1)),
+        surplus
+    );
+    let agreement = get_rental_agreement(&pic, SUBNET_FOR_RENT);
+    assert_eq!(agreement.paid_until_nanos, summary.paid_until_nanos);
+    assert!(
+        agreement.paid_until_nanos >= paid_until_before + 90 * SECONDS_PER_DAY * NANOS_PER_SECOND
+    );
+}
+
+#[test]
+fn test_billing_records() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+    let start = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+
+    // let the burn timer run across two midnights
+    for _ in 0..3 {
+        pic.advance_time(Duration::from_secs(SECONDS_PER_DAY));
+        for _ in 0..3 {
+            pic.tick();
+        }
+    }
+    let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+
+    let records = query_multi_arg::<Vec<BillingRecord>>(
+        &pic,
+        SRC_ID,
+        None,
+        "list_billing_records",
+        (SUBNET_FOR_RENT, start - SECONDS_PER_DAY, now),
+    );
+    assert!(records.len() >= 3);
+    let rental_condition = get_rental_condition(&pic, RentalConditionId::App13CH);
+    for window in records.windows(2) {
+        assert!(window[0].day_start_secs < window[1].day_start_secs);
+    }
+    for record in &records {
+        assert_eq!(record.subnet_id, SUBNET_FOR_RENT);
+        assert_eq!(record.day_start_secs % SECONDS_PER_DAY, 0);
+        assert_eq!(record.rental_condition_id, RentalConditionId::App13CH);
+        assert_eq!(record.daily_cost_cycles, rental_condition.daily_cost_cycles);
+        assert_eq!(record.cycles_burned_past_due, 0);
+        assert!(record.covered_nanos <= SECONDS_PER_DAY * NANOS_PER_SECOND);
+    }
+
+    // the records add up to the agreement's counters
+    let agreement = get_rental_agreement(&pic, SUBNET_FOR_RENT);
+    assert_eq!(
+        records.iter().map(|r| r.cycles_burned).sum::<u128>(),
+        agreement.total_cycles_burned
+    );
+    assert_eq!(
+        records.iter().map(|r| r.covered_nanos).sum::<u64>(),
+        records.last().unwrap().billed_until_nanos - agreement.creation_time_nanos
+    );
+
+    // the range is inclusive and filters by day
+    let last = records.last().unwrap().day_start_secs;
+    let tail = query_multi_arg::<Vec<BillingRecord>>(
+        &pic,
+        SRC_ID,
+        None,
+        "list_billing_records",
+        (SUBNET_FOR_RENT, last, last),
+    );
+    assert_eq!(tail, vec![records.last().unwrap().clone()]);
+}
+
+#[test]
+fn test_metrics() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+    pic.advance_time(Duration::from_secs(60 * 60));
+    for _ in 0..3 {
+        pic.tick();
+    }
+
+    let response = http_get(&pic, "/metrics?format=text");
+    assert_eq!(response.status_code, 200);
+    assert!(response.headers.contains(&(
+        "Content-Type".to_string(),
+        "text/plain; version=0.0.4".to_string()
+    )));
+    let body = String::from_utf8(response.body.into_vec()).unwrap();
+    let agreement = get_rental_agreement(&pic, SUBNET_FOR_RENT);
+    for line in [
+        "subnet_rental_agreements{condition=\"App13CH\"} 1".to_string(),
+        "subnet_rental_requests{condition=\"App13CH\"} 0".to_string(),
+        format!(
+            "subnet_rental_icp_received_e8s{{source=\"agreements\"}} {}",
+            agreement.total_icp_paid.e8s()
+        ),
+        format!(
+            "subnet_rental_cycles_created {}",
+            agreement.total_cycles_created
+        ),
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "missing {line:?} in\n{body}"
+        );
+    }
+    assert!(body.contains("subnet_rental_earliest_lapse_days "));
+    assert!(body.contains("subnet_rental_timer_last_run_seconds{timer=\"burn_cycles\"}"));
+
+    assert_eq!(http_get(&pic, "/unknown").status_code, 404);
+}
+
+#[test]
+fn test_dashboard() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+
+    let body = |response: HttpResponse| {
+        assert_eq!(response.status_code, 200);
+        assert!(response.headers.contains(&(
+            "Content-Type".to_string(),
+            "text/html; charset=utf-8".to_string()
+        )));
+        // The gateway serves the pages on the normal domain without verifying them.
+        assert!(response
+            .headers
+            .iter()
+            .any(|(name, _)| name == "IC-Certificate"));
+        String::from_utf8(response.body.into_vec()).unwrap()
+    };
+
+    let agreements = body(http_get(&pic, "/agreements"));
+    assert!(agreements.contains(&SUBNET_FOR_RENT.to_string()));
+    assert!(agreements.contains(&USER_1.to_string()));
+    assert!(agreements.contains("OK: "));
+
+    let requests = body(http_get(&pic, "/requests"));
+    assert!(requests.contains("None."));
+
+    let conditions = body(http_get(&pic, "/conditions"));
+    assert!(conditions.contains("App13CH"));
+
+    let history = body(http_get(&pic, &format!("/history/{SUBNET_FOR_RENT}")));
+    assert!(history.contains("RentalAgreementCreated"));
+
+    assert_eq!(http_get(&pic, "/history/not-a-principal").status_code, 400);
+}
+
+#[test]
+fn test_canister_status() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+    pic.advance_time(Duration::from_secs(60 * 60));
+    for _ in 0..3 {
+        pic.tick();
+    }
+
+    let status = query::<CanisterStatus>(&pic, SRC_ID, None, "get_canister_status", ());
+    let burn_cycles = status
+        .timers
+        .iter()
+        .find(|timer| timer.name == "burn_cycles")
+        .expect("burn_cycles timer did not run");
+    let end = burn_cycles.last_end_time_nanos.unwrap();
+    assert!(burn_cycles.last_start_time_nanos <= end);
+    assert!(end <= pic.get_time().as_nanos_since_unix_epoch());
+    assert_eq!(burn_cycles.skipped_entries_total, 0);
+    assert_eq!(burn_cycles.last_error, None);
+    assert!(status.timers.iter().any(|timer| timer.name == "locking"));
+
+    assert!(status.cycle_balance > 0);
+    assert!(status.stable_memory_pages > 0);
+    assert_eq!(status.memory_regions.len(), 19);
+    // The rental agreement is stored in memory region 1.
+    assert!(status.memory_regions[1].size_pages > 0);
+}
+
+fn http_get(pic: &PocketIc, url: &str) -> HttpResponse {
+    query::<HttpResponse>(
+        pic,
+        SRC_ID,
+        None,
+        "http_request",
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        },
+    )
+}
+
+#[test]
+fn test_statement() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+    let start = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+
+    // burn for a day, then top up
+    pic.advance_time(Duration::from_secs(SECONDS_PER_DAY));
+    for _ in 0..3 {
+        pic.tick();
+    }
+    let exchange_rate = 4_103_000_000; // 1 ICP = 4.103 XDR
+    set_cmc_exchange_rate(&pic, exchange_rate);
+    pay_src(&pic, USER_1, Tokens::from_e8s(100 * E8S));
+    let top_up = update::<Result<TopUpSummary, TopUpError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "top_up_subnet",
+        SUBNET_FOR_RENT,
+    )
+    .unwrap()
+    .unwrap();
+    pic.advance_time(Duration::from_secs(SECONDS_PER_DAY));
+    for _ in 0..3 {
+        pic.tick();
+    }
+    let now = pic.get_time().as_nanos_since_unix_epoch() / NANOS_PER_SECOND;
+
+    // the whole lifetime of the agreement
+    let agreement = get_rental_agreement(&pic, SUBNET_FOR_RENT);
+    let statement = get_statement(&pic, Some(USER_1), SUBNET_FOR_RENT, start, now).unwrap();
+    assert_eq!(statement.user, USER_1);
+    assert_eq!(statement.opening_balance_cycles, 0);
+    assert_eq!(statement.payments.len(), 2);
+    assert_eq!(statement.payments[1].cycles, top_up.cycles_added);
+    assert_eq!(statement.total_paid_icp, agreement.total_icp_paid);
+    assert_eq!(statement.total_paid_cycles, agreement.total_cycles_created);
+    assert_eq!(statement.cycles_burned, agreement.total_cycles_burned);
+    assert_eq!(
+        statement.closing_balance_cycles,
+        agreement.total_cycles_created - agreement.total_cycles_burned
+    );
+    assert_eq!(statement.paid_until_nanos, agreement.paid_until_nanos);
+
+    // only the last day
+    let statement = get_statement(&pic, Some(USER_1), SUBNET_FOR_RENT, now, now).unwrap();
+    assert_eq!(
+        statement.closing_balance_cycles,
+        statement.opening_balance_cycles + statement.total_paid_cycles - statement.cycles_burned
+    );
+    assert!(statement.opening_balance_cycles > 0);
+
+    // the history is short enough for a single page
+    let csv = query_multi_arg::<Result<StatementCsvPage, StatementError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_1),
+        "get_statement_csv",
+        (SUBNET_FOR_RENT, start, now, None::<StatementCursor>),
+    )
+    .unwrap();
+    let StatementCsvPage::Complete(csv) = csv else {
+        panic!("Expected a complete statement, got {csv:?}");
+    };
+    assert!(csv.starts_with("time_nanos,entry,icp_e8s,cycles\n"));
+    assert_eq!(csv.matches(",payment,").count(), 2);
+
+    // the statement is as public as the history it is built from
+    let res = get_statement(&pic, Some(USER_2), SUBNET_FOR_RENT, now, now);
+    assert_eq!(res, Ok(statement));
+
+    // unknown subnets have no statement
+    let res = get_statement(&pic, None, USER_1, start, now);
+    assert!(res.is_err());
+}
+
+/// Reads all pages of a statement.
+fn get_statement(
+    pic: &PocketIc,
+    sender: Option<Principal>,
+    subnet_id: Principal,
+    from_secs: u64,
+    to_secs: u64,
+) -> Result<Statement, StatementError> {
+    let mut cursor = None;
+    loop {
+        match query_multi_arg::<Result<StatementPage, StatementError>>(
+            pic,
+            SRC_ID,
+            sender,
+            "get_statement",
+            (subnet_id, from_secs, to_secs, cursor),
+        )? {
+            StatementPage::Incomplete(next) => cursor = Some(next),
+            StatementPage::Complete(statement) => return Ok(statement),
+        }
+    }
+}
+
+#[test]
+fn test_renter_notifications() {
+    let pic = setup_with_rented_subnet();
+    rent_subnet_helper(&pic, SUBNET_FOR_RENT, USER_1);
+    let target_canister = pic.create_canister();
+
+    // the SRC and its dependencies cannot be notified
+    for canister_id in [SRC_ID, MAINNET_EXCHANGE_RATE_CANISTER_ID] {
+        let res = update::<Result<(), RegisterNotificationTargetError>>(
+            &pic,
+            SRC_ID,
+            Some(USER_1),
+            "register_notification_target",
+            RegisterNotificationTargetPayload {
+                subnet_id: SUBNET_FOR_RENT,
+                target: Some(NotificationTarget {
+                    canister_id,
+                    method: "notify".to_string(),
+                    coverage_thresholds_days: vec![],
+                }),
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            res,
+            Err(RegisterNotificationTargetError::InvalidTarget { .. })
+        ));
+    }
+
+    // only the renter can register a target
+    let payload = RegisterNotificationTargetPayload {
+        subnet_id: SUBNET_FOR_RENT,
+        target: Some(NotificationTarget {
+            canister_id: target_canister,
+            method: "notify".to_string(),
+            coverage_thresholds_days: vec![],
+        }),
+    };
+    let res = update::<Result<(), RegisterNotificationTargetError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_2),
+        "register_notification_target",
+        payload.clone(),
+    )
+    .unwrap();
+    assert!(res.is_err());
+    update::<Result<(), RegisterNotificationTargetError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_1),
+        "register_notification_target",
+        payload,
+    )
+    .unwrap()
+    .unwrap();
+    let registration = query::<Option<NotificationRegistration>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_notification_registration",
+        SUBNET_FOR_RENT,
+    )
+    .unwrap();
+    assert_eq!(registration.target.coverage_thresholds_days, vec![30, 7, 1]);
+    assert_eq!(registration.lowest_notified_threshold_days, None);
+
+    // a failed top-up is recorded along with the notification attempt
+    let events_before = subnet_event_count(&pic, SUBNET_FOR_RENT);
+    update::<Result<TopUpSummary, TopUpError>>(
+        &pic,
+        SRC_ID,
+        None,
+        "top_up_subnet",
+        SUBNET_FOR_RENT,
+    )
+    .unwrap()
+    .unwrap_err();
+    assert_eq!(subnet_event_count(&pic, SUBNET_FOR_RENT), events_before + 2);
+
+    // the coverage drops below 30 and 7 days, but only the more urgent threshold is notified
+    pic.advance_time(Duration::from_secs(175 * SECONDS_PER_DAY));
+    for _ in 0..3 {
+        pic.tick();
+    }
+    let registration = query::<Option<NotificationRegistration>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_notification_registration",
+        SUBNET_FOR_RENT,
+    )
+    .unwrap();
+    assert_eq!(registration.lowest_notified_threshold_days, Some(7));
+    assert!(!registration.past_due_notified);
+
+    // the agreement lapses
+    pic.advance_time(Duration::from_secs(6 * SECONDS_PER_DAY));
+    for _ in 0..3 {
+        pic.tick();
+    }
+    let registration = query::<Option<NotificationRegistration>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_notification_registration",
+        SUBNET_FOR_RENT,
+    )
+    .unwrap();
+    assert_eq!(registration.lowest_notified_threshold_days, Some(1));
+    assert!(registration.past_due_notified);
+
+    // the renter can remove the target
+    update::<Result<(), RegisterNotificationTargetError>>(
+        &pic,
+        SRC_ID,
+        Some(USER_1),
+        "register_notification_target",
+        RegisterNotificationTargetPayload {
+            subnet_id: SUBNET_FOR_RENT,
+            target: None,
+        },
+    )
+    .unwrap()
+    .unwrap();
+    let registration = query::<Option<NotificationRegistration>>(
+        &pic,
+        SRC_ID,
+        None,
+        "get_notification_registration",
+        SUBNET_FOR_RENT,
+    );
+    assert_eq!(registration, None);
 }
 
 #[test]
@@ -1337,10 +2896,24 @@ fn upgrade_migrates_target_subnet_to_app7ch() {
     assert!(burned.total_cycles_burned > 0);
     let events_before = subnet_event_count(&pic, subnet_id);
 
+    // The pending migration reports the switch without applying it.
+    let migrations = query::<Vec<MigrationReport>>(&pic, SRC_ID, None, "list_migrations", ());
+    assert_eq!(migrations.len(), 1);
+    assert_eq!(migrations[0].applied_time_nanos, None);
+    assert!(matches!(
+        &migrations[0].dry_run,
+        Some(Ok(MigrationOutcome::Changes(changes))) if changes.len() == 1
+    ));
+    assert_eq!(get_rental_agreement(&pic, subnet_id), burned);
+
     let src_wasm = fs::read(SRC_WASM).expect("Build the wasm with ./scripts/build.sh");
     pic.upgrade_canister(SRC_ID, src_wasm.clone(), encode_one(()).unwrap(), None)
         .unwrap();
 
+    let migrations = query::<Vec<MigrationReport>>(&pic, SRC_ID, None, "list_migrations", ());
+    assert!(migrations[0].applied_time_nanos.is_some());
+    assert_eq!(migrations[0].dry_run, None);
+
     let after = get_rental_agreement(&pic, subnet_id);
     assert_eq!(after.rental_condition_id, RentalConditionId::App7CH);
 
@@ -1373,6 +2946,54 @@ fn upgrade_migrates_target_subnet_to_app7ch() {
     assert_eq!(subnet_event_count(&pic, subnet_id), events_before + 1);
 }
 
+#[test]
+fn test_governance_switches_rental_condition() {
+    let pic = setup_with_rented_subnet();
+    let subnet_id = *pic.topology().get_app_subnets().first().unwrap();
+    rent_subnet_helper(&pic, subnet_id, USER_1);
+    pic.advance_time(Duration::from_secs(10 * SECONDS_PER_DAY));
+    for _ in 0..5 {
+        pic.tick();
+    }
+
+    let switch = |sender: Principal, new_condition_id: RentalConditionId| {
+        update::<()>(
+            &pic,
+            SRC_ID,
+            Some(sender),
+            "execute_switch_rental_condition",
+            SwitchRentalConditionPayload {
+                subnet_id,
+                new_condition_id,
+            },
+        )
+    };
+
+    assert!(switch(USER_1, RentalConditionId::App7CH)
+        .unwrap_err()
+        .contains(&proposal_error(ExecuteProposalError::UnauthorizedCaller)));
+    assert!(
+        switch(MAINNET_GOVERNANCE_CANISTER_ID, RentalConditionId::App13CH)
+            .unwrap_err()
+            .contains("Switching rental condition failed: variant { InvalidRentalConditionSwitch")
+    );
+
+    // downsizing extends the agreement
+    let before = get_rental_agreement(&pic, subnet_id);
+    assert_eq!(before.rental_condition_id, RentalConditionId::App13CH);
+    switch(MAINNET_GOVERNANCE_CANISTER_ID, RentalConditionId::App7CH).unwrap();
+    let downsized = get_rental_agreement(&pic, subnet_id);
+    assert_eq!(downsized.rental_condition_id, RentalConditionId::App7CH);
+    assert!(downsized.paid_until_nanos > before.paid_until_nanos);
+    assert_eq!(downsized.total_cycles_created, before.total_cycles_created);
+
+    // upsizing shortens it again
+    switch(MAINNET_GOVERNANCE_CANISTER_ID, RentalConditionId::App13CH).unwrap();
+    let upsized = get_rental_agreement(&pic, subnet_id);
+    assert_eq!(upsized.rental_condition_id, RentalConditionId::App13CH);
+    assert!(upsized.paid_until_nanos < downsized.paid_until_nanos);
+}
+
 /// PocketIC never assigns the migration's hardcoded mainnet subnet id.
 #[test]
 fn upgrade_does_not_migrate_unrelated_agreements() {
@@ -1389,6 +3010,20 @@ fn upgrade_does_not_migrate_unrelated_agreements() {
 
     let after = get_rental_agreement(&pic, subnet_id);
     assert_eq!(after, before);
+
+    // The migration waits for the target subnet to be rented.
+    let migrations = query::<Vec<MigrationReport>>(&pic, SRC_ID, None, "list_migrations", ());
+    assert_eq!(migrations[0].applied_time_nanos, None);
+
+    // Everything written before the upgrade still decodes. The check runs in timers.
+    for _ in 0..3 {
+        pic.tick();
+    }
+    let storage_check =
+        query::<Option<StorageCheckReport>>(&pic, SRC_ID, None, "get_storage_check", ()).unwrap();
+    assert!(storage_check.finished_time_nanos.is_some());
+    assert!(storage_check.entries_checked > 0);
+    assert_eq!(storage_check.failures, vec![]);
 }
 
 // ====================================================================================================================
@@ -1466,6 +3101,11 @@ fn query_multi_arg<T: for<'a> Deserialize<'a> + candid::CandidType>(
     }
 }
 
+/// The candid value of `error`, as contained in the reject message of a governance method.
+fn proposal_error(error: ExecuteProposalError) -> String {
+    IDLValue::try_from_candid_type(&error).unwrap().to_string()
+}
+
 fn update<T: CandidType + for<'a> Deserialize<'a>>(
     pic: &PocketIc,
     canister_id: Principal,
@@ -1515,7 +3155,7 @@ fn check_balance(pic: &PocketIc, owner: Principal, subaccount: Subaccount) -> To
 }
 
 fn check_subnet_status(pic: &PocketIc) -> RentalAgreementStatus {
-    query::<Result<RentalAgreementStatus, String>>(
+    query::<Result<RentalAgreementStatus, RentalAgreementStatusError>>(
         pic,
         SRC_ID,
         None,
@@ -1541,6 +3181,7 @@ fn rent_subnet_helper(pic: &PocketIc, subnet_id: Principal, renting_principal: P
         rental_condition_id: RentalConditionId::App13CH,
         proposal_id: 136408,
         proposal_creation_time_seconds: now,
+        price_quote_id: None,
     };
 
     // 1 day passes ...
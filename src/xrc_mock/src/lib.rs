@@ -19,7 +19,8 @@ use ic_xrc_types::{
 use std::cell::RefCell;
 
 thread_local! {
-    static RATES: RefCell<Vec<(u64, u64)>> = const { RefCell::new(vec![]) } // (timestamp, rate) where rate is 1 ICP = X XDR (10^9 precision)
+    static RATES: RefCell<Vec<(u64, u64)>> = const { RefCell::new(vec![]) }; // (timestamp, rate) where rate is 1 ICP = X XDR (10^9 precision)
+    static BASE_ASSET_NUM_RECEIVED_RATES: RefCell<usize> = const { RefCell::new(5) };
 }
 
 // See https://github.com/dfinity/exchange-rate-canister/blob/2f2a08f36fa6d043da9751d61d77952b36a59006/src/xrc/src/lib.rs#L56
@@ -51,7 +52,7 @@ async fn get_exchange_rate(request: GetExchangeRateRequest) -> GetExchangeRateRe
         metadata: ExchangeRateMetadata {
             decimals: 9,
             base_asset_num_queried_sources: 7,
-            base_asset_num_received_rates: 5,
+            base_asset_num_received_rates: BASE_ASSET_NUM_RECEIVED_RATES.with_borrow(|n| *n),
             quote_asset_num_queried_sources: 10,
             quote_asset_num_received_rates: 4,
             standard_deviation: 0,
@@ -66,3 +67,9 @@ fn set_exchange_rate_data(data: Vec<(u64, u64)>) {
         rates.extend(data);
     });
 }
+
+/// Allows simulating a rate that is backed by too few sources.
+#[ic_cdk::update]
+fn set_base_asset_num_received_rates(num_received_rates: u64) {
+    BASE_ASSET_NUM_RECEIVED_RATES.with_borrow_mut(|n| *n = num_received_rates as usize);
+}